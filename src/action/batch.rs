@@ -0,0 +1,270 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use sonic_rs::Deserialize;
+use teloxide::types::{ChatId, MessageId};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinSet,
+    time::{sleep_until, Instant},
+};
+
+/// How pending deletions of a chat are grouped into a single request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchConfig {
+    /// Send the batch once it has this many messages.
+    pub max_batch_size: usize,
+    /// Or once its first message has waited for this long.
+    #[serde(deserialize_with = "crate::config::secs")]
+    pub max_batch_delay: Duration,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 20,
+            max_batch_delay: Duration::from_millis(500),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Command {
+    Delete(ChatId, MessageId),
+    Flush(oneshot::Sender<()>),
+}
+
+type Spawn = Box<dyn FnOnce() -> mpsc::UnboundedSender<Command> + Send>;
+
+/// Handle to the task grouping deletions.
+/// The task flushes everything left and exits once all handles are dropped.
+#[derive(Clone)]
+pub(crate) struct Batcher(Arc<Mutex<Task>>);
+
+/// The task is spawned on the first deletion, so that a `Batcher` can be
+/// made outside a runtime.
+struct Task {
+    spawn: Option<Spawn>,
+    tx: Option<mpsc::UnboundedSender<Command>>,
+}
+
+impl fmt::Debug for Batcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let running = self.0.lock().unwrap().tx.is_some();
+        f.debug_struct("Batcher")
+            .field("running", &running)
+            .finish()
+    }
+}
+
+impl Batcher {
+    /// `flush` is run on each batch as its own task, so that a batch slowed
+    /// down (e.g. by flood control) holds up no other.
+    pub(crate) fn new<F, Fut>(config: BatchConfig, flush: F) -> Self
+    where
+        F: FnMut(ChatId, Vec<MessageId>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let spawn: Spawn = Box::new(move || {
+            let (tx, rx) = mpsc::unbounded_channel();
+            tokio::spawn(run(config, rx, flush));
+            tx
+        });
+        Self(Arc::new(Mutex::new(Task {
+            spawn: Some(spawn),
+            tx: None,
+        })))
+    }
+
+    pub(crate) fn push(&self, chat_id: ChatId, msg_id: MessageId) {
+        let mut task = self.0.lock().unwrap();
+        if let Some(spawn) = task.spawn.take() {
+            task.tx = Some(spawn());
+        }
+        if let Some(tx) = &task.tx {
+            // Only fails if the task has gone, nothing we can do then
+            let _ = tx.send(Command::Delete(chat_id, msg_id));
+        }
+    }
+
+    /// Send all pending batches now, return once they are done.
+    pub(crate) async fn flush(&self) {
+        let (done, wait) = oneshot::channel();
+        let sent = match &self.0.lock().unwrap().tx {
+            Some(tx) => tx.send(Command::Flush(done)).is_ok(),
+            None => false, // Nothing ever pushed
+        };
+        if sent {
+            let _ = wait.await;
+        }
+    }
+}
+
+async fn run<F, Fut>(config: BatchConfig, mut rx: mpsc::UnboundedReceiver<Command>, mut flush: F)
+where
+    F: FnMut(ChatId, Vec<MessageId>) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    // Chat => (deadline, messages)
+    let mut pending: HashMap<ChatId, (Instant, Vec<MessageId>)> = HashMap::new();
+    let mut running = JoinSet::new();
+    loop {
+        let deadline = pending.values().map(|(deadline, _)| *deadline).min();
+        let command = tokio::select! {
+            command = rx.recv() => command,
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                let now = Instant::now();
+                let due: Vec<_> = pending
+                    .iter()
+                    .filter(|(_, (deadline, _))| *deadline <= now)
+                    .map(|(chat_id, _)| *chat_id)
+                    .collect();
+                for chat_id in due {
+                    let (_, msg_ids) = pending.remove(&chat_id).unwrap();
+                    running.spawn(flush(chat_id, msg_ids));
+                }
+                continue;
+            }
+            Some(_) = running.join_next(), if !running.is_empty() => continue,
+        };
+        match command {
+            Some(Command::Delete(chat_id, msg_id)) => {
+                let (_, msg_ids) = pending
+                    .entry(chat_id)
+                    .or_insert_with(|| (Instant::now() + config.max_batch_delay, Vec::new()));
+                if !msg_ids.contains(&msg_id) {
+                    msg_ids.push(msg_id);
+                }
+                if msg_ids.len() >= config.max_batch_size {
+                    let (_, msg_ids) = pending.remove(&chat_id).unwrap();
+                    running.spawn(flush(chat_id, msg_ids));
+                }
+            }
+            Some(Command::Flush(done)) => {
+                for (chat_id, (_, msg_ids)) in pending.drain() {
+                    running.spawn(flush(chat_id, msg_ids));
+                }
+                while running.join_next().await.is_some() {}
+                let _ = done.send(());
+            }
+            None => {
+                for (chat_id, (_, msg_ids)) in pending.drain() {
+                    running.spawn(flush(chat_id, msg_ids));
+                }
+                while running.join_next().await.is_some() {}
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn recording_batcher(
+    config: BatchConfig,
+) -> (Batcher, mpsc::UnboundedReceiver<(ChatId, Vec<MessageId>)>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let batcher = Batcher::new(config, move |chat_id, msg_ids| {
+        tx.send((chat_id, msg_ids)).unwrap();
+        async {}
+    });
+    (batcher, rx)
+}
+
+#[tokio::test]
+async fn test_batch_size() {
+    let (batcher, mut flushed) = recording_batcher(BatchConfig {
+        max_batch_size: 3,
+        max_batch_delay: Duration::from_secs(3600),
+    });
+    for id in [1, 2, 2, 3, 4] {
+        batcher.push(ChatId(-1), MessageId(id));
+    }
+    batcher.push(ChatId(-2), MessageId(1));
+    assert_eq!(
+        (ChatId(-1), vec![MessageId(1), MessageId(2), MessageId(3)]),
+        flushed.recv().await.unwrap()
+    );
+    assert!(flushed.try_recv().is_err());
+
+    // Partial batches are sent on flush
+    batcher.flush().await;
+    let mut rest = vec![flushed.try_recv().unwrap(), flushed.try_recv().unwrap()];
+    rest.sort_by_key(|(chat_id, _)| chat_id.0);
+    assert_eq!(
+        vec![
+            (ChatId(-2), vec![MessageId(1)]),
+            (ChatId(-1), vec![MessageId(4)])
+        ],
+        rest
+    );
+}
+
+#[tokio::test]
+async fn test_batch_delay() {
+    let delay = Duration::from_millis(50);
+    let (batcher, mut flushed) = recording_batcher(BatchConfig {
+        max_batch_size: 100,
+        max_batch_delay: delay,
+    });
+    let start = Instant::now();
+    batcher.push(ChatId(-1), MessageId(1));
+    batcher.push(ChatId(-1), MessageId(2));
+    assert_eq!(
+        (ChatId(-1), vec![MessageId(1), MessageId(2)]),
+        flushed.recv().await.unwrap()
+    );
+    assert!(start.elapsed() >= delay);
+
+    // Left over ones are sent when the handle is gone
+    batcher.push(ChatId(-1), MessageId(3));
+    drop(batcher);
+    assert_eq!(
+        (ChatId(-1), vec![MessageId(3)]),
+        flushed.recv().await.unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_batch_concurrent() {
+    let (tx, mut flushed) = mpsc::unbounded_channel();
+    let config = BatchConfig {
+        max_batch_size: 1,
+        max_batch_delay: Duration::from_secs(3600),
+    };
+    let batcher = Batcher::new(config, move |chat_id, msg_ids| {
+        let tx = tx.clone();
+        async move {
+            // Chat -1 is stuck in flood control
+            if chat_id == ChatId(-1) {
+                std::future::pending::<()>().await;
+            }
+            tx.send((chat_id, msg_ids)).unwrap();
+        }
+    });
+    batcher.push(ChatId(-1), MessageId(1));
+    batcher.push(ChatId(-2), MessageId(1));
+    assert_eq!(
+        (ChatId(-2), vec![MessageId(1)]),
+        flushed.recv().await.unwrap()
+    );
+}
+
+#[test]
+fn test_batcher_outside_runtime() {
+    let batcher = Batcher::new(Default::default(), |_, _| async {});
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        batcher.flush().await;
+        batcher.push(ChatId(-1), MessageId(1));
+        batcher.flush().await;
+    });
+}
@@ -0,0 +1,1267 @@
+mod batch;
+
+use chrono::DateTime;
+use log::{debug, info, warn};
+use std::{
+    collections::HashSet,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use teloxide::{
+    payloads::{
+        BanChatMember, BanChatMemberSetters, RestrictChatMemberSetters, SendMessageSetters,
+        UnbanChatMemberSetters,
+    },
+    requests::{JsonRequest, Output, Request, Requester},
+    types::{
+        ChatId, ChatPermissions, InlineKeyboardButton, InlineKeyboardMarkup, MessageId, ParseMode,
+        UserId,
+    },
+    ApiError, Bot, RequestError,
+};
+use tokio::{
+    sync::{mpsc, Semaphore},
+    task::JoinSet,
+    time::{sleep, sleep_until, timeout, Instant},
+};
+
+use crate::storage::unix_now;
+
+pub use batch::BatchConfig;
+use batch::Batcher;
+
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+// Notices are deleted after this to avoid clutter
+const NOTICE_LIFETIME: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct Actions {
+    bot: Bot,
+    max_retry: u32,
+    outstanding_limit: Arc<Semaphore>,
+    cooldown: Cooldown,
+    in_flight: InFlight,
+    deletions: Batcher,
+    /// Deletions are limited and held off apart from the rest, so that bans
+    /// go on while a flood of deletions waits on flood control.
+    deletion_limit: Arc<Semaphore>,
+    deletion_cooldown: Cooldown,
+    tasks: Tasks,
+    notices: Notices,
+    /// Only log bans, without actually banning, until this time.
+    enforce_bans_after: Option<SystemTime>,
+    /// Posted to the chat after a ban, see `ban_notice()`.
+    ban_notice_template: Option<Arc<str>>,
+    mode: ActionMode,
+    batch_config: BatchConfig,
+    /// See `report_delivered()`.
+    delivered: Option<mpsc::UnboundedSender<Delivered>>,
+}
+
+/// A deletion or ban done on Telegram, or given up on errors that won't go
+/// away by retrying, see `Actions::report_delivered()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivered {
+    Deleted(ChatId, MessageId),
+    Banned(ChatId, UserId),
+}
+
+/// Whether actions are carried out at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ActionMode {
+    #[default]
+    Enforce,
+    /// Log what would be done without sending anything to Telegram, e.g.
+    /// to try new patterns on live traffic.
+    DryRun,
+}
+
+/// Deadline shared by action tasks, before which no request should be sent.
+/// One `RetryAfter` pauses every task sharing it, instead of letting each
+/// task wake up and hit the limit again on its own.
+#[derive(Debug, Clone, Default)]
+struct Cooldown(Arc<Mutex<Option<Instant>>>);
+
+impl Cooldown {
+    /// Push the deadline to at least `delay` from now.
+    fn extend(&self, delay: Duration) {
+        let until = Instant::now() + delay;
+        let mut deadline = self.0.lock().unwrap();
+        if deadline.is_none_or(|t| t < until) {
+            *deadline = Some(until);
+        }
+    }
+
+    /// Wait until the deadline has passed, including any extension made while waiting.
+    async fn wait(&self) {
+        loop {
+            let deadline = *self.0.lock().unwrap();
+            match deadline {
+                Some(until) if until > Instant::now() => sleep_until(until).await,
+                _ => break,
+            }
+        }
+    }
+}
+
+/// Spawned action tasks, so that they can be waited for on shutdown.
+/// `None` once shut down, no more tasks are taken then.
+#[derive(Debug, Clone)]
+struct Tasks(Arc<Mutex<Option<JoinSet<()>>>>);
+
+impl Default for Tasks {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(Some(JoinSet::new()))))
+    }
+}
+
+impl Tasks {
+    fn spawn<F: Future<Output = ()> + Send + 'static>(&self, task: F) {
+        match self.0.lock().unwrap().as_mut() {
+            Some(tasks) => {
+                // Reap finished ones, or they pile up
+                while tasks.try_join_next().is_some() {}
+                tasks.spawn(task);
+            }
+            None => warn!("Action dropped, shutting down"),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.0.lock().unwrap().is_none()
+    }
+
+    /// Refuse new tasks and wait for the running ones.
+    async fn close(&self) {
+        let tasks = self.0.lock().unwrap().take();
+        if let Some(mut tasks) = tasks {
+            while tasks.join_next().await.is_some() {}
+        }
+    }
+}
+
+/// Notices waiting to be deleted, e.g. of bans. Not kept in `Tasks`, so
+/// shutdown deletes them right away instead of waiting them out.
+#[derive(Debug, Clone, Default)]
+struct Notices(Arc<Mutex<HashSet<(ChatId, MessageId)>>>);
+
+impl Notices {
+    /// Delete the message after `lifetime`, unless taken by `take_all()` first.
+    fn expire(&self, chat_id: ChatId, msg_id: MessageId, lifetime: Duration, deletions: Batcher) {
+        self.0.lock().unwrap().insert((chat_id, msg_id));
+        let notices = self.clone();
+        tokio::spawn(async move {
+            sleep(lifetime).await;
+            if notices.0.lock().unwrap().remove(&(chat_id, msg_id)) {
+                deletions.push(chat_id, msg_id);
+            }
+        });
+    }
+
+    fn take_all(&self) -> Vec<(ChatId, MessageId)> {
+        self.0.lock().unwrap().drain().collect()
+    }
+}
+
+/// Targets of ban tasks still running, so that the same request
+/// made again in the meantime can be skipped. Repeated deletions are
+/// dropped by the batcher instead.
+#[derive(Debug, Clone, Default)]
+struct InFlight(Arc<Mutex<HashSet<Target>>>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Target {
+    User(ChatId, UserId),
+}
+
+/// Remove the target from `InFlight` once dropped.
+#[derive(Debug)]
+struct InFlightGuard {
+    in_flight: InFlight,
+    target: Target,
+}
+
+impl InFlight {
+    /// Mark `target` as in flight, or `None` if it already is.
+    fn start(&self, target: Target) -> Option<InFlightGuard> {
+        if !self.0.lock().unwrap().insert(target) {
+            return None;
+        }
+        Some(InFlightGuard {
+            in_flight: self.clone(),
+            target,
+        })
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.in_flight.0.lock().unwrap().remove(&self.target);
+    }
+}
+
+impl Actions {
+    /// `max_outstanding_requests` limits all requests, each taking one while
+    /// sent. Of those, `max_outstanding_deletions` are for batches of
+    /// deletions and the rest for bans and messages. Flood control hit by
+    /// deletions holds up deletions only, so bans are never queued behind
+    /// them.
+    pub fn new(
+        bot: &Bot,
+        max_outstanding_requests: usize,
+        max_outstanding_deletions: usize,
+        max_retry: u32,
+    ) -> Self {
+        assert!(
+            (1..max_outstanding_requests).contains(&max_outstanding_deletions),
+            "deletions must leave some of the outstanding requests"
+        );
+        let deletion_limit = Arc::new(Semaphore::new(max_outstanding_deletions));
+        let deletion_cooldown = Cooldown::default();
+        Self {
+            bot: bot.clone(),
+            max_retry,
+            outstanding_limit: Arc::new(Semaphore::new(
+                max_outstanding_requests - max_outstanding_deletions,
+            )),
+            cooldown: Default::default(),
+            deletions: new_batcher(
+                bot,
+                &deletion_cooldown,
+                &deletion_limit,
+                max_retry,
+                Default::default(),
+                None,
+            ),
+            deletion_limit,
+            deletion_cooldown,
+            in_flight: Default::default(),
+            tasks: Default::default(),
+            notices: Default::default(),
+            enforce_bans_after: None,
+            ban_notice_template: None,
+            mode: ActionMode::Enforce,
+            batch_config: Default::default(),
+            delivered: None,
+        }
+    }
+
+    /// Replace the default `BatchConfig` for deletions.
+    pub fn batch_config(mut self, config: BatchConfig) -> Self {
+        self.batch_config = config;
+        self.rebuild_batcher();
+        self
+    }
+
+    /// Send each deletion and ban to `delivered` once Telegram has done it,
+    /// or once there is nothing left to do, e.g. the message is already gone.
+    /// Bans skipped during the trial period, and those Telegram refuses (e.g.
+    /// on admins), count as done.
+    pub fn report_delivered(mut self, delivered: mpsc::UnboundedSender<Delivered>) -> Self {
+        self.delivered = Some(delivered);
+        self.rebuild_batcher();
+        self
+    }
+
+    fn rebuild_batcher(&mut self) {
+        self.deletions = new_batcher(
+            &self.bot,
+            &self.deletion_cooldown,
+            &self.deletion_limit,
+            self.max_retry,
+            self.batch_config,
+            self.delivered.clone(),
+        );
+    }
+
+    /// Send all deletions still waiting in batches, e.g. before shutdown.
+    pub async fn flush(&self) {
+        self.deletions.flush().await
+    }
+
+    /// Stop taking new actions, then wait for running ones and pending
+    /// deletions to finish, up to `limit`. Anything left after that is dropped.
+    /// Notices are deleted without waiting for their lifetime.
+    pub async fn shutdown(self, limit: Duration) {
+        let drained = timeout(limit, async {
+            self.tasks.close().await;
+            for (chat_id, msg_id) in self.notices.take_all() {
+                self.deletions.push(chat_id, msg_id);
+            }
+            self.deletions.flush().await;
+        })
+        .await;
+        if drained.is_err() {
+            warn!("Some actions not finished in {:?}, dropped", limit);
+        }
+    }
+
+    pub fn mode(mut self, mode: ActionMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    fn is_dry_run(&self) -> bool {
+        self.mode == ActionMode::DryRun
+    }
+
+    /// Trial period for new deployments: deletions happen as usual, but bans
+    /// are only logged until `time`.
+    pub fn enforce_bans_after(mut self, time: SystemTime) -> Self {
+        self.enforce_bans_after = Some(time);
+        self
+    }
+
+    /// Post a notice to the chat after each ban. `{user}` and `{reason}` in
+    /// `template` are replaced with the user's id and why they got banned.
+    /// Not their name, which spammers fill with their pitch. `{name}` is
+    /// taken as `{user}` for older templates.
+    pub fn ban_notice_template(mut self, template: String) -> Self {
+        self.ban_notice_template = Some(template.into());
+        self
+    }
+
+    fn ban_notice(&self, user_id: UserId, reason: &str) -> Option<String> {
+        let template = self.ban_notice_template.as_deref()?;
+        let user = user_id.to_string();
+        let notice = template
+            .replace("{user}", &user)
+            .replace("{name}", &user)
+            .replace("{reason}", reason);
+        Some(notice)
+    }
+
+    fn bans_enforced_at(&self, now: SystemTime) -> bool {
+        self.enforce_bans_after.is_none_or(|after| now >= after)
+    }
+
+    /// Queue the message for deletion, it is deleted along with others of
+    /// the same chat, see `BatchConfig`.
+    pub async fn spwan_delete_message(&self, chat_id: ChatId, msg_id: MessageId) {
+        if self.tasks.is_closed() {
+            warn!("[{}] Not deleting [{:?}], shutting down", chat_id, msg_id);
+            return;
+        }
+        if self.is_dry_run() {
+            info!("[{}] Would delete [{:?}] (dry run)", chat_id, msg_id);
+            return;
+        }
+        info!("[{}] Deleting [{:?}]", chat_id, msg_id);
+        self.deletions.push(chat_id, msg_id);
+    }
+
+    /// Spawn a new task to ban the user, and delete all their messages in
+    /// the chat if `revoke_messages`. A notice is posted once banned if
+    /// `ban_notice_template()` is set.
+    pub async fn spawn_ban_user(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        revoke_messages: bool,
+        reason: &str,
+    ) {
+        if self.is_dry_run() {
+            info!(
+                "[{}] Would ban user [{}] for {} (dry run)",
+                chat_id, user_id, reason
+            );
+            return;
+        }
+        let delivered = self.delivered.clone();
+        let report = move || {
+            if let Some(delivered) = delivered {
+                let _ = delivered.send(Delivered::Banned(chat_id, user_id));
+            }
+        };
+        if !self.bans_enforced_at(SystemTime::now()) {
+            info!("[{}] Would ban user [{}] (trial period)", chat_id, user_id);
+            report();
+            return;
+        }
+        let Some(guard) = self.in_flight.start(Target::User(chat_id, user_id)) else {
+            debug!("[{}] Already banning [{}]", chat_id, user_id);
+            return;
+        };
+        let permit = self
+            .outstanding_limit
+            .clone()
+            .acquire_owned()
+            .await
+            .unwrap(); // Semaphore never get closed
+        let bot = self.bot.clone();
+        let cooldown = self.cooldown.clone();
+        let deletions = self.deletions.clone();
+        let notice = self.ban_notice(user_id, reason);
+        let notices = self.notices.clone();
+        self.tasks.spawn(async move {
+            info!("[{}] Ban user [{}]", chat_id, user_id);
+            let request = ban_request(&bot, chat_id, user_id, revoke_messages);
+            let banned = ban_user(request, &cooldown).await;
+            drop(guard);
+            match banned {
+                Ok(()) => (),
+                Err(err) if is_permanent(&err) => {
+                    // e.g. an admin, trying again on restart won't help
+                    warn!("[{}] Give up banning [{}]: {:?}", chat_id, user_id, err);
+                    report();
+                    return;
+                }
+                Err(err) => {
+                    warn!("[{}] Failed to ban [{}]: {:?}", chat_id, user_id, err);
+                    return;
+                }
+            }
+            report();
+            let Some(notice) = notice else { return };
+            let sent = send_message(bot, &cooldown, chat_id, notice).await;
+            drop(permit); // Don't hold it while waiting
+            match sent {
+                Ok(msg_id) => notices.expire(chat_id, msg_id, NOTICE_LIFETIME, deletions),
+                Err(err) => warn!("[{}] Failed to post ban notice: {:?}", chat_id, err),
+            }
+        });
+    }
+}
+
+impl Actions {
+    /// Spawn a new task to lift the ban of a user, so they can join again.
+    pub async fn spawn_unban_user(&self, chat_id: ChatId, user_id: UserId) {
+        if self.is_dry_run() {
+            info!("[{}] Would unban user [{}] (dry run)", chat_id, user_id);
+            return;
+        }
+        let permit = self
+            .outstanding_limit
+            .clone()
+            .acquire_owned()
+            .await
+            .unwrap(); // Semaphore never get closed
+        let bot = self.bot.clone();
+        let cooldown = self.cooldown.clone();
+        self.tasks.spawn(async move {
+            info!("[{}] Unban user [{}]", chat_id, user_id);
+            cooldown.wait().await;
+            // Don't kick them out if they are still in the chat
+            let request = bot.unban_chat_member(chat_id, user_id).only_if_banned(true);
+            match request.send().await {
+                Ok(_) => (),
+                Err(RequestError::RetryAfter(delay)) => {
+                    cooldown.extend(delay.duration());
+                    warn!("[{}] Failed to unban [{}]: flood control", chat_id, user_id);
+                }
+                Err(err) => warn!("[{}] Failed to unban [{}]: {:?}", chat_id, user_id, err),
+            }
+            drop(permit);
+        });
+    }
+
+    /// Spawn a new task to mute the new member, and post `text` (in HTML)
+    /// with a 啊 button sending `answer` back. The message is deleted after
+    /// `timeout`, whether answered or not. If either fails, the member is
+    /// left unmuted and `(chat_id, user_id)` is sent to `failed`.
+    pub async fn spawn_challenge(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        text: String,
+        answer: String,
+        timeout: Duration,
+        failed: mpsc::Sender<(ChatId, UserId)>,
+    ) {
+        if self.is_dry_run() {
+            info!("[{}] Would challenge user [{}] (dry run)", chat_id, user_id);
+            return;
+        }
+        let permit = self
+            .outstanding_limit
+            .clone()
+            .acquire_owned()
+            .await
+            .unwrap(); // Semaphore never get closed
+        let bot = self.bot.clone();
+        let cooldown = self.cooldown.clone();
+        let deletions = self.deletions.clone();
+        self.tasks.spawn(async move {
+            let permissions = ChatPermissions::empty();
+            let request = bot.restrict_chat_member(chat_id, user_id, permissions);
+            if let Err(err) = send_once(request, &cooldown).await {
+                warn!("[{}] Failed to restrict [{}]: {:?}", chat_id, user_id, err);
+                let _ = failed.send((chat_id, user_id)).await;
+                return;
+            }
+            let button = InlineKeyboardButton::callback("啊", answer);
+            let request = bot
+                .send_message(chat_id, text)
+                .parse_mode(ParseMode::Html)
+                .reply_markup(InlineKeyboardMarkup::new([[button]]));
+            let sent = send_once(request, &cooldown).await;
+            let msg = match sent {
+                Ok(msg) => msg,
+                Err(err) => {
+                    warn!("[{}] Failed to post challenge: {:?}", chat_id, err);
+                    // No way to answer it, let them go
+                    let request =
+                        bot.restrict_chat_member(chat_id, user_id, ChatPermissions::all());
+                    if let Err(err) = send_once(request, &cooldown).await {
+                        warn!(
+                            "[{}] Failed to unrestrict [{}]: {:?}",
+                            chat_id, user_id, err
+                        );
+                    }
+                    let _ = failed.send((chat_id, user_id)).await;
+                    return;
+                }
+            };
+            drop(permit); // Don't hold it while waiting
+            sleep(timeout).await;
+            deletions.push(chat_id, msg.id);
+        });
+    }
+
+    /// Spawn a new task to mute the user for `duration`. Telegram takes
+    /// less than 30 seconds or more than 366 days as forever.
+    pub async fn spawn_restrict_user(&self, chat_id: ChatId, user_id: UserId, duration: Duration) {
+        if self.is_dry_run() {
+            info!(
+                "[{}] Would mute user [{}] for {:?} (dry run)",
+                chat_id, user_id, duration
+            );
+            return;
+        }
+        let permit = self
+            .outstanding_limit
+            .clone()
+            .acquire_owned()
+            .await
+            .unwrap(); // Semaphore never get closed
+        let bot = self.bot.clone();
+        let cooldown = self.cooldown.clone();
+        self.tasks.spawn(async move {
+            info!("[{}] Mute user [{}] for {:?}", chat_id, user_id, duration);
+            let until = (unix_now() + duration.as_secs()) as i64;
+            let request = bot
+                .restrict_chat_member(chat_id, user_id, ChatPermissions::empty())
+                .until_date(DateTime::from_timestamp(until, 0).unwrap_or_default());
+            if let Err(err) = send_once(request, &cooldown).await {
+                warn!("[{}] Failed to mute [{}]: {:?}", chat_id, user_id, err);
+            }
+            drop(permit);
+        });
+    }
+
+    /// Spawn a new task to lift restrictions on the user, back to what the
+    /// chat allows for everyone.
+    pub async fn spawn_unrestrict_user(&self, chat_id: ChatId, user_id: UserId) {
+        if self.is_dry_run() {
+            info!(
+                "[{}] Would unrestrict user [{}] (dry run)",
+                chat_id, user_id
+            );
+            return;
+        }
+        let permit = self
+            .outstanding_limit
+            .clone()
+            .acquire_owned()
+            .await
+            .unwrap(); // Semaphore never get closed
+        let bot = self.bot.clone();
+        let cooldown = self.cooldown.clone();
+        self.tasks.spawn(async move {
+            info!("[{}] Unrestrict user [{}]", chat_id, user_id);
+            let request = bot.restrict_chat_member(chat_id, user_id, ChatPermissions::all());
+            if let Err(err) = send_once(request, &cooldown).await {
+                warn!(
+                    "[{}] Failed to unrestrict [{}]: {:?}",
+                    chat_id, user_id, err
+                );
+            }
+            drop(permit);
+        });
+    }
+
+    /// Spawn a new task to remove the user from the chat without a ban,
+    /// so they may join again.
+    pub async fn spawn_kick_user(&self, chat_id: ChatId, user_id: UserId) {
+        if self.is_dry_run() {
+            info!("[{}] Would kick user [{}] (dry run)", chat_id, user_id);
+            return;
+        }
+        let permit = self
+            .outstanding_limit
+            .clone()
+            .acquire_owned()
+            .await
+            .unwrap(); // Semaphore never get closed
+        let bot = self.bot.clone();
+        let cooldown = self.cooldown.clone();
+        self.tasks.spawn(async move {
+            info!("[{}] Kick user [{}]", chat_id, user_id);
+            let request = ban_request(&bot, chat_id, user_id, false);
+            if let Err(err) = ban_user(request, &cooldown).await {
+                warn!("[{}] Failed to kick [{}]: {:?}", chat_id, user_id, err);
+                return;
+            }
+            let request = bot.unban_chat_member(chat_id, user_id).only_if_banned(true);
+            if let Err(err) = send_once(request, &cooldown).await {
+                warn!(
+                    "[{}] Failed to unban kicked [{}]: {:?}",
+                    chat_id, user_id, err
+                );
+            }
+            drop(permit);
+        });
+    }
+
+    /// Spawn a new task to stop the spinner on the button pressed.
+    pub async fn spawn_answer_callback_query(&self, query_id: String) {
+        if self.is_dry_run() {
+            return;
+        }
+        let bot = self.bot.clone();
+        let cooldown = self.cooldown.clone();
+        self.tasks.spawn(async move {
+            let request = bot.answer_callback_query(query_id);
+            if let Err(err) = send_once(request, &cooldown).await {
+                debug!("Failed to answer callback query: {:?}", err);
+            }
+        });
+    }
+
+    /// Spawn a new task to get the bio of the user, sent to `bios` along
+    /// with `chat_id` if they have one. Nothing is changed, so this is done
+    /// in dry-run mode too.
+    pub async fn spawn_fetch_bio(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        bios: mpsc::Sender<(ChatId, UserId, String)>,
+    ) {
+        let permit = self
+            .outstanding_limit
+            .clone()
+            .acquire_owned()
+            .await
+            .unwrap(); // Semaphore never get closed
+        let bot = self.bot.clone();
+        let cooldown = self.cooldown.clone();
+        self.tasks.spawn(async move {
+            let request = bot.get_chat(ChatId::from(user_id));
+            let result = send_once(request, &cooldown).await;
+            drop(permit);
+            match result {
+                Ok(chat) => {
+                    if let Some(bio) = chat.bio() {
+                        let _ = bios.send((chat_id, user_id, bio.to_string())).await;
+                    }
+                }
+                Err(err) => warn!("Failed to get bio of [{}]: {:?}", user_id, err),
+            }
+        });
+    }
+
+    /// Spawn a new task to check if `requester` is an admin or the owner
+    /// of the chat, `(chat_id, requester, target)` is sent to `granted` if
+    /// so. Nothing is changed, so this is done in dry-run mode too.
+    pub async fn spawn_check_privileged(
+        &self,
+        chat_id: ChatId,
+        requester: UserId,
+        target: UserId,
+        granted: mpsc::Sender<(ChatId, UserId, UserId)>,
+    ) {
+        let permit = self
+            .outstanding_limit
+            .clone()
+            .acquire_owned()
+            .await
+            .unwrap(); // Semaphore never get closed
+        let bot = self.bot.clone();
+        let cooldown = self.cooldown.clone();
+        self.tasks.spawn(async move {
+            let result = is_privileged(bot, &cooldown, chat_id, requester).await;
+            drop(permit);
+            match result {
+                Ok(true) => {
+                    let _ = granted.send((chat_id, requester, target)).await;
+                }
+                Ok(false) => info!("[{}] Ignore command from [{}]", chat_id, requester),
+                Err(err) => warn!("[{}] Failed to get [{}]: {:?}", chat_id, requester, err),
+            }
+        });
+    }
+
+    /// Spawn a new task to send a text message to the chat.
+    pub async fn spawn_send_message(&self, chat_id: ChatId, text: String) {
+        if self.is_dry_run() {
+            info!("[{}] Would send {:?} (dry run)", chat_id, text);
+            return;
+        }
+        let permit = self
+            .outstanding_limit
+            .clone()
+            .acquire_owned()
+            .await
+            .unwrap(); // Semaphore never get closed
+        let bot = self.bot.clone();
+        let cooldown = self.cooldown.clone();
+        self.tasks.spawn(async move {
+            if let Err(err) = send_message(bot, &cooldown, chat_id, text).await {
+                warn!("[{}] Failed to send message: {:?}", chat_id, err);
+            }
+            drop(permit);
+        });
+    }
+
+    /// Like `spawn_send_message()`, but the message is deleted after a
+    /// while, like ban notices.
+    pub async fn spawn_send_notice(&self, chat_id: ChatId, text: String) {
+        if self.is_dry_run() {
+            info!("[{}] Would post {:?} (dry run)", chat_id, text);
+            return;
+        }
+        let permit = self
+            .outstanding_limit
+            .clone()
+            .acquire_owned()
+            .await
+            .unwrap(); // Semaphore never get closed
+        let bot = self.bot.clone();
+        let cooldown = self.cooldown.clone();
+        let deletions = self.deletions.clone();
+        let notices = self.notices.clone();
+        self.tasks.spawn(async move {
+            let sent = send_message(bot, &cooldown, chat_id, text).await;
+            drop(permit);
+            match sent {
+                Ok(msg_id) => notices.expire(chat_id, msg_id, NOTICE_LIFETIME, deletions),
+                Err(err) => warn!("[{}] Failed to post notice: {:?}", chat_id, err),
+            }
+        });
+    }
+
+    /// Like `spawn_send_message` but only if `requester` is an admin of the chat.
+    pub async fn spawn_admin_reply(&self, chat_id: ChatId, requester: UserId, text: String) {
+        if self.is_dry_run() {
+            info!(
+                "[{}] Would reply {:?} to [{}] (dry run)",
+                chat_id, text, requester
+            );
+            return;
+        }
+        let permit = self
+            .outstanding_limit
+            .clone()
+            .acquire_owned()
+            .await
+            .unwrap(); // Semaphore never get closed
+        let bot = self.bot.clone();
+        let cooldown = self.cooldown.clone();
+        self.tasks.spawn(async move {
+            match is_privileged(bot.clone(), &cooldown, chat_id, requester).await {
+                Ok(true) => {
+                    if let Err(err) = send_message(bot, &cooldown, chat_id, text).await {
+                        warn!("[{}] Failed to send message: {:?}", chat_id, err);
+                    }
+                }
+                Ok(false) => info!("[{}] Ignore command from [{}]", chat_id, requester),
+                Err(err) => warn!("[{}] Failed to get [{}]: {:?}", chat_id, requester, err),
+            }
+            drop(permit);
+        });
+    }
+}
+
+async fn send_message(
+    bot: Bot,
+    cooldown: &Cooldown,
+    chat_id: ChatId,
+    text: String,
+) -> Result<MessageId, RequestError> {
+    cooldown.wait().await;
+    match bot.send_message(chat_id, text).send().await {
+        Ok(msg) => Ok(msg.id),
+        Err(RequestError::RetryAfter(delay)) => {
+            cooldown.extend(delay.duration());
+            Err(RequestError::RetryAfter(delay))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Send the request after the cooldown, without retry. Extend the cooldown
+/// on flood control.
+async fn send_once<R>(request: R, cooldown: &Cooldown) -> Result<Output<R>, RequestError>
+where
+    R: Request<Err = RequestError>,
+{
+    cooldown.wait().await;
+    let result = request.send().await;
+    if let Err(RequestError::RetryAfter(delay)) = &result {
+        cooldown.extend(delay.duration());
+    }
+    result
+}
+
+async fn is_privileged(
+    bot: Bot,
+    cooldown: &Cooldown,
+    chat_id: ChatId,
+    user_id: UserId,
+) -> Result<bool, RequestError> {
+    cooldown.wait().await;
+    match bot.get_chat_member(chat_id, user_id).send().await {
+        Ok(member) => Ok(member.is_privileged()),
+        Err(RequestError::RetryAfter(delay)) => {
+            cooldown.extend(delay.duration());
+            Err(RequestError::RetryAfter(delay))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Exponential backoff for the `retry`-th retry, randomized to 0.5x-1.5x so
+/// that tasks failed at the same moment won't retry in lockstep.
+pub fn backoff_delay(base: Duration, retry: u32) -> Duration {
+    (base * 2u32.pow(retry)).mul_f64(0.5 + fastrand::f64())
+}
+
+fn new_batcher(
+    bot: &Bot,
+    cooldown: &Cooldown,
+    outstanding_limit: &Arc<Semaphore>,
+    max_retry: u32,
+    config: BatchConfig,
+    delivered: Option<mpsc::UnboundedSender<Delivered>>,
+) -> Batcher {
+    let bot = bot.clone();
+    let cooldown = cooldown.clone();
+    let outstanding_limit = outstanding_limit.clone();
+    Batcher::new(config, move |chat_id, msg_ids| {
+        let bot = bot.clone();
+        let cooldown = cooldown.clone();
+        let delivered = delivered.clone();
+        let outstanding_limit = outstanding_limit.clone();
+        async move {
+            let _permit = outstanding_limit.acquire_owned().await.unwrap(); // Semaphore never get closed
+            let delete = |msg_ids| {
+                delete_messages(bot.clone(), cooldown.clone(), chat_id, msg_ids, max_retry)
+            };
+            match delete_batch(msg_ids.clone(), delete).await {
+                Ok(()) => (),
+                Err(err) if is_permanent(&err) => {
+                    warn!("[{}] Give up deleting messages: {:?}", chat_id, err)
+                }
+                Err(err) => {
+                    warn!("[{}] Failed to delete messages: {:?}", chat_id, err);
+                    return;
+                }
+            }
+            if let Some(delivered) = delivered {
+                for msg_id in msg_ids {
+                    let _ = delivered.send(Delivered::Deleted(chat_id, msg_id));
+                }
+            }
+        }
+    })
+}
+
+/// Rejected by Telegram for good, e.g. no rights to ban an admin, rather
+/// than failed on the way.
+fn is_permanent(err: &RequestError) -> bool {
+    matches!(err, RequestError::Api(_))
+}
+
+fn is_message_gone(err: &RequestError) -> bool {
+    matches!(
+        err,
+        RequestError::Api(ApiError::MessageToDeleteNotFound | ApiError::MessageIdInvalid)
+    )
+}
+
+/// Delete messages in one request with `delete`. Should the batch fail on
+/// messages already gone, delete them one by one so that the rest still go.
+async fn delete_batch<F, Fut>(msg_ids: Vec<MessageId>, mut delete: F) -> Result<(), RequestError>
+where
+    F: FnMut(Vec<MessageId>) -> Fut,
+    Fut: Future<Output = Result<(), RequestError>>,
+{
+    let err = match delete(msg_ids.clone()).await {
+        Err(err) if is_message_gone(&err) => err,
+        result => return result,
+    };
+    if msg_ids.len() == 1 {
+        debug!("Message {:?} already gone: {}", msg_ids[0], err);
+        return Ok(());
+    }
+    debug!(
+        "Batch of {} failed ({}), delete one by one",
+        msg_ids.len(),
+        err
+    );
+    let mut result = Ok(());
+    for msg_id in msg_ids {
+        match delete(vec![msg_id]).await {
+            Err(err) if is_message_gone(&err) => debug!("Message {:?} already gone", msg_id),
+            Err(err) => result = Err(err),
+            Ok(()) => (),
+        }
+    }
+    result
+}
+
+/// Errors on messages not found are left to `delete_batch()`.
+async fn delete_messages(
+    bot: Bot,
+    cooldown: Cooldown,
+    mut chat_id: ChatId,
+    msg_ids: Vec<MessageId>,
+    max_retry: u32,
+) -> Result<(), RequestError> {
+    let mut retry: u32 = 0;
+    loop {
+        cooldown.wait().await;
+        match bot.delete_messages(chat_id, msg_ids.clone()).send().await {
+            Ok(_) => break Ok(()),
+            Err(RequestError::RetryAfter(delay)) if retry < max_retry => {
+                warn!("RetryAfter received, retry deleting after {:?}", delay);
+                cooldown.extend(delay.duration());
+            }
+            Err(RequestError::Network(err)) if retry < max_retry => {
+                warn!("Delayed deleting due to network error: {}", err);
+                sleep(backoff_delay(RETRY_BASE_DELAY, retry)).await;
+            }
+            Err(RequestError::MigrateToChatId(new_chat_id)) if retry < max_retry => {
+                chat_id = new_chat_id;
+            }
+            Err(err) if is_message_gone(&err) => break Err(err),
+            Err(RequestError::Api(ApiError::MessageCantBeDeleted)) => {
+                debug!("No enough rights to delete message in group {}", chat_id);
+                break Ok(()); // No treat as error since we the bot onwer can't help with it
+            }
+            Err(RequestError::Api(ApiError::BotKicked))
+            | Err(RequestError::Api(ApiError::ChatNotFound)) => {
+                debug!("Bot was kicked from group {}", chat_id);
+                break Ok(()); // No treat as error
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to delete messages {:?} of [{}]: {}",
+                    msg_ids, chat_id, err
+                );
+                break Err(err);
+            }
+        }
+        retry += 1;
+    }
+}
+
+fn ban_request(
+    bot: &Bot,
+    chat_id: ChatId,
+    user_id: UserId,
+    revoke_messages: bool,
+) -> JsonRequest<BanChatMember> {
+    let request = bot.ban_chat_member(chat_id, user_id);
+    if revoke_messages {
+        request.revoke_messages(true)
+    } else {
+        request
+    }
+}
+
+async fn ban_user(
+    request: JsonRequest<BanChatMember>,
+    cooldown: &Cooldown,
+) -> Result<(), RequestError> {
+    // No retry here. Ban them next time.
+    cooldown.wait().await;
+    match request.send().await {
+        Ok(_) => Ok(()),
+        Err(RequestError::RetryAfter(delay)) => {
+            // Still hold off the other tasks
+            cooldown.extend(delay.duration());
+            Err(RequestError::RetryAfter(delay))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+#[tokio::test]
+async fn test_cooldown_shared() {
+    let cooldown = Cooldown::default();
+    let delay = Duration::from_millis(200);
+    let start = Instant::now();
+
+    // One task hits flood control...
+    let hit = cooldown.clone();
+    tokio::spawn(async move { hit.extend(delay) })
+        .await
+        .unwrap();
+    // ...and the other one has to wait for it before sending.
+    let other = cooldown.clone();
+    tokio::spawn(async move { other.wait().await })
+        .await
+        .unwrap();
+    assert!(start.elapsed() >= delay);
+
+    // A shorter delay never cuts an existing cooldown short
+    cooldown.extend(delay);
+    cooldown.extend(Duration::from_millis(1));
+    let start = Instant::now();
+    cooldown.wait().await;
+    assert!(start.elapsed() >= delay - Duration::from_millis(20));
+}
+
+#[tokio::test]
+async fn test_enforce_bans_after() {
+    let bot = Bot::new("0:test");
+    let now = SystemTime::now();
+    let actions = Actions::new(&bot, 2, 1, 0);
+    assert!(actions.bans_enforced_at(now));
+
+    let actions = actions.enforce_bans_after(now + Duration::from_secs(60));
+    assert!(!actions.bans_enforced_at(now));
+    assert!(actions.bans_enforced_at(now + Duration::from_secs(60)));
+    assert!(actions.bans_enforced_at(now + Duration::from_secs(120)));
+}
+
+#[tokio::test]
+async fn test_dry_run() {
+    let bot = Bot::new("0:test");
+    let actions = Actions::new(&bot, 2, 1, 0).mode(ActionMode::DryRun);
+    actions
+        .spawn_ban_user(ChatId(-1), UserId(1), true, "spam")
+        .await;
+    actions.spawn_send_message(ChatId(-1), "hi".into()).await;
+    actions.spwan_delete_message(ChatId(-1), MessageId(1)).await;
+    // Nothing spawned nor queued
+    assert!(actions.in_flight.0.lock().unwrap().is_empty());
+    assert_eq!(1, actions.outstanding_limit.available_permits());
+    assert_eq!(1, actions.deletion_limit.available_permits());
+    let tasks = actions.tasks.0.lock().unwrap().take().unwrap();
+    assert!(tasks.is_empty());
+}
+
+#[tokio::test]
+async fn test_deletions_not_blocking_bans() {
+    let bot = Bot::new("0:test");
+    let actions = Actions::new(&bot, 2, 1, 0).batch_config(BatchConfig {
+        max_batch_size: usize::MAX,
+        max_batch_delay: Duration::from_secs(3600),
+    });
+    for id in 0..1000 {
+        actions
+            .spwan_delete_message(ChatId(-1), MessageId(id))
+            .await;
+    }
+    // A ban would get its permit right away
+    assert!(actions.outstanding_limit.try_acquire().is_ok());
+}
+
+#[tokio::test]
+async fn test_throttled_deletions_not_blocking_bans() {
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Response, Server,
+    };
+    use std::{convert::Infallible, net::SocketAddr};
+
+    // Fake API with deletions under flood control
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|req| async move {
+            let body = if req.uri().path().ends_with("/DeleteMessages") {
+                r#"{"ok":false,"error_code":429,"description":"Too Many Requests",
+                    "parameters":{"retry_after":3600}}"#
+            } else {
+                r#"{"ok":true,"result":true}"#
+            };
+            Ok::<_, Infallible>(Response::new(Body::from(body)))
+        }))
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+    let url = format!("http://{}/", server.local_addr()).parse().unwrap();
+    tokio::spawn(server);
+
+    let bot = Bot::new("0:test").set_api_url(url);
+    let (tx, mut delivered) = mpsc::unbounded_channel();
+    let actions = Actions::new(&bot, 2, 1, 1)
+        .batch_config(BatchConfig {
+            max_batch_size: 1,
+            max_batch_delay: Duration::from_secs(3600),
+        })
+        .report_delivered(tx);
+    for id in 0..10 {
+        actions
+            .spwan_delete_message(ChatId(-1), MessageId(id))
+            .await;
+    }
+    // Let the deletions hit flood control
+    while actions.deletion_cooldown.0.lock().unwrap().is_none() {
+        sleep(Duration::from_millis(10)).await;
+    }
+    actions
+        .spawn_ban_user(ChatId(-1), UserId(1), true, "spam")
+        .await;
+    let banned = timeout(Duration::from_secs(5), delivered.recv()).await;
+    assert_eq!(
+        Some(Delivered::Banned(ChatId(-1), UserId(1))),
+        banned.unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_delete_batch_fallback() {
+    let deleted = Mutex::new(Vec::new());
+    let delete = |msg_ids: Vec<MessageId>| {
+        let deleted = &deleted;
+        async move {
+            // Message 2 is gone already
+            if msg_ids.contains(&MessageId(2)) {
+                return Err(RequestError::Api(ApiError::MessageToDeleteNotFound));
+            }
+            deleted.lock().unwrap().extend(msg_ids);
+            Ok(())
+        }
+    };
+    let msg_ids = vec![MessageId(1), MessageId(2), MessageId(3)];
+    delete_batch(msg_ids, delete).await.unwrap();
+    assert_eq!(vec![MessageId(1), MessageId(3)], *deleted.lock().unwrap());
+
+    // Other errors are not worked around
+    let failed = delete_batch(vec![MessageId(1), MessageId(3)], |_| async {
+        Err(RequestError::Api(ApiError::MessageCantBeDeleted))
+    });
+    assert!(failed.await.is_err());
+}
+
+#[tokio::test]
+async fn test_shutdown() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let bot = Bot::new("0:test");
+    let actions = Actions::new(&bot, 2, 1, 0);
+    let done = Arc::new(AtomicBool::new(false));
+    let task_done = done.clone();
+    actions.tasks.spawn(async move {
+        sleep(Duration::from_millis(50)).await;
+        task_done.store(true, Ordering::SeqCst);
+    });
+    let tasks = actions.tasks.clone();
+    actions.shutdown(Duration::from_secs(10)).await;
+    assert!(done.load(Ordering::SeqCst));
+    assert!(tasks.is_closed());
+}
+
+#[test]
+fn test_ban_request_revoke() {
+    use teloxide::requests::HasPayload;
+
+    let bot = Bot::new("0:test");
+    let request = ban_request(&bot, ChatId(-1), UserId(1), true);
+    assert_eq!(Some(true), request.payload_ref().revoke_messages);
+    let request = ban_request(&bot, ChatId(-1), UserId(1), false);
+    assert_eq!(None, request.payload_ref().revoke_messages);
+}
+
+#[tokio::test]
+async fn test_ban_notice() {
+    let bot = Bot::new("0:test");
+    let actions = Actions::new(&bot, 2, 1, 0);
+    assert_eq!(None, actions.ban_notice(UserId(42), "spam"));
+
+    let actions = actions.ban_notice_template("{user} removed for {reason}".into());
+    assert_eq!(
+        Some("42 removed for spam".into()),
+        actions.ban_notice(UserId(42), "spam")
+    );
+    // Older templates get the id too, never the name
+    let actions = actions.ban_notice_template("{name} removed".into());
+    assert_eq!(
+        Some("42 removed".into()),
+        actions.ban_notice(UserId(42), "spam")
+    );
+}
+
+#[tokio::test]
+async fn test_notices_taken_on_shutdown() {
+    let bot = Bot::new("0:test");
+    let actions = Actions::new(&bot, 2, 1, 0);
+    let notices = Notices::default();
+    let lifetime = Duration::from_secs(3600);
+    notices.expire(
+        ChatId(-1),
+        MessageId(1),
+        lifetime,
+        actions.deletions.clone(),
+    );
+    // Handed over at once, instead of after their lifetime
+    assert_eq!(vec![(ChatId(-1), MessageId(1))], notices.take_all());
+    assert!(notices.take_all().is_empty());
+}
+
+#[test]
+fn test_backoff_delay() {
+    let base = Duration::from_secs(1);
+    for retry in 0..5 {
+        let expected = base * 2u32.pow(retry);
+        for _ in 0..100 {
+            let delay = backoff_delay(base, retry);
+            assert!(delay >= expected / 2, "{:?} too short", delay);
+            assert!(delay <= expected * 3 / 2, "{:?} too long", delay);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_in_flight() {
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Response, Server,
+    };
+    use std::{
+        convert::Infallible,
+        net::SocketAddr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    // Fake API counting bans, each taking a while
+    static BANS: AtomicUsize = AtomicUsize::new(0);
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|req| async move {
+            if req.uri().path().ends_with("/BanChatMember") {
+                BANS.fetch_add(1, Ordering::SeqCst);
+                sleep(Duration::from_millis(200)).await;
+            }
+            Ok::<_, Infallible>(Response::new(Body::from(r#"{"ok":true,"result":true}"#)))
+        }))
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+    let url = format!("http://{}/", server.local_addr()).parse().unwrap();
+    tokio::spawn(server);
+
+    let bot = Bot::new("0:test").set_api_url(url);
+    let (tx, mut delivered) = mpsc::unbounded_channel();
+    let actions = Actions::new(&bot, 2, 1, 0).report_delivered(tx);
+    // Back-to-back bans of the same user are sent once
+    for _ in 0..2 {
+        actions
+            .spawn_ban_user(ChatId(-1), UserId(1), true, "spam")
+            .await;
+    }
+    let banned = timeout(Duration::from_secs(5), delivered.recv()).await;
+    assert_eq!(
+        Some(Delivered::Banned(ChatId(-1), UserId(1))),
+        banned.unwrap()
+    );
+    assert_eq!(1, BANS.load(Ordering::SeqCst));
+    // Sent again once done
+    actions
+        .spawn_ban_user(ChatId(-1), UserId(1), true, "spam")
+        .await;
+    timeout(Duration::from_secs(5), delivered.recv())
+        .await
+        .unwrap();
+    assert_eq!(2, BANS.load(Ordering::SeqCst));
+}
@@ -0,0 +1,406 @@
+mod patterns;
+#[cfg(feature = "remote-classifier")]
+mod remote;
+mod stats;
+
+use std::{
+    fmt,
+    ops::{Add, AddAssign},
+    sync::LazyLock,
+};
+
+use sonic_rs::{Deserialize, Serialize};
+
+pub use patterns::{PatternError, PatternSources, SpamPatterns};
+#[cfg(feature = "remote-classifier")]
+pub use remote::RemoteClassifier;
+pub use stats::MatchStats;
+
+static DEFAULT_PATTERNS: LazyLock<SpamPatterns> = LazyLock::new(SpamPatterns::default);
+
+/// Tells how spammy a text is. `SpamPatterns` is the built-in one, others
+/// can be plugged in with `PolicyStateBuilder::classifier()`.
+pub trait SpamClassifier: fmt::Debug + Send + Sync {
+    fn classify_tier(&self, text: &str) -> RiskTier;
+
+    /// Score of the text with the default `ScoringConfig`.
+    fn classify(&self, text: &str) -> SpamState {
+        self.classify_tier(text).spam_state()
+    }
+}
+
+impl SpamClassifier for SpamPatterns {
+    fn classify_tier(&self, text: &str) -> RiskTier {
+        self.classify_text(text)
+    }
+}
+
+pub(crate) static SPAM_THREHOLD: u8 = ScoringConfig::SPAM_THRESHOLD;
+
+/// Scores given to each message by its `RiskTier`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScoringConfig {
+    /// A user is `Spam` once their score reaches this, summed up over
+    /// their messages.
+    pub spam_threshold: u8,
+    pub medium_risk_score: u8,
+    pub unknown_risk_score: u8,
+}
+
+impl ScoringConfig {
+    pub const SPAM_THRESHOLD: u8 = 100;
+    pub const MEDIUM_RISK_SCORE: u8 = Self::SPAM_THRESHOLD / 2;
+    pub const UNKNOWN_RISK_SCORE: u8 = Self::SPAM_THRESHOLD / 6;
+}
+
+impl Default for ScoringConfig {
+    fn default() -> Self {
+        Self {
+            spam_threshold: Self::SPAM_THRESHOLD,
+            medium_risk_score: Self::MEDIUM_RISK_SCORE,
+            unknown_risk_score: Self::UNKNOWN_RISK_SCORE,
+        }
+    }
+}
+
+/// Spam score of a user, summed up from each of their messages in order.
+///
+/// ```
+/// use ahgroupbot::SpamState;
+///
+/// let state = SpamState::with_score(30) + SpamState::with_score(20);
+/// assert_eq!(state.score(), Some(50));
+/// assert!(!state.is_spam());
+///
+/// let state = state + SpamState::with_score(60);
+/// assert!(state.is_spam());
+/// assert_eq!(state, SpamState::new_spam());
+///
+/// let state = state + SpamState::Authentic;
+/// assert!(state.is_authentic());
+/// assert_eq!(state.score(), None);
+/// ```
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SpamState {
+    Authentic,
+    /// Posted valid ah but not enough to be `Authentic` yet (unix time of
+    /// the first one). Risky text is still scored from then on, turning
+    /// them into `Spam` at the threshold like anyone else.
+    Trusted {
+        since_ts: u64,
+        #[serde(default)]
+        score: u8,
+    },
+    MaybeSpam(u8),
+    Spam,
+}
+
+impl Default for SpamState {
+    fn default() -> Self {
+        Self::MaybeSpam(0)
+    }
+}
+
+impl Add for SpamState {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        self.add_with(rhs, SPAM_THREHOLD)
+    }
+}
+
+impl AddAssign for SpamState {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+impl SpamState {
+    /// Like `+`, but turn into `Spam` once the score reaches `threshold`,
+    /// e.g. `ScoringConfig::spam_threshold`.
+    pub fn add_with(self, rhs: Self, threshold: u8) -> Self {
+        match (self, rhs) {
+            (Self::Authentic, _) | (_, Self::Authentic) => Self::Authentic,
+            (Self::Spam, _) | (_, Self::Spam) => Self::Spam,
+            (
+                Self::Trusted {
+                    since_ts: a,
+                    score: x,
+                },
+                Self::Trusted {
+                    since_ts: b,
+                    score: y,
+                },
+            ) => match x.saturating_add(y) {
+                score if score < threshold => Self::Trusted {
+                    since_ts: a.min(b),
+                    score,
+                },
+                _ => Self::Spam,
+            },
+            // Earning trust starts over, but trust doesn't shield from scores
+            (Self::MaybeSpam(_), trusted @ Self::Trusted { .. }) => trusted,
+            (Self::Trusted { since_ts, score }, Self::MaybeSpam(more)) => {
+                match score.saturating_add(more) {
+                    score if score < threshold => Self::Trusted { since_ts, score },
+                    _ => Self::Spam,
+                }
+            }
+            (Self::MaybeSpam(a), Self::MaybeSpam(b)) => match a.saturating_add(b) {
+                score if score < threshold => Self::MaybeSpam(score),
+                _ => Self::Spam,
+            },
+        }
+    }
+
+    /// Not yet sure, turn into `Spam` if `score` reaches the threshold.
+    pub fn with_score(score: u8) -> Self {
+        Self::MaybeSpam(0) + Self::MaybeSpam(score)
+    }
+
+    pub fn new_spam() -> Self {
+        Self::Spam
+    }
+
+    pub fn is_spam(&self) -> bool {
+        matches!(self, Self::Spam)
+    }
+
+    pub fn is_authentic(&self) -> bool {
+        matches!(self, Self::Authentic)
+    }
+
+    /// Either `Trusted` or `Authentic`, no new-user restrictions apply.
+    pub fn is_trusted(&self) -> bool {
+        matches!(self, Self::Trusted { .. } | Self::Authentic)
+    }
+
+    /// Current score if it is still `MaybeSpam`.
+    pub fn score(&self) -> Option<u8> {
+        match self {
+            Self::MaybeSpam(score) => Some(*score),
+            Self::Authentic | Self::Trusted { .. } | Self::Spam => None,
+        }
+    }
+}
+
+/// Which group of patterns a text falls into, checked in the order listed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RiskTier {
+    NoRisk,
+    HighRisk,
+    MediumRisk,
+    Unknown,
+}
+
+impl RiskTier {
+    /// Higher for riskier ones, e.g. to pick the worse of two.
+    pub fn rank(self) -> u8 {
+        match self {
+            Self::NoRisk => 0,
+            Self::Unknown => 1,
+            Self::MediumRisk => 2,
+            Self::HighRisk => 3,
+        }
+    }
+
+    pub fn spam_state(self) -> SpamState {
+        self.spam_state_with(&Default::default())
+    }
+
+    pub fn spam_state_with(self, config: &ScoringConfig) -> SpamState {
+        let score = match self {
+            Self::NoRisk => 0,
+            Self::HighRisk => return SpamState::Spam,
+            Self::MediumRisk => config.medium_risk_score,
+            Self::Unknown => config.unknown_risk_score,
+        };
+        if score < config.spam_threshold {
+            SpamState::MaybeSpam(score)
+        } else {
+            SpamState::Spam
+        }
+    }
+}
+
+impl fmt::Display for RiskTier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NoRisk => "no-risk",
+            Self::HighRisk => "high",
+            Self::MediumRisk => "medium",
+            Self::Unknown => "unknown",
+        })
+    }
+}
+
+/// Classify text with the built-in patterns.
+pub fn classify_text(text: &str) -> RiskTier {
+    DEFAULT_PATTERNS.classify_text(text)
+}
+
+pub fn check_message_text(text: &str) -> SpamState {
+    classify_text(text).spam_state()
+}
+
+pub fn check_full_name_likely_spammer(name: &str) -> bool {
+    DEFAULT_PATTERNS.check_full_name(name)
+}
+
+#[test]
+fn test_spam_state_ops() {
+    // Authentic take highest priority
+    assert_eq!(
+        SpamState::Authentic,
+        SpamState::Authentic + SpamState::Authentic
+    );
+    assert_eq!(SpamState::Authentic, SpamState::Authentic + SpamState::Spam);
+    assert_eq!(SpamState::Authentic, SpamState::Spam + SpamState::Authentic);
+    assert_eq!(
+        SpamState::Authentic,
+        SpamState::MaybeSpam(0) + SpamState::Authentic
+    );
+
+    // MaybeSpam ops
+    assert_eq!(
+        SpamState::MaybeSpam(3),
+        SpamState::MaybeSpam(1) + SpamState::MaybeSpam(2)
+    );
+    assert_eq!(
+        SpamState::Spam,
+        SpamState::MaybeSpam(1) + SpamState::MaybeSpam(SPAM_THREHOLD - 1)
+    );
+    assert_eq!(SpamState::Spam, SpamState::MaybeSpam(1) + SpamState::Spam);
+    assert_eq!(SpamState::Spam, SpamState::Spam + SpamState::MaybeSpam(1));
+
+    // Trusted start over, but are still scored
+    let trusted = SpamState::Trusted {
+        since_ts: 10,
+        score: 0,
+    };
+    assert_eq!(trusted, SpamState::MaybeSpam(90) + trusted);
+    let scored = SpamState::Trusted {
+        since_ts: 10,
+        score: 90,
+    };
+    assert_eq!(scored, trusted + SpamState::MaybeSpam(90));
+    assert_eq!(SpamState::Spam, scored + SpamState::MaybeSpam(10));
+    let later = SpamState::Trusted {
+        since_ts: 20,
+        score: 0,
+    };
+    assert_eq!(scored, scored + later);
+    assert_eq!(SpamState::Spam, scored + scored);
+    assert_eq!(SpamState::Spam, trusted + SpamState::Spam);
+    assert_eq!(SpamState::Spam, SpamState::Spam + trusted);
+    assert_eq!(SpamState::Authentic, trusted + SpamState::Authentic);
+}
+
+#[test]
+fn test_spam_state_accessors() {
+    assert_eq!(SpamState::MaybeSpam(5), SpamState::with_score(5));
+    assert_eq!(SpamState::Spam, SpamState::with_score(SPAM_THREHOLD));
+    assert_eq!(SpamState::Spam, SpamState::with_score(u8::MAX));
+    assert_eq!(Some(5), SpamState::with_score(5).score());
+    assert_eq!(None, SpamState::new_spam().score());
+    assert!(SpamState::new_spam().is_spam());
+    assert!(!SpamState::default().is_spam());
+    assert!(!SpamState::default().is_authentic());
+    assert!(!SpamState::default().is_trusted());
+    let trusted = SpamState::Trusted {
+        since_ts: 0,
+        score: 0,
+    };
+    assert!(trusted.is_trusted() && !trusted.is_authentic() && !trusted.is_spam());
+    assert_eq!(None, trusted.score());
+    assert!(SpamState::Authentic.is_trusted());
+}
+
+#[test]
+fn test_spam_text() {
+    let high = SpamState::Spam;
+    let medium = SpamState::MaybeSpam(ScoringConfig::MEDIUM_RISK_SCORE);
+    let unknown = SpamState::MaybeSpam(ScoringConfig::UNKNOWN_RISK_SCORE);
+    let no_risk = SpamState::MaybeSpam(0);
+
+    assert_eq!(no_risk, check_message_text("aaa"));
+    assert_eq!(no_risk, check_message_text("test[AAa]test"));
+    assert_eq!(no_risk, check_message_text("AHh!!"));
+    assert_eq!(no_risk, check_message_text("啊啊"));
+    assert_eq!(no_risk, check_message_text("啊啊啊，好啊"));
+    assert_eq!(high, check_message_text("开户啊5k"));
+    assert_eq!(high, check_message_text("啊 搬U 开户"));
+    assert_eq!(medium, check_message_text("啊 搞事情"));
+    assert_eq!(high, check_message_text("啊啊啊啊啊 日结"));
+    assert_eq!(medium, check_message_text("啊啊啊啊啊啊 5k"));
+    assert_eq!(unknown, check_message_text(""));
+    assert_eq!(unknown, check_message_text("123"));
+    assert_eq!(medium, check_message_text("5k"));
+    assert_eq!(medium, check_message_text("…搞事情…"));
+    assert_eq!(high, check_message_text("…搬U…"));
+    assert_eq!(high, check_message_text("…3天开户…"));
+}
+
+#[test]
+fn test_spam_text_folded() {
+    let high = SpamState::Spam;
+    let medium = SpamState::MaybeSpam(ScoringConfig::MEDIUM_RISK_SCORE);
+    let no_risk = SpamState::MaybeSpam(0);
+
+    // Fullwidth & case
+    assert_eq!(high, check_message_text("USDT"));
+    assert_eq!(high, check_message_text("ＵＳＤＴ"));
+    assert_eq!(high, check_message_text("ｕｓｄｔ"));
+    assert_eq!(high, check_message_text("…搬Ｕ…"));
+    assert_eq!(high, check_message_text("ＴＲＸ"));
+    assert_eq!(medium, check_message_text("10k"));
+    assert_eq!(medium, check_message_text("10K"));
+    assert_eq!(medium, check_message_text("１０Ｋ"));
+    assert_eq!(no_risk, check_message_text("ＡＡＡ"));
+    // Circled digits
+    assert_eq!(medium, check_message_text("⑤Ｗ"));
+    assert_eq!(medium, check_message_text("⑩k"));
+    assert_eq!(high, check_message_text("③U"));
+}
+
+#[test]
+fn test_classify_text() {
+    assert_eq!(RiskTier::NoRisk, classify_text("啊啊啊"));
+    assert_eq!(RiskTier::HighRisk, classify_text("日结"));
+    assert_eq!(RiskTier::MediumRisk, classify_text("5k"));
+    assert_eq!(RiskTier::Unknown, classify_text("hello"));
+}
+
+#[test]
+fn test_scoring_config() {
+    let config = ScoringConfig::default();
+    assert_eq!(100, config.spam_threshold);
+    assert_eq!(50, config.medium_risk_score);
+    assert_eq!(16, config.unknown_risk_score);
+    for tier in [
+        RiskTier::NoRisk,
+        RiskTier::HighRisk,
+        RiskTier::MediumRisk,
+        RiskTier::Unknown,
+    ] {
+        assert_eq!(tier.spam_state(), tier.spam_state_with(&config));
+    }
+    // Two medium-risk messages make a spammer
+    let medium = RiskTier::MediumRisk.spam_state_with(&config);
+    assert_eq!(Some(config.medium_risk_score), medium.score());
+    assert!((medium + medium).is_spam());
+
+    let strict = ScoringConfig {
+        spam_threshold: 40,
+        ..config
+    };
+    assert!(RiskTier::MediumRisk.spam_state_with(&strict).is_spam());
+}
+
+#[test]
+fn test_spam_name() {
+    assert!(check_full_name_likely_spammer("立即来🔥赚麻了"));
+    assert!(check_full_name_likely_spammer("来看竹页吧"));
+    assert!(!check_full_name_likely_spammer("_(:з」∠)_"));
+}
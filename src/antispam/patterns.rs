@@ -0,0 +1,235 @@
+use std::{fmt, io, path::Path};
+
+use regex::Regex;
+use sonic_rs::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+use super::RiskTier;
+
+/// Regex source of each pattern group.
+/// Text and full name patterns are matched against `fold_text()`-ed text,
+/// keep Latin in lowercase.
+/// The username pattern sees lowercase usernames without `_`.
+/// Missing fields on a pattern file fall back to the built-in ones.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct PatternSources {
+    pub high_risk: String,
+    pub medium_risk: String,
+    pub no_risk: String,
+    pub full_name: String,
+    pub username: String,
+}
+
+impl Default for PatternSources {
+    fn default() -> Self {
+        Self {
+            high_risk: concat!(
+                r"(\d|黑|搬|送)u|usdt|开户|(会|會)(员|員)|收入|接入|",
+                r"兼职|专职|咨询|日结|小白|钱|赚|支付|风险|主页|介绍|trx|散户|",
+                r"团队|专线|代理|合作|保底|日入|招人|商家|💵|💯|🧧|📣",
+            )
+            .into(),
+            medium_risk: r"\d(w|k)|千|万|月|天|年|最|搞|做|操作|进群|做事|事情|了解|❤️|✈️".into(),
+            no_risk: r"阿|啊|a{3,}|ah+".into(),
+            full_name: r"🔥|看竹页".into(),
+            username: r"usdt|airdrop|kanzhuye|zhuanqian".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SpamPatterns {
+    high_risk: Regex,
+    medium_risk: Regex,
+    no_risk: Regex,
+    full_name: Regex,
+    username: Regex,
+}
+
+#[derive(Debug)]
+pub enum PatternError {
+    /// Failed to read the pattern file.
+    Io(io::Error),
+    /// Pattern file is not a valid JSON object of regex strings.
+    Parse(sonic_rs::Error),
+    /// Some of regexes failed to compile, with the name of their group.
+    Regex(Vec<(&'static str, regex::Error)>),
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "failed to read pattern file: {}", err),
+            Self::Parse(err) => write!(f, "invalid pattern file: {}", err),
+            Self::Regex(errors) => {
+                write!(f, "invalid regex in")?;
+                for (name, err) in errors {
+                    write!(f, "\n[{}] {}", name, err)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+impl Default for SpamPatterns {
+    fn default() -> Self {
+        Self::compile(&Default::default()).expect("built-in patterns")
+    }
+}
+
+impl SpamPatterns {
+    /// Compile all pattern groups, reporting every one that fails.
+    pub fn compile(sources: &PatternSources) -> Result<Self, PatternError> {
+        let mut errors = Vec::new();
+        let mut compile = |name, src: &str| {
+            Regex::new(src)
+                .inspect_err(|err| errors.push((name, err.clone())))
+                .ok()
+        };
+        let high_risk = compile("high_risk", &sources.high_risk);
+        let medium_risk = compile("medium_risk", &sources.medium_risk);
+        let no_risk = compile("no_risk", &sources.no_risk);
+        let full_name = compile("full_name", &sources.full_name);
+        let username = compile("username", &sources.username);
+        match (high_risk, medium_risk, no_risk, full_name, username) {
+            (
+                Some(high_risk),
+                Some(medium_risk),
+                Some(no_risk),
+                Some(full_name),
+                Some(username),
+            ) => Ok(Self {
+                high_risk,
+                medium_risk,
+                no_risk,
+                full_name,
+                username,
+            }),
+            _ => Err(PatternError::Regex(errors)),
+        }
+    }
+
+    /// Load pattern sources from a JSON file and compile them.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, PatternError> {
+        let buf = std::fs::read(path).map_err(PatternError::Io)?;
+        let sources: PatternSources = sonic_rs::from_slice(&buf).map_err(PatternError::Parse)?;
+        Self::compile(&sources)
+    }
+
+    pub fn classify_text(&self, text: &str) -> RiskTier {
+        let text = &fold_text(text);
+        // Some 啊 must not shield the pitch around it
+        if self.high_risk.is_match(text) {
+            RiskTier::HighRisk
+        } else if self.medium_risk.is_match(text) {
+            RiskTier::MediumRisk
+        } else if self.no_risk.is_match(text) {
+            RiskTier::NoRisk
+        } else {
+            RiskTier::Unknown
+        }
+    }
+
+    pub fn check_full_name(&self, name: &str) -> bool {
+        self.full_name.is_match(&fold_text(name))
+    }
+
+    /// Usernames are only `[A-Za-z0-9_]`, with `_` often used to split up
+    /// keywords, e.g. `U_S_D_T`.
+    pub fn check_username(&self, username: &str) -> bool {
+        let folded: String = username
+            .chars()
+            .filter(|&c| c != '_')
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        self.username.is_match(&folded)
+    }
+
+    /// Whether either the display name or the username looks like a spammer.
+    pub fn check_name(&self, full_name: &str, username: Option<&str>) -> bool {
+        self.check_full_name(full_name) || username.is_some_and(|u| self.check_username(u))
+    }
+}
+
+/// Fold fullwidth, circled and other styled forms (NFKC) and case, so that
+/// `１０Ｋ`, `⑩K` and `10k` look the same to the patterns. Invisible
+/// characters put in to split up keywords are dropped.
+fn fold_text(text: &str) -> String {
+    text.nfkc()
+        .filter(|&c| !is_invisible(c))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Zero-width and other format characters that render as nothing.
+fn is_invisible(c: char) -> bool {
+    matches!(c, '\u{00ad}' | '\u{200b}'..='\u{200f}' | '\u{2060}'..='\u{2064}' | '\u{feff}')
+}
+
+#[test]
+fn test_compile_patterns() {
+    let sources = PatternSources {
+        high_risk: "(unclosed".into(),
+        full_name: "[z-a]".into(),
+        ..Default::default()
+    };
+    match SpamPatterns::compile(&sources) {
+        Err(PatternError::Regex(errors)) => {
+            let names: Vec<_> = errors.iter().map(|(name, _)| *name).collect();
+            assert_eq!(names, ["high_risk", "full_name"]);
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let sources = PatternSources {
+        high_risk: "foo".into(),
+        ..Default::default()
+    };
+    let patterns = SpamPatterns::compile(&sources).unwrap();
+    assert_eq!(RiskTier::HighRisk, patterns.classify_text("FOO"));
+    assert_eq!(RiskTier::Unknown, patterns.classify_text("日结"));
+}
+
+#[test]
+fn test_load_patterns() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("patterns.json");
+    std::fs::write(&path, r#"{"medium_risk": "bar"}"#).unwrap();
+    let patterns = SpamPatterns::load(&path).unwrap();
+    assert_eq!(RiskTier::MediumRisk, patterns.classify_text("bar"));
+    assert_eq!(RiskTier::HighRisk, patterns.classify_text("日结"));
+
+    std::fs::write(&path, "not json").unwrap();
+    assert!(matches!(
+        SpamPatterns::load(&path),
+        Err(PatternError::Parse(_))
+    ));
+}
+
+#[test]
+fn test_check_username() {
+    let patterns = SpamPatterns::default();
+    assert!(patterns.check_name("Alice", Some("Free_USDT_here")));
+    assert!(patterns.check_name("Alice", Some("kan_zhu_ye")));
+    assert!(!patterns.check_name("Alice", Some("alice_ah")));
+    assert!(!patterns.check_name("Alice", None));
+    assert!(patterns.check_name("看竹页", Some("alice_ah")));
+}
+
+#[test]
+fn test_check_full_name_folded() {
+    let patterns = SpamPatterns::default();
+    assert!(patterns.check_full_name("Alice 看\u{200b}竹\u{feff}页"));
+    let sources = PatternSources {
+        full_name: "usdt".into(),
+        ..Default::default()
+    };
+    let patterns = SpamPatterns::compile(&sources).unwrap();
+    assert!(patterns.check_full_name("ＵＳＤＴ Bob"));
+    assert!(patterns.check_full_name("U\u{200d}SDT"));
+    assert!(!patterns.check_full_name("Bob"));
+}
@@ -0,0 +1,140 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::Duration,
+};
+
+use log::warn;
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::text_hash;
+
+/// Verdicts kept for text asked before, older ones are forgotten.
+const CACHE_LEN: usize = 1000;
+
+/// Ask an HTTP service about text the patterns find medium-risk, see
+/// `PolicyState::take_remote_checks()`. The service takes `{"text": "..."}`
+/// by POST and answers with `{"spam": true|false}`.
+///
+/// Asked off the update loop, so a slow service never holds up moderation.
+/// Fails open: on timeout or any error, nothing more is done.
+#[derive(Debug)]
+pub struct RemoteClassifier {
+    client: Client,
+    url: Url,
+    timeout: Duration,
+    /// Verdicts by `text_hash()`, and their order to forget them.
+    cache: Mutex<(HashMap<String, bool>, VecDeque<String>)>,
+}
+
+#[derive(Serialize)]
+struct RemoteRequest<'a> {
+    text: &'a str,
+}
+
+#[derive(Deserialize)]
+struct RemoteVerdict {
+    spam: bool,
+}
+
+impl RemoteClassifier {
+    pub fn new(url: Url, timeout: Duration) -> Self {
+        Self {
+            client: Client::new(),
+            url,
+            timeout,
+            cache: Default::default(),
+        }
+    }
+
+    /// Whether the service finds the text spam, `None` if it failed to
+    /// answer. Text asked recently is answered from the cache.
+    pub async fn is_spam(&self, text: &str) -> Option<bool> {
+        let hash = text_hash(text);
+        if let Some(&spam) = self.cache.lock().unwrap().0.get(&hash) {
+            return Some(spam);
+        }
+        let spam = match self.ask(text).await {
+            Ok(spam) => spam,
+            Err(err) => {
+                warn!("Remote classifier failed: {}", err);
+                return None;
+            }
+        };
+        let (verdicts, order) = &mut *self.cache.lock().unwrap();
+        if verdicts.insert(hash.clone(), spam).is_none() {
+            order.push_back(hash);
+        }
+        while order.len() > CACHE_LEN {
+            if let Some(hash) = order.pop_front() {
+                verdicts.remove(&hash);
+            }
+        }
+        Some(spam)
+    }
+
+    async fn ask(&self, text: &str) -> reqwest::Result<bool> {
+        let verdict: RemoteVerdict = self
+            .client
+            .post(self.url.clone())
+            .timeout(self.timeout)
+            .json(&RemoteRequest { text })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(verdict.spam)
+    }
+}
+
+#[tokio::test]
+async fn test_remote_classifier() {
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Response, Server,
+    };
+    use std::{
+        convert::Infallible,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    // Says spam on anything asking to hurry, counting requests
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = requests.clone();
+    let make_service = make_service_fn(move |_| {
+        let counter = counter.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                    let spam = String::from_utf8_lossy(&body).contains("hurry");
+                    let body = format!(r#"{{"spam": {}}}"#, spam);
+                    Ok::<_, Infallible>(Response::new(Body::from(body)))
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+    let url = format!("http://{}/", server.local_addr()).parse().unwrap();
+    tokio::spawn(server);
+
+    let timeout = Duration::from_secs(5);
+    let remote = RemoteClassifier::new(url, timeout);
+    assert_eq!(Some(true), remote.is_spam("5k, hurry").await);
+    assert_eq!(Some(false), remote.is_spam("5k").await);
+    // Asked once only
+    assert_eq!(Some(true), remote.is_spam("5k, hurry").await);
+    assert_eq!(2, requests.load(Ordering::SeqCst));
+
+    // Down service gives no verdict
+    let url = "http://127.0.0.1:1/".parse().unwrap();
+    let remote = RemoteClassifier::new(url, timeout);
+    assert_eq!(None, remote.is_spam("5k").await);
+}
@@ -0,0 +1,88 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::RiskTier;
+
+/// Cheap counters on how the patterns match live traffic, as a health signal
+/// for a broken pattern set that silently stops catching spam.
+#[derive(Debug, Default)]
+pub struct MatchStats {
+    /// Unix time of the last high-risk match, or when the tracking started.
+    high_risk_ts: AtomicU64,
+    /// Unix time of the last medium-risk match, 0 for never.
+    medium_risk_ts: AtomicU64,
+    /// Number of texts classified since the last high-risk match.
+    since_high_risk: AtomicU64,
+}
+
+impl MatchStats {
+    pub const fn new() -> Self {
+        Self {
+            high_risk_ts: AtomicU64::new(0),
+            medium_risk_ts: AtomicU64::new(0),
+            since_high_risk: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, tier: RiskTier, now_ts: u64) {
+        // Start the clock on first use, so a fresh start is not considered stale
+        let _ = self
+            .high_risk_ts
+            .compare_exchange(0, now_ts, Ordering::Relaxed, Ordering::Relaxed);
+        match tier {
+            RiskTier::HighRisk => {
+                self.high_risk_ts.store(now_ts, Ordering::Relaxed);
+                self.since_high_risk.store(0, Ordering::Relaxed);
+            }
+            RiskTier::MediumRisk => {
+                self.medium_risk_ts.store(now_ts, Ordering::Relaxed);
+                self.since_high_risk.fetch_add(1, Ordering::Relaxed);
+            }
+            RiskTier::NoRisk | RiskTier::Unknown => {
+                self.since_high_risk.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Unix time of the last match of high- or medium-risk patterns.
+    pub fn last_match(&self, tier: RiskTier) -> Option<u64> {
+        let ts = match tier {
+            RiskTier::HighRisk => self.high_risk_ts.load(Ordering::Relaxed),
+            RiskTier::MediumRisk => self.medium_risk_ts.load(Ordering::Relaxed),
+            RiskTier::NoRisk | RiskTier::Unknown => 0,
+        };
+        (ts > 0).then_some(ts)
+    }
+
+    /// True if high-risk patterns have not matched for `max_age_secs`,
+    /// while at least `min_messages` texts have been checked meanwhile.
+    pub fn is_high_risk_stale(&self, now_ts: u64, max_age_secs: u64, min_messages: u64) -> bool {
+        let last = self.high_risk_ts.load(Ordering::Relaxed);
+        last > 0
+            && now_ts.saturating_sub(last) > max_age_secs
+            && self.since_high_risk.load(Ordering::Relaxed) >= min_messages
+    }
+}
+
+#[test]
+fn test_high_risk_stale() {
+    const DAY: u64 = 24 * 3600;
+    let stats = MatchStats::new();
+    assert!(!stats.is_high_risk_stale(10 * DAY, DAY, 0));
+
+    stats.record(RiskTier::HighRisk, DAY);
+    for _ in 0..10 {
+        stats.record(RiskTier::Unknown, 2 * DAY);
+    }
+    stats.record(RiskTier::MediumRisk, 3 * DAY);
+    assert_eq!(Some(DAY), stats.last_match(RiskTier::HighRisk));
+    assert_eq!(Some(3 * DAY), stats.last_match(RiskTier::MediumRisk));
+
+    assert!(stats.is_high_risk_stale(9 * DAY, 7 * DAY, 10));
+    // Recent enough
+    assert!(!stats.is_high_risk_stale(7 * DAY, 7 * DAY, 10));
+    // Not enough traffic
+    assert!(!stats.is_high_risk_stale(9 * DAY, 7 * DAY, 100));
+
+    stats.record(RiskTier::HighRisk, 9 * DAY);
+    assert!(!stats.is_high_risk_stale(9 * DAY, 7 * DAY, 0));
+}
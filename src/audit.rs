@@ -0,0 +1,219 @@
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+use sonic_rs::{Deserialize, Serialize};
+use teloxide::types::{ChatId, Update, UpdateKind, UserId};
+use tokio::{fs::File, io::AsyncWriteExt};
+
+use crate::{
+    policy::{Action, BanReason},
+    storage::unix_now,
+};
+
+/// What was done to a user.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Delete,
+    Mute,
+    Ban,
+    Unban,
+}
+
+impl fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Delete => write!(f, "delete"),
+            Self::Mute => write!(f, "mute"),
+            Self::Ban => write!(f, "ban"),
+            Self::Unban => write!(f, "unban"),
+        }
+    }
+}
+
+/// One line of the audit log.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// Unix timestamp of the action.
+    pub ts: u64,
+    pub chat_id: ChatId,
+    pub user_id: Option<UserId>,
+    pub action: AuditAction,
+    pub reason: Option<String>,
+    /// Hash of the message text, to tell which message it was without
+    /// keeping what people wrote, see `text_hash()`.
+    pub text_hash: Option<String>,
+}
+
+impl AuditEntry {
+    /// Entry for what `action` does to the message of `update`, `None` if
+    /// it deletes or bans nothing.
+    pub fn from_action(update: &Update, action: &Action) -> Option<Self> {
+        let message = match &update.kind {
+            UpdateKind::Message(msg) | UpdateKind::EditedMessage(msg) => msg,
+            _ => return None,
+        };
+        let (audit_action, reason) = match action {
+            Action::Delete(..) => (AuditAction::Delete, None),
+            Action::Restrict(_, _, _, duration) => {
+                let reason = format!("for {} secs", duration.as_secs());
+                (AuditAction::Mute, Some(reason))
+            }
+            Action::DeleteAndBan(_, _, _, reason) => (AuditAction::Ban, Some(reason.to_string())),
+            _ => return None,
+        };
+        let (chat_id, _) = action.get_delete()?;
+        let sender = message.from.as_ref().map(|user| user.id);
+        let (user_id, message) = match action.get_ban() {
+            // Banned on reports, it's about the reported message
+            Some((_, user_id, _)) if Some(user_id) != sender => {
+                (Some(user_id), message.reply_to_message().unwrap_or(message))
+            }
+            _ => (sender, message),
+        };
+        Some(Self {
+            ts: unix_now(),
+            chat_id,
+            user_id,
+            action: audit_action,
+            reason,
+            text_hash: message.text().or_else(|| message.caption()).map(text_hash),
+        })
+    }
+
+    /// Entry for a ban on no message in particular, see `Action::Ban`.
+    pub fn ban(chat_id: ChatId, user_id: UserId, reason: BanReason) -> Self {
+        Self {
+            ts: unix_now(),
+            chat_id,
+            user_id: Some(user_id),
+            action: AuditAction::Ban,
+            reason: Some(reason.to_string()),
+            text_hash: None,
+        }
+    }
+
+    /// Entry for a ban lifted on request of `requester`.
+    pub fn unban(chat_id: ChatId, user_id: UserId, requester: UserId) -> Self {
+        Self {
+            ts: unix_now(),
+            chat_id,
+            user_id: Some(user_id),
+            action: AuditAction::Unban,
+            reason: Some(format!("by {}", requester)),
+            text_hash: None,
+        }
+    }
+}
+
+impl fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {} in {}", self.action, self.ts, self.chat_id)?;
+        if let Some(reason) = &self.reason {
+            write!(f, " ({})", reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// FNV-1a of the text in hex. Stable across builds, unlike `DefaultHasher`.
+pub fn text_hash(text: &str) -> String {
+    let hash = text.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// Append-only JSONL file of moderation actions, for admins to review
+/// why a user got banned.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    file: File,
+}
+
+impl AuditLog {
+    pub async fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::options()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .await?;
+        Ok(Self { path, file })
+    }
+
+    pub async fn append(&mut self, entry: &AuditEntry) -> io::Result<()> {
+        let mut line = sonic_rs::to_vec(entry).map_err(io::Error::other)?;
+        line.push(b'\n');
+        // One write per line, so that a crash leaves at most the last one broken
+        self.file.write_all(&line).await
+    }
+
+    /// All entries about the user, oldest first. Broken lines are skipped.
+    pub async fn find_user(&self, user_id: UserId) -> io::Result<Vec<AuditEntry>> {
+        let buf = tokio::fs::read(&self.path).await?;
+        let mut entries = Vec::new();
+        for line in buf.split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+            match sonic_rs::from_slice::<AuditEntry>(line) {
+                Ok(entry) if entry.user_id == Some(user_id) => entries.push(entry),
+                Ok(_) => (),
+                Err(err) => warn!("Skip broken audit log line: {}", err),
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[tokio::test]
+async fn test_audit_log() {
+    use teloxide::types::MessageId;
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("audit.jsonl");
+    let update: Update = sonic_rs::from_str(
+        r#"{
+            "update_id": 1,
+            "message": {
+                "message_id": 1,
+                "date": 1700000000,
+                "chat": { "id": -1001, "type": "supergroup", "title": "ah" },
+                "from": { "id": 1, "is_bot": false, "first_name": "test" },
+                "text": "Buy USDT now"
+            }
+        }"#,
+    )
+    .unwrap();
+    let ban = Action::DeleteAndBan(ChatId(-1001), MessageId(1), UserId(1), BanReason::SpamText);
+    let entry = AuditEntry::from_action(&update, &ban).unwrap();
+    assert_eq!(AuditAction::Ban, entry.action);
+    assert_eq!(Some(UserId(1)), entry.user_id);
+    assert_eq!(Some(BanReason::SpamText.to_string()), entry.reason);
+    assert_eq!(Some(text_hash("Buy USDT now")), entry.text_hash);
+    assert_eq!(None, AuditEntry::from_action(&update, &Action::Accept));
+
+    let mut log = AuditLog::open(&path).await.unwrap();
+    log.append(&entry).await.unwrap();
+    let delete = Action::Delete(ChatId(-1001), MessageId(1));
+    let mut other = AuditEntry::from_action(&update, &delete).unwrap();
+    other.user_id = Some(UserId(2));
+    log.append(&other).await.unwrap();
+    drop(log);
+
+    // Reopened logs are appended to
+    let mut log = AuditLog::open(&path).await.unwrap();
+    let unban = AuditEntry::unban(ChatId(-1001), UserId(1), UserId(3));
+    log.append(&unban).await.unwrap();
+    assert_eq!(vec![entry, unban], log.find_user(UserId(1)).await.unwrap());
+    assert_eq!(1, log.find_user(UserId(2)).await.unwrap().len());
+    assert!(log.find_user(UserId(4)).await.unwrap().is_empty());
+}
+
+#[test]
+fn test_text_hash() {
+    assert_eq!("cbf29ce484222325", text_hash(""));
+    assert_eq!("af63dc4c8601ec8c", text_hash("a"));
+}
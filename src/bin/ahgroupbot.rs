@@ -1,73 +1,1325 @@
-use ahgroupbot::{Actions, PolicyState};
-use futures::StreamExt;
-use log::{debug, info, warn};
-use std::{env, fs, path::PathBuf, time::Duration};
+#[cfg(feature = "remote-classifier")]
+use ahgroupbot::RemoteClassifier;
+use ahgroupbot::{
+    backoff_delay, challenge_answer, unix_now, Action, ActionMode, Actions, AuditAction,
+    AuditEntry, AuditLog, BanReason, Config, ConfigError, PatternError, PendingAction,
+    PolicyConfig, PolicyState, RiskTier, SpamPatterns, StorageError,
+};
+use axum::{
+    extract::{DefaultBodyLimit, State},
+    http::StatusCode,
+    routing::get,
+};
+use futures::{stream, Stream, StreamExt};
+use log::{debug, error, info, warn};
+use std::{
+    convert::Infallible,
+    env, fmt, fs, io,
+    net::SocketAddr,
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, UNIX_EPOCH},
+};
 use teloxide::{
-    update_listeners::{polling_default, AsUpdateStream},
-    Bot, RequestError,
+    payloads::{GetUpdatesSetters, SetWebhookSetters},
+    requests::Requester,
+    types::{AllowedUpdate, ChatId, Update, UpdateKind, UserId},
+    update_listeners::{webhooks, AsUpdateStream, UpdateListener},
+    ApiError, Bot, RequestError,
 };
-use tokio::time::sleep;
-
-// Avoid unlimited concurrent requests sending to Telegram server.
-// Not sure if it is necessary, set as a safeguard anyway.
-const MAX_OUTSTANDING_REQUESTS: usize = 30;
+use tokio::{
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+    time::{interval, sleep},
+};
+use url::Url;
 
-const MAX_RETRY: u32 = 5;
+// Wait this long for ongoing deletes & bans on exit
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 const RETRY_BASE_DELAY: Duration = Duration::from_secs(2);
+// Give up if polling keeps failing for reasons other than network
+const MAX_SKIPPED_ERRORS: u32 = 20;
+
+// Warn if high-risk patterns matched nothing for a week over a hundred messages,
+// the pattern may have been broken.
+const PATTERN_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+const PATTERN_STALE_AFTER: Duration = Duration::from_secs(7 * 24 * 3600);
+const PATTERN_STALE_MIN_MESSAGES: u64 = 100;
+
+// Kick new members not answered the challenge in time, checked this often
+const CHALLENGE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+// Users listed on `/ahstats`
+const AH_LEADERBOARD_LEN: usize = 10;
+
+// The main loop marks itself alive this often, and counts as stuck if it
+// hasn't for a while
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+const HEARTBEAT_STALE_AFTER: Duration = Duration::from_secs(60);
+
+// Long polling waits this long for updates, and counts as stuck if no poll
+// has come back for a while
+const POLL_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_STALE_AFTER: Duration = Duration::from_secs(120);
+
+/// Items of a comma-separated list from the environment, trimmed. Empty
+/// ones are skipped, e.g. of `FOO=` or a trailing comma.
+fn split_list(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+/// Load spam patterns from `path`, fallback to built-in ones if it does not exist.
+fn load_patterns(path: &Path) -> Result<SpamPatterns, PatternError> {
+    match SpamPatterns::load(path) {
+        Err(PatternError::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+            info!("No {}, use built-in patterns", path.display());
+            Ok(Default::default())
+        }
+        Ok(patterns) => {
+            info!("Patterns loaded from {}", path.display());
+            Ok(patterns)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// What to do about an error from polling updates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PollErrorAction {
+    /// Transient, back off then poll again.
+    Retry,
+    /// Not about connectivity (e.g. an update we cannot parse), log and go on.
+    Skip,
+    /// Won't go away without someone fixing it.
+    Abort,
+}
+
+fn classify_poll_error(err: &RequestError) -> PollErrorAction {
+    match err {
+        RequestError::Api(ApiError::InvalidToken)
+        | RequestError::Api(ApiError::TerminatedByOtherGetUpdates) => PollErrorAction::Abort,
+        RequestError::Network(_) | RequestError::RetryAfter(_) | RequestError::Io(_) => {
+            PollErrorAction::Retry
+        }
+        RequestError::Api(_)
+        | RequestError::InvalidJson { .. }
+        | RequestError::MigrateToChatId(_) => PollErrorAction::Skip,
+    }
+}
+
+/// Run `f` until it succeeds, at most `max_retry` more times with backoff.
+async fn retry_with_backoff<T, E: fmt::Display>(
+    max_retry: u32,
+    base_delay: Duration,
+    mut f: impl AsyncFnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut retry = 0;
+    loop {
+        match f().await {
+            Err(err) if retry < max_retry => {
+                warn!("Retry after error: {}", err);
+                sleep(backoff_delay(base_delay, retry)).await;
+                retry += 1;
+            }
+            result => break result,
+        }
+    }
+}
+
+/// Long polling for updates. Unlike teloxide's `Polling`, each successful
+/// `getUpdates` is noted in `health`, also those with nothing new.
+fn poll_updates(
+    bot: Bot,
+    allowed_updates: Vec<AllowedUpdate>,
+    health: Arc<Mutex<Health>>,
+) -> impl Stream<Item = Result<Update, RequestError>> {
+    let init = (bot, 0, Vec::<Update>::new().into_iter());
+    stream::unfold(init, move |(bot, mut offset, mut buffer)| {
+        let allowed_updates = allowed_updates.clone();
+        let health = health.clone();
+        async move {
+            loop {
+                if let Some(update) = buffer.next() {
+                    return Some((Ok(update), (bot, offset, buffer)));
+                }
+                let updates = bot
+                    .get_updates()
+                    .offset(offset)
+                    .timeout(POLL_TIMEOUT.as_secs() as u32)
+                    .allowed_updates(allowed_updates.clone())
+                    .await;
+                match updates {
+                    Ok(updates) => {
+                        health.lock().unwrap().last_poll = Instant::now();
+                        if let Some(update) = updates.last() {
+                            offset = update.id.as_offset();
+                        }
+                        buffer = updates.into_iter();
+                    }
+                    Err(err) => return Some((Err(err), (bot, offset, buffer))),
+                }
+            }
+        }
+    })
+}
+
+/// Save the policy state, retrying on errors, and note the time in `health`.
+async fn save_state(
+    policy: &mut PolicyState,
+    max_retry: u32,
+    health: &Mutex<Health>,
+) -> Result<(), StorageError> {
+    retry_with_backoff(max_retry, RETRY_BASE_DELAY, async || policy.save().await).await?;
+    health.lock().unwrap().last_save = Some(unix_now());
+    Ok(())
+}
+
+/// Update types the policy needs to see.
+fn allowed_updates(config: &PolicyConfig) -> Vec<AllowedUpdate> {
+    let mut updates = vec![AllowedUpdate::Message, AllowedUpdate::EditedMessage];
+    if config.monitor_channels {
+        updates.push(AllowedUpdate::ChannelPost);
+    }
+    if config.captcha_timeout.is_some() {
+        updates.push(AllowedUpdate::CallbackQuery);
+    }
+    updates
+}
+
+// Updates are a few KiB at most, refuse anything far bigger
+const WEBHOOK_MAX_BODY: usize = 256 * 1024;
+
+/// A random secret for Telegram to send along with each update, made of the
+/// characters it allows.
+fn webhook_secret() -> Result<String, getrandom::Error> {
+    const CHARSET: &[u8; 64] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789_-";
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes)?;
+    Ok(bytes
+        .iter()
+        .map(|&b| CHARSET[b as usize % CHARSET.len()] as char)
+        .collect())
+}
+
+/// Teloxide's webhook handler at the path of `url`, taking only requests
+/// with `secret` and bodies up to `WEBHOOK_MAX_BODY`.
+fn webhook_router(
+    url: Url,
+    secret: String,
+) -> (impl UpdateListener<Err = Infallible>, axum::Router) {
+    let options = webhooks::Options::new(([127, 0, 0, 1], 0).into(), url).secret_token(secret);
+    let (listener, _stop, router) = webhooks::axum_no_setup(options);
+    (
+        listener,
+        router.layer(DefaultBodyLimit::max(WEBHOOK_MAX_BODY)),
+    )
+}
+
+/// Register `url` as the webhook and serve it on `addr`, return received updates.
+/// TLS is left to a reverse proxy in front. Telegram sends a random secret
+/// generated here along with each update, requests without it are rejected.
+async fn listen_webhook(
+    bot: &Bot,
+    url: Url,
+    addr: SocketAddr,
+    allowed_updates: Vec<AllowedUpdate>,
+) -> Result<impl UpdateListener<Err = Infallible>, Box<dyn std::error::Error>> {
+    let secret = webhook_secret()?;
+    let (listener, router) = webhook_router(url.clone(), secret.clone());
+    let server = TcpListener::bind(addr).await?;
+    // Teloxide's own setup can't limit update types
+    bot.set_webhook(url)
+        .secret_token(secret)
+        .allowed_updates(allowed_updates)
+        .await?;
+    tokio::spawn(async move {
+        if let Err(err) = axum::serve(server, router).await {
+            error!("Webhook server failed: {}", err);
+        }
+    });
+    info!("Listening webhook on {}", addr);
+    Ok(listener)
+}
+
+/// Liveness of the main loop and of polling, for `/healthz` and the systemd
+/// watchdog.
+#[derive(Debug)]
+struct Health {
+    last_heartbeat: Instant,
+    /// Last successful `getUpdates` or webhook request, updates or not.
+    last_poll: Instant,
+    /// Whether `last_poll` should be recent. Only for long polling, as
+    /// Telegram calls the webhook only when there are updates.
+    polling: bool,
+    /// Unix timestamps, `None` if not yet since start.
+    last_update: Option<u64>,
+    last_save: Option<u64>,
+}
+
+impl Health {
+    fn new(polling: bool) -> Self {
+        Self {
+            last_heartbeat: Instant::now(),
+            last_poll: Instant::now(),
+            polling,
+            last_update: None,
+            last_save: None,
+        }
+    }
+
+    fn is_loop_running(&self) -> bool {
+        self.last_heartbeat.elapsed() < HEARTBEAT_STALE_AFTER
+    }
+
+    fn is_polling(&self) -> bool {
+        !self.polling || self.last_poll.elapsed() < POLL_STALE_AFTER
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.is_loop_running() && self.is_polling()
+    }
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ts = |ts: Option<u64>| ts.map_or("never".into(), |ts| ts.to_string());
+        let state = if self.is_loop_running() {
+            "ok"
+        } else {
+            "stuck"
+        };
+        writeln!(f, "loop: {}", state)?;
+        let state = if self.is_polling() { "ok" } else { "stuck" };
+        let elapsed = self.last_poll.elapsed().as_secs();
+        writeln!(f, "polling: {} (last {}s ago)", state, elapsed)?;
+        writeln!(f, "last update: {}", ts(self.last_update))?;
+        writeln!(f, "last save: {}", ts(self.last_save))
+    }
+}
+
+/// Serve `health_router()` on `addr`.
+async fn serve_health(addr: SocketAddr, health: Arc<Mutex<Health>>) -> io::Result<()> {
+    let server = TcpListener::bind(addr).await?;
+    tokio::spawn(async move {
+        if let Err(err) = axum::serve(server, health_router(health)).await {
+            error!("Health check server failed: {}", err);
+        }
+    });
+    info!("Serving health check on {}", addr);
+    Ok(())
+}
+
+/// `GET /healthz`, 200 if the main loop and polling are running and 503 if
+/// stuck, with the time of the last update and save in the body.
+fn health_router(health: Arc<Mutex<Health>>) -> axum::Router {
+    async fn healthz(State(health): State<Arc<Mutex<Health>>>) -> (StatusCode, String) {
+        health_response(&health.lock().unwrap())
+    }
+    axum::Router::new()
+        .route("/healthz", get(healthz))
+        .with_state(health)
+}
+
+fn health_response(health: &Health) -> (StatusCode, String) {
+    let status = if health.is_healthy() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, health.to_string())
+}
+
+/// Tell systemd about our state (e.g. `READY=1`) if it's waiting for that,
+/// see sd_notify(3). Only socket paths are supported, not abstract ones.
+fn sd_notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(err) = sd_notify_to(Path::new(&path), state) {
+        warn!("Failed to notify systemd: {}", err);
+    }
+}
+
+fn sd_notify_to(path: &Path, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+/// How often to ping the systemd watchdog, half its timeout as suggested.
+fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.trim().parse().ok()?;
+    Some(Duration::from_micros(usec) / 2)
+}
+
+#[cfg(feature = "remote-classifier")]
+type Remote = Arc<RemoteClassifier>;
+#[cfg(not(feature = "remote-classifier"))]
+type Remote = std::convert::Infallible;
+
+#[cfg(feature = "remote-classifier")]
+fn new_remote(settings: Option<&RemoteClassifierSettings>) -> Option<Remote> {
+    let settings = settings?;
+    let remote = RemoteClassifier::new(settings.url.clone(), settings.timeout);
+    Some(Arc::new(remote))
+}
+
+#[cfg(not(feature = "remote-classifier"))]
+fn new_remote(settings: Option<&RemoteClassifierSettings>) -> Option<Remote> {
+    if settings.is_some() {
+        warn!("REMOTE_CLASSIFIER_URL ignored, built without remote-classifier");
+    }
+    None
+}
+
+/// Spawn a task to ask the remote classifier about the text, its verdict
+/// is sent to `verdicts` along with the chat and the sender.
+#[cfg(feature = "remote-classifier")]
+fn spawn_remote_check(
+    remote: &Remote,
+    (chat_id, user_id, text): (ChatId, UserId, String),
+    verdicts: tokio::sync::mpsc::Sender<(ChatId, UserId, bool)>,
+) {
+    let remote = remote.clone();
+    tokio::spawn(async move {
+        if let Some(spam) = remote.is_spam(&text).await {
+            let _ = verdicts.send((chat_id, user_id, spam)).await;
+        }
+    });
+}
+
+#[cfg(not(feature = "remote-classifier"))]
+fn spawn_remote_check(
+    remote: &Remote,
+    _: (ChatId, UserId, String),
+    _: tokio::sync::mpsc::Sender<(ChatId, UserId, bool)>,
+) {
+    match *remote {}
+}
+
+async fn ban_user(actions: &Actions, chat_id: ChatId, user_id: UserId, reason: BanReason) {
+    actions
+        .spawn_ban_user(
+            chat_id,
+            user_id,
+            reason.revoke_messages(),
+            &reason.to_string(),
+        )
+        .await;
+}
+
+/// Send again deletions and bans that were not confirmed before the last
+/// shutdown, see `PolicyState::add_pending()`.
+async fn replay_pending(actions: &Actions, policy: &mut PolicyState) {
+    let pending = policy.pending_actions();
+    if !pending.is_empty() {
+        info!("Replay {} pending actions", pending.len());
+    }
+    for action in pending {
+        match action {
+            PendingAction::Delete(chat_id, msg_id) => {
+                actions.spwan_delete_message(chat_id, msg_id).await
+            }
+            PendingAction::Ban {
+                chat_id,
+                user_id,
+                revoke_messages,
+                reason,
+            } => {
+                actions
+                    .spawn_ban_user(chat_id, user_id, revoke_messages, &reason)
+                    .await
+            }
+        }
+    }
+}
+
+/// Tell why a message is acted on, so that patterns can be judged by the log.
+fn log_dry_run(policy: &PolicyState, update: &Update, action: &Action) {
+    let text = match &update.kind {
+        UpdateKind::Message(msg) | UpdateKind::EditedMessage(msg) => msg.text().unwrap_or_default(),
+        _ => "",
+    };
+    let tier = policy.classify_text(text);
+    info!("Dry run: {:?} on {} text {:?}", action, tier, text);
+}
+
+/// Everything resolved from the environment.
+struct Settings {
+    token: String,
+    token_path: PathBuf,
+    db_path: PathBuf,
+    patterns_path: PathBuf,
+    /// See `AuditLog`.
+    audit_path: PathBuf,
+    /// `None` if the file doesn't exist.
+    config_path: Option<PathBuf>,
+    /// Loaded from the config file, then overridden by environment variables.
+    config: Config,
+    /// Unix timestamp, see `Actions::enforce_bans_after()`.
+    enforce_bans_after: Option<u64>,
+    ban_notice: Option<String>,
+    /// Log actions instead of taking them, see `ActionMode::DryRun`.
+    dry_run: bool,
+    /// Receive updates on this URL, via a server on `webhook_addr`.
+    /// Long polling if not set.
+    webhook_url: Option<Url>,
+    webhook_addr: SocketAddr,
+    /// Serve `/healthz` here if set.
+    health_addr: Option<SocketAddr>,
+    /// Only used with the `remote-classifier` feature.
+    remote_classifier: Option<RemoteClassifierSettings>,
+}
+
+#[derive(Debug, Clone)]
+struct RemoteClassifierSettings {
+    url: Url,
+    timeout: Duration,
+}
+
+impl Settings {
+    fn from_env() -> io::Result<Self> {
+        let mut token_path: PathBuf = env::var("CREDENTIALS_DIRECTORY")
+            .unwrap_or_else(|_| "./".into())
+            .into();
+        token_path.push("token");
+        let token = fs::read_to_string(&token_path).inspect_err(|e| {
+            eprintln!(
+                "fail to read token from $CREDENTIALS_DIRECTORY/token `{}`: {}",
+                token_path.display(),
+                e
+            );
+        })?;
+
+        let state_dir: PathBuf = env::var("STATE_DIRECTORY")
+            .map(|p| p.into())
+            .or_else(|_| env::current_dir())
+            .expect("STATE_DIRECTORY not a valid path");
+
+        let config_path = env::var_os("CONFIG_FILE")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| state_dir.join("config.json"));
+        let (config_path, mut config) = match Config::load(&config_path) {
+            Ok(config) => (Some(config_path), config),
+            Err(ConfigError::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+                (None, Config::default())
+            }
+            Err(err) => return Err(io::Error::other(err)),
+        };
+        let policy = &mut config.policy;
+        if let Ok(ids) = env::var("ALLOWED_BOT_IDS") {
+            policy.allowed_bots = split_list(&ids)
+                .map(|id| id.parse().map(UserId))
+                .collect::<Result<_, _>>()
+                .expect("ALLOWED_BOT_IDS not a comma-separated list of user id");
+        }
+        policy.exempt_allowed_bots |= env::var_os("EXEMPT_ALLOWED_BOTS").is_some();
+        if let Ok(ids) = env::var("LINKED_CHANNEL_IDS") {
+            policy.linked_channels = split_list(&ids)
+                .map(|id| id.parse().map(ChatId))
+                .collect::<Result<_, _>>()
+                .expect("LINKED_CHANNEL_IDS not a comma-separated list of chat id");
+        }
+        if let Ok(max) = env::var("MAX_CONSECUTIVE_DELETIONS") {
+            let max = max
+                .trim()
+                .parse()
+                .expect("MAX_CONSECUTIVE_DELETIONS not a number");
+            policy.max_consecutive_deletions = Some(max);
+        }
+        policy.cautious_name_check |= env::var_os("CAUTIOUS_NAME_CHECK").is_some();
+        policy.monitor_channels |= env::var_os("MONITOR_CHANNELS").is_some();
+        policy.log_accepted |= env::var_os("LOG_ACCEPTED").is_some();
+        if let Ok(domains) = env::var("ALLOWED_DOMAINS") {
+            policy.allowed_domains = split_list(&domains).map(str::to_lowercase).collect();
+        }
+        if let Ok(min) = env::var("MIN_AH_TO_AUTHENTIC") {
+            policy.min_ah_to_authentic = min
+                .trim()
+                .parse()
+                .expect("MIN_AH_TO_AUTHENTIC not a number");
+        }
+        if let Ok(max) = env::var("MAX_DICE_PER_WINDOW") {
+            let max = max
+                .trim()
+                .parse()
+                .expect("MAX_DICE_PER_WINDOW not a number");
+            policy.max_dice_per_window = Some(max);
+        }
+        if let Ok(secs) = env::var("DICE_WINDOW_SECS") {
+            let secs = secs.trim().parse().expect("DICE_WINDOW_SECS not a number");
+            policy.dice_window = Duration::from_secs(secs);
+        }
+        policy.allow_authentic_dice |= env::var_os("ALLOW_AUTHENTIC_DICE").is_some();
+        if let Ok(max) = env::var("MAX_MESSAGE_LEN") {
+            // 0 to disable
+            let max: usize = max.trim().parse().expect("MAX_MESSAGE_LEN not a number");
+            policy.max_message_len = (max > 0).then_some(max);
+        }
+        policy.score_long_messages |= env::var_os("SCORE_LONG_MESSAGES").is_some();
+        if let Ok(secs) = env::var("SCORE_HALF_LIFE_SECS") {
+            // 0 to disable
+            let secs = secs
+                .trim()
+                .parse()
+                .expect("SCORE_HALF_LIFE_SECS not a number");
+            policy.score_half_life = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        if let Ok(secs) = env::var("CAPTCHA_TIMEOUT_SECS") {
+            // 0 to disable
+            let secs = secs
+                .trim()
+                .parse()
+                .expect("CAPTCHA_TIMEOUT_SECS not a number");
+            policy.captcha_timeout = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        if let Ok(quorum) = env::var("REPORT_QUORUM") {
+            // 0 to disable
+            let quorum = quorum.trim().parse().expect("REPORT_QUORUM not a number");
+            policy.report_quorum = (quorum > 0).then_some(quorum);
+        }
+        if let Ok(min) = env::var("REPORT_MIN_AH") {
+            policy.min_reporter_ah = min.trim().parse().expect("REPORT_MIN_AH not a number");
+        }
+        policy.screen_bio |= env::var_os("SCREEN_BIO").is_some();
+        if let Ok(score) = env::var("MUTE_THRESHOLD") {
+            // 0 to disable
+            let score = score.trim().parse().expect("MUTE_THRESHOLD not a number");
+            policy.mute_threshold = (score > 0).then_some(score);
+        }
+        if let Ok(secs) = env::var("MUTE_SECS") {
+            let secs = secs.trim().parse().expect("MUTE_SECS not a number");
+            policy.mute_duration = Duration::from_secs(secs);
+        }
+        if let Ok(secs) = env::var("AH_STATS_COOLDOWN_SECS") {
+            let secs = secs
+                .trim()
+                .parse()
+                .expect("AH_STATS_COOLDOWN_SECS not a number");
+            policy.ah_stats_cooldown = Duration::from_secs(secs);
+        }
+        if let Ok(max) = env::var("MAX_USERS") {
+            let max = max.trim().parse().expect("MAX_USERS not a number");
+            policy.max_users = Some(max);
+        }
+        if let Ok(min) = env::var("MIN_NOA") {
+            policy.min_noa = min.trim().parse().expect("MIN_NOA not a number");
+        }
+        if let Ok(kinds) = env::var("ALLOWED_SYSTEM_MESSAGES") {
+            policy.allowed_system_messages = split_list(&kinds)
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .expect("ALLOWED_SYSTEM_MESSAGES has unknown kind");
+        }
+
+        let enforce_bans_after = env::var("ENFORCE_BANS_AFTER").ok().map(|ts| {
+            ts.trim()
+                .parse()
+                .expect("ENFORCE_BANS_AFTER not a unix timestamp")
+        });
+
+        let webhook_url = env::var("WEBHOOK_URL")
+            .ok()
+            .map(|url| url.trim().parse().expect("WEBHOOK_URL not a valid URL"));
+        let webhook_port = env::var("WEBHOOK_PORT")
+            .map(|port| port.trim().parse().expect("WEBHOOK_PORT not a port number"))
+            .unwrap_or(8443);
+
+        let health_addr = env::var("HEALTH_PORT").ok().map(|port| {
+            let port = port.trim().parse().expect("HEALTH_PORT not a port number");
+            SocketAddr::from(([127, 0, 0, 1], port))
+        });
+
+        let remote_classifier = env::var("REMOTE_CLASSIFIER_URL").ok().map(|url| {
+            let timeout = env::var("REMOTE_CLASSIFIER_TIMEOUT_MS")
+                .map(|ms| {
+                    ms.trim()
+                        .parse()
+                        .expect("REMOTE_CLASSIFIER_TIMEOUT_MS not a number")
+                })
+                .unwrap_or(2000);
+            RemoteClassifierSettings {
+                url: url
+                    .trim()
+                    .parse()
+                    .expect("REMOTE_CLASSIFIER_URL not a valid URL"),
+                timeout: Duration::from_millis(timeout),
+            }
+        });
+
+        Ok(Self {
+            token,
+            token_path,
+            db_path: state_dir.join("state.json"),
+            patterns_path: state_dir.join("patterns.json"),
+            audit_path: state_dir.join("audit.jsonl"),
+            config_path,
+            config,
+            enforce_bans_after,
+            ban_notice: env::var("BAN_NOTICE").ok(),
+            dry_run: env::var_os("DRY_RUN").is_some() || env::args().any(|arg| arg == "--dry-run"),
+            webhook_url,
+            webhook_addr: SocketAddr::from(([127, 0, 0, 1], webhook_port)),
+            health_addr,
+            remote_classifier,
+        })
+    }
+}
+
+/// Readable dump for logs, with the token redacted.
+impl fmt::Display for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "token: <redacted> from {}", self.token_path.display())?;
+        writeln!(
+            f,
+            "state: {} (imports {} if none)",
+            self.db_path.with_extension("redb").display(),
+            self.db_path.display()
+        )?;
+        if self.patterns_path.exists() {
+            writeln!(f, "patterns: {}", self.patterns_path.display())?;
+        } else {
+            writeln!(f, "patterns: built-in")?;
+        }
+        writeln!(f, "audit log: {}", self.audit_path.display())?;
+        match &self.config_path {
+            Some(path) => writeln!(f, "config: {}", path.display())?,
+            None => writeln!(f, "config: built-in")?,
+        }
+        match self.enforce_bans_after {
+            Some(ts) => writeln!(f, "enforce bans after: {}", ts)?,
+            None => writeln!(f, "enforce bans after: always enforced")?,
+        }
+        match &self.ban_notice {
+            Some(notice) => writeln!(f, "ban notice: {:?}", notice)?,
+            None => writeln!(f, "ban notice: none")?,
+        }
+        if self.dry_run {
+            writeln!(f, "dry run: only log actions")?;
+        }
+        match &self.webhook_url {
+            Some(url) => writeln!(f, "updates: webhook {} on {}", url, self.webhook_addr)?,
+            None => writeln!(f, "updates: long polling")?,
+        }
+        if let Some(addr) = self.health_addr {
+            writeln!(f, "health check: http://{}/healthz", addr)?;
+        }
+        if let Some(remote) = &self.remote_classifier {
+            writeln!(
+                f,
+                "remote classifier: {} (timeout {:?})",
+                remote.url, remote.timeout
+            )?;
+        }
+        write!(f, "{:#?}", self.config)
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
-    let mut token_path: PathBuf = env::var("CREDENTIALS_DIRECTORY")
-        .unwrap_or_else(|_| "./".into())
-        .into();
-    token_path.push("token");
-    let token = fs::read_to_string(&token_path).inspect_err(|e| {
-        eprintln!(
-            "fail to read token from $CREDENTIALS_DIRECTORY/token `{}`: {}",
-            token_path.display(),
-            e
-        );
-    })?;
-
-    let mut db_path = env::var("STATE_DIRECTORY")
-        .map(|p| p.into())
-        .or_else(|_| env::current_dir())
-        .expect("STATE_DIRECTORY not a valid path");
-    db_path.push("state.json");
-
-    let bot = Bot::new(token.trim());
-    let actions = Actions::new(&bot, MAX_OUTSTANDING_REQUESTS, MAX_RETRY);
-    let mut policy = PolicyState::new(&db_path)
+    let settings = Settings::from_env()?;
+    if env::args().skip(1).any(|arg| arg == "--print-config") {
+        println!("{}", settings);
+        return Ok(());
+    }
+    info!("Config:\n{}", settings);
+
+    let bot = Bot::new(settings.token.trim());
+    let Config {
+        policy: policy_config,
+        scoring,
+        actions: actions_config,
+        allowed_stickers,
+        allowed_sticker_sets,
+        schedule,
+    } = settings.config;
+    let max_retry = actions_config.max_retry;
+    let mut actions = Actions::new(
+        &bot,
+        actions_config.max_outstanding_requests,
+        actions_config.max_outstanding_deletions,
+        max_retry,
+    )
+    .batch_config(actions_config.batch);
+    if let Some(ts) = settings.enforce_bans_after {
+        actions = actions.enforce_bans_after(UNIX_EPOCH + Duration::from_secs(ts));
+    }
+    if let Some(template) = settings.ban_notice {
+        actions = actions.ban_notice_template(template);
+    }
+    let (delivered_tx, mut delivered_rx) = tokio::sync::mpsc::unbounded_channel();
+    if settings.dry_run {
+        actions = actions.mode(ActionMode::DryRun);
+    } else {
+        actions = actions.report_delivered(delivered_tx);
+    }
+    let patterns_path = settings.patterns_path;
+    let allowed_updates = allowed_updates(&policy_config);
+    let captcha_timeout = policy_config.captcha_timeout;
+    let remote = new_remote(settings.remote_classifier.as_ref());
+    let mut policy = PolicyState::builder(&settings.db_path)
+        .config(policy_config)
+        .scoring(scoring)
+        .schedule(schedule)
+        .patterns(load_patterns(&patterns_path)?)
+        .remote_check(remote.is_some())
+        .dry_run(settings.dry_run)
+        .build()
         .await
         .expect("Failed to open/create policy state file");
-    let mut poll = polling_default(bot.clone()).await;
-    let mut stream = Box::pin(poll.as_stream());
+    for sticker in &allowed_stickers {
+        policy.allow_sticker(None, sticker);
+    }
+    for set_name in &allowed_sticker_sets {
+        policy.allow_sticker_set(None, set_name);
+    }
+    if !settings.dry_run {
+        replay_pending(&actions, &mut policy).await;
+    }
+    let mut audit = AuditLog::open(&settings.audit_path).await?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let health = Arc::new(Mutex::new(Health::new(settings.webhook_url.is_none())));
+    let mut webhook;
+    let mut stream: Pin<Box<dyn Stream<Item = Result<Update, RequestError>>>> =
+        match settings.webhook_url {
+            Some(url) => {
+                webhook = listen_webhook(&bot, url, settings.webhook_addr, allowed_updates).await?;
+                let health = health.clone();
+                Box::pin(webhook.as_stream().map(move |update| {
+                    health.lock().unwrap().last_poll = Instant::now();
+                    update.map_err(|never| match never {})
+                }))
+            }
+            None => {
+                if let Err(err) = bot.delete_webhook().await {
+                    warn!("Failed to delete webhook: {}", err);
+                }
+                Box::pin(poll_updates(bot.clone(), allowed_updates, health.clone()))
+            }
+        };
     let mut retry_count = 0u32;
+    let mut skip_count = 0u32;
+    let mut last_health_check = Instant::now();
+    let mut challenge_check = interval(CHALLENGE_CHECK_INTERVAL);
+    let (bio_tx, mut bio_rx) = tokio::sync::mpsc::channel::<(_, _, String)>(16);
+    let (unban_tx, mut unban_rx) = tokio::sync::mpsc::channel(16);
+    let (verdict_tx, mut verdict_rx) = tokio::sync::mpsc::channel(16);
+    let (challenge_failed_tx, mut challenge_failed_rx) = tokio::sync::mpsc::channel(16);
+    if let Some(addr) = settings.health_addr {
+        serve_health(addr, health.clone()).await?;
+    }
+    let watchdog = watchdog_interval();
+    let mut heartbeat =
+        interval(watchdog.map_or(HEARTBEAT_INTERVAL, |t| t.min(HEARTBEAT_INTERVAL)));
     info!("AhGroupBot started");
-    while let Some(update) = stream.next().await {
+    sd_notify("READY=1");
+    loop {
+        let update = tokio::select! {
+            update = stream.next() => match update {
+                Some(update) => update,
+                None => break,
+            },
+            _ = hangup.recv() => {
+                // Keep the old patterns if new ones are broken
+                match load_patterns(&patterns_path) {
+                    Ok(patterns) => policy.set_patterns(patterns),
+                    Err(err) => error!("Keep current patterns, failed to reload: {}", err),
+                }
+                continue;
+            }
+            _ = heartbeat.tick() => {
+                // Only reached if the loop isn't stuck on something
+                health.lock().unwrap().last_heartbeat = Instant::now();
+                if watchdog.is_some() {
+                    sd_notify("WATCHDOG=1");
+                }
+                continue;
+            }
+            _ = challenge_check.tick() => {
+                let expired = policy.expired_challenges();
+                if expired.is_empty() {
+                    continue;
+                }
+                for (chat_id, user_id) in expired {
+                    actions.spawn_kick_user(chat_id, user_id).await;
+                }
+                save_state(&mut policy, max_retry, &health).await?;
+                continue;
+            }
+            Some((chat_id, user_id)) = challenge_failed_rx.recv() => {
+                policy.cancel_challenge(chat_id, user_id);
+                save_state(&mut policy, max_retry, &health).await?;
+                continue;
+            }
+            Some(delivered) = delivered_rx.recv() => {
+                policy.confirm_delivered(delivered);
+                continue;
+            }
+            Some((chat_id, user_id, bio)) = bio_rx.recv() => {
+                let action = policy.check_bio(chat_id, user_id, &bio);
+                if let Some((chat_id, user_id, reason)) = action.get_ban() {
+                    ban_user(&actions, chat_id, user_id, reason).await;
+                    if !settings.dry_run {
+                        policy.add_pending(&action);
+                        let entry = AuditEntry::ban(chat_id, user_id, reason);
+                        if let Err(err) = audit.append(&entry).await {
+                            warn!("Failed to write audit log: {}", err);
+                        }
+                    }
+                }
+                // Screened or banned, either way not to be lost
+                save_state(&mut policy, max_retry, &health).await?;
+                continue;
+            }
+            Some((chat_id, user_id, spam)) = verdict_rx.recv() => {
+                let action = policy.check_remote_verdict(chat_id, user_id, spam);
+                if let Some((chat_id, user_id, reason)) = action.get_ban() {
+                    ban_user(&actions, chat_id, user_id, reason).await;
+                    if !settings.dry_run {
+                        policy.add_pending(&action);
+                        let entry = AuditEntry::ban(chat_id, user_id, reason);
+                        if let Err(err) = audit.append(&entry).await {
+                            warn!("Failed to write audit log: {}", err);
+                        }
+                    }
+                }
+                save_state(&mut policy, max_retry, &health).await?;
+                continue;
+            }
+            Some((chat_id, requester, user_id)) = unban_rx.recv() => {
+                policy.pardon_user(user_id);
+                actions.spawn_unban_user(chat_id, user_id).await;
+                if !settings.dry_run {
+                    let entry = AuditEntry::unban(chat_id, user_id, requester);
+                    if let Err(err) = audit.append(&entry).await {
+                        warn!("Failed to write audit log: {}", err);
+                    }
+                }
+                actions
+                    .spawn_send_message(chat_id, format!("Unbanned {}", user_id))
+                    .await;
+                save_state(&mut policy, max_retry, &health).await?;
+                continue;
+            }
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+        };
         debug!("Update: {:?}", update);
         let update = match update {
             Ok(update) => {
                 retry_count = 0;
+                skip_count = 0;
+                health.lock().unwrap().last_update = Some(unix_now());
                 update
             }
-            Err(RequestError::Network(err)) if retry_count < MAX_RETRY => {
-                warn!("Netwrok error: {}", err);
-                sleep(RETRY_BASE_DELAY * 2u32.pow(retry_count)).await;
-                retry_count += 1;
-                continue;
-            }
-            Err(err) => return Err(err.into()),
+            Err(err) => match classify_poll_error(&err) {
+                PollErrorAction::Retry if retry_count < max_retry => {
+                    warn!("Polling error, retry later: {}", err);
+                    let delay = match &err {
+                        RequestError::RetryAfter(delay) => delay.duration(),
+                        _ => backoff_delay(RETRY_BASE_DELAY, retry_count),
+                    };
+                    sleep(delay).await;
+                    retry_count += 1;
+                    continue;
+                }
+                PollErrorAction::Skip if skip_count < MAX_SKIPPED_ERRORS => {
+                    warn!("Polling error, skipped: {}", err);
+                    skip_count += 1;
+                    continue;
+                }
+                _ => return Err(err.into()),
+            },
         };
         let action = policy.check_update(&update);
-        policy.save().await?;
+        if settings.dry_run && action != Action::Accept {
+            log_dry_run(&policy, &update, &action);
+        }
+        if last_health_check.elapsed() > PATTERN_HEALTH_CHECK_INTERVAL {
+            last_health_check = Instant::now();
+            let now = unix_now();
+            let stats = policy.match_stats();
+            if stats.is_high_risk_stale(
+                now,
+                PATTERN_STALE_AFTER.as_secs(),
+                PATTERN_STALE_MIN_MESSAGES,
+            ) {
+                warn!(
+                    "High-risk patterns have not matched since {:?}, check if they are broken",
+                    stats.last_match(RiskTier::HighRisk)
+                );
+            }
+        }
+        if !settings.dry_run {
+            // Saved before sending, so that it's sent again if we crash
+            policy.add_pending(&action);
+        }
+        // Don't stop moderating on a transient disk hiccup. A failed save
+        // is rolled back and its users kept dirty, so a retry writes them.
+        save_state(&mut policy, max_retry, &health).await?;
         if let Some((chat_id, msg_id)) = action.get_delete() {
             actions.spwan_delete_message(chat_id, msg_id).await;
         }
-        if let Some((chat_id, user_id)) = action.get_ban() {
-            actions.spawn_ban_user(chat_id, user_id).await;
+        if let Some((chat_id, msg_id)) = action.get_delete_reported() {
+            actions.spwan_delete_message(chat_id, msg_id).await;
+        }
+        if !settings.dry_run {
+            if let Some(entry) = AuditEntry::from_action(&update, &action) {
+                if let Err(err) = audit.append(&entry).await {
+                    warn!("Failed to write audit log: {}", err);
+                }
+            }
+        }
+        if let UpdateKind::CallbackQuery(query) = &update.kind {
+            actions.spawn_answer_callback_query(query.id.clone()).await;
+        }
+        if let Some((chat_id, user_id, reason)) = action.get_ban() {
+            ban_user(&actions, chat_id, user_id, reason).await;
+        }
+        if let Some((chat_id, user_id, duration)) = action.get_restrict() {
+            actions
+                .spawn_restrict_user(chat_id, user_id, duration)
+                .await;
+        }
+        for check in policy.take_remote_checks() {
+            if let Some(remote) = &remote {
+                spawn_remote_check(remote, check, verdict_tx.clone());
+            }
+        }
+        for (chat_id, user_id) in policy.bios_to_screen(&update) {
+            actions
+                .spawn_fetch_bio(chat_id, user_id, bio_tx.clone())
+                .await;
+        }
+        if let Some((chat_id, requester, user_id)) = action.get_unban() {
+            actions
+                .spawn_check_privileged(chat_id, requester, user_id, unban_tx.clone())
+                .await;
+        }
+        if let (Some((chat_id, user_id)), Some(timeout)) = (action.get_challenge(), captcha_timeout)
+        {
+            // Mentioned by id, names of new members are often spam themselves
+            let text = format!(
+                r#"<a href="tg://user?id={}">New member</a>, press 啊 in {} seconds to stay"#,
+                user_id,
+                timeout.as_secs()
+            );
+            let answer = challenge_answer(user_id);
+            let failed = challenge_failed_tx.clone();
+            actions
+                .spawn_challenge(chat_id, user_id, text, answer, timeout, failed)
+                .await;
+        }
+        if let Some((chat_id, user_id)) = action.get_challenge_passed() {
+            actions.spawn_unrestrict_user(chat_id, user_id).await;
+        }
+        if let Some(chat_id) = action.get_ah_stats() {
+            let leaderboard = policy.ah_leaderboard(chat_id, AH_LEADERBOARD_LEN);
+            actions
+                .spawn_send_notice(chat_id, leaderboard.to_string())
+                .await;
+        }
+        if let Some((chat_id, requester, user_id)) = action.get_whois() {
+            let mut report = policy.whois(chat_id, user_id).to_string();
+            match audit.find_user(user_id).await {
+                Ok(entries) => match entries.iter().rfind(|e| e.action != AuditAction::Delete) {
+                    Some(entry) => report += &format!("\nLast ban/unban: {}", entry),
+                    None => report += "\nLast ban/unban: none",
+                },
+                Err(err) => warn!("Failed to read audit log: {}", err),
+            }
+            actions.spawn_admin_reply(chat_id, requester, report).await;
         }
     }
+    info!("Shutting down");
+    sd_notify("STOPPING=1");
+    actions.shutdown(SHUTDOWN_TIMEOUT).await;
+    save_state(&mut policy, max_retry, &health).await?;
     Ok(())
 }
+
+#[test]
+fn test_split_list() {
+    assert_eq!(0, split_list("").count());
+    assert_eq!(0, split_list(" , ").count());
+    assert_eq!(vec!["1", "2"], split_list(" 1,2, ").collect::<Vec<_>>());
+}
+
+#[test]
+fn test_classify_poll_error() {
+    use teloxide::types::{ChatId, Seconds};
+
+    assert_eq!(
+        PollErrorAction::Abort,
+        classify_poll_error(&RequestError::Api(ApiError::InvalidToken))
+    );
+    assert_eq!(
+        PollErrorAction::Abort,
+        classify_poll_error(&RequestError::Api(ApiError::TerminatedByOtherGetUpdates))
+    );
+    assert_eq!(
+        PollErrorAction::Retry,
+        classify_poll_error(&RequestError::RetryAfter(Seconds::from_seconds(5)))
+    );
+    assert_eq!(
+        PollErrorAction::Retry,
+        classify_poll_error(&RequestError::Io(io::ErrorKind::ConnectionReset.into()))
+    );
+    assert_eq!(
+        PollErrorAction::Skip,
+        classify_poll_error(&RequestError::Api(ApiError::Unknown("oops".into())))
+    );
+    assert_eq!(
+        PollErrorAction::Skip,
+        classify_poll_error(&RequestError::MigrateToChatId(ChatId(-1)))
+    );
+}
+
+#[test]
+fn test_health_response() {
+    let mut health = Health::new(true);
+    health.last_update = Some(1700000000);
+    assert_eq!(StatusCode::OK, health_response(&health).0);
+
+    health.last_heartbeat = Instant::now() - HEARTBEAT_STALE_AFTER;
+    let (status, body) = health_response(&health);
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+    assert!(body.contains("loop: stuck"), "{}", body);
+    assert!(body.contains("last update: 1700000000"), "{}", body);
+    assert!(body.contains("last save: never"), "{}", body);
+
+    // Polling stuck while the loop goes on
+    health.last_heartbeat = Instant::now();
+    health.last_poll = Instant::now() - POLL_STALE_AFTER;
+    let (status, body) = health_response(&health);
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+    assert!(body.contains("loop: ok"), "{}", body);
+    assert!(body.contains("polling: stuck"), "{}", body);
+    // No updates for long is fine on webhook
+    health.polling = false;
+    assert_eq!(StatusCode::OK, health_response(&health).0);
+}
+
+#[tokio::test]
+async fn test_health_router() {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+    };
+
+    let health = Arc::new(Mutex::new(Health::new(true)));
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(server, health_router(health)).await });
+    // Status code of a request to the path
+    let request = |method: &'static str, path: &'static str| {
+        tokio::task::spawn_blocking(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(
+                stream,
+                "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                method, path
+            )
+            .unwrap();
+            let mut resp = String::new();
+            let _ = stream.read_to_string(&mut resp);
+            resp.split(' ').nth(1).unwrap_or_default().to_string()
+        })
+    };
+
+    assert_eq!("200", request("GET", "/healthz").await.unwrap());
+    assert_eq!("404", request("GET", "/").await.unwrap());
+    assert_eq!("405", request("POST", "/healthz").await.unwrap());
+}
+
+#[test]
+fn test_sd_notify() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("notify");
+    let socket = UnixDatagram::bind(&path).unwrap();
+    sd_notify_to(&path, "READY=1").unwrap();
+    let mut buf = [0; 16];
+    let len = socket.recv(&mut buf).unwrap();
+    assert_eq!(b"READY=1", &buf[..len]);
+}
+
+#[test]
+fn test_print_settings() {
+    let settings = Settings {
+        token: "123:secret-token".into(),
+        token_path: "/run/credentials/token".into(),
+        db_path: "/var/lib/ahgroupbot/state.json".into(),
+        patterns_path: "/nonexistent/patterns.json".into(),
+        audit_path: "/var/lib/ahgroupbot/audit.jsonl".into(),
+        config_path: None,
+        config: Default::default(),
+        enforce_bans_after: Some(1700000000),
+        ban_notice: None,
+        dry_run: true,
+        webhook_url: None,
+        webhook_addr: SocketAddr::from(([127, 0, 0, 1], 8443)),
+        health_addr: Some(SocketAddr::from(([127, 0, 0, 1], 8080))),
+        remote_classifier: None,
+    };
+    let dump = settings.to_string();
+    assert!(!dump.contains("secret-token"), "{}", dump);
+    for field in [
+        "/run/credentials/token",
+        "/var/lib/ahgroupbot/state.json",
+        "patterns: built-in",
+        "/var/lib/ahgroupbot/audit.jsonl",
+        "1700000000",
+        "ban notice: none",
+        "updates: long polling",
+        "127.0.0.1:8080/healthz",
+        "dry run",
+        "max_action_age",
+        "exempt_authentic_edits",
+        "allowed_bots",
+        "exempt_allowed_bots",
+        "linked_channels",
+        "max_consecutive_deletions",
+        "min_ah_to_authentic",
+        "allowed_domains",
+        "cautious_name_check",
+        "monitor_channels",
+        "ah_history_len",
+        "log_accepted",
+        "max_dice_per_window",
+        "dice_window",
+        "allow_authentic_dice",
+        "max_message_len",
+        "score_long_messages",
+        "score_half_life",
+        "max_users",
+        "min_noa",
+        "captcha_timeout",
+        "report_quorum",
+        "min_reporter_ah",
+        "screen_bio",
+        "mute_threshold",
+        "mute_duration",
+        "ah_stats_cooldown",
+        "allowed_system_messages",
+    ] {
+        assert!(dump.contains(field), "{} missing in {}", field, dump);
+    }
+}
+
+#[test]
+fn test_settings_keep_config_flags() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    fs::write(temp_dir.path().join("token"), "123:token").unwrap();
+    let config_path = temp_dir.path().join("config.json");
+    fs::write(&config_path, r#"{"policy":{"log_accepted":true}}"#).unwrap();
+    // Only this test touches these variables
+    env::set_var("CREDENTIALS_DIRECTORY", temp_dir.path());
+    env::set_var("CONFIG_FILE", &config_path);
+    env::remove_var("LOG_ACCEPTED");
+    let settings = Settings::from_env().unwrap();
+    assert!(settings.config.policy.log_accepted);
+    assert!(!settings.config.policy.monitor_channels);
+}
+
+#[test]
+fn test_allowed_updates() {
+    let mut config = PolicyConfig::default();
+    let updates = allowed_updates(&config);
+    assert!(updates.contains(&AllowedUpdate::Message));
+    assert!(updates.contains(&AllowedUpdate::EditedMessage));
+    assert!(!updates.contains(&AllowedUpdate::ChannelPost));
+
+    config.monitor_channels = true;
+    assert!(allowed_updates(&config).contains(&AllowedUpdate::ChannelPost));
+}
+
+#[tokio::test]
+async fn test_retry_with_backoff() {
+    let delay = Duration::from_millis(1);
+    let mut attempts = 0;
+    let result = retry_with_backoff(3, delay, async || {
+        attempts += 1;
+        match attempts {
+            1 => Err(io::Error::other("disk hiccup")),
+            _ => Ok(attempts),
+        }
+    })
+    .await;
+    assert_eq!(2, result.unwrap());
+
+    let mut attempts = 0;
+    let result: Result<(), _> = retry_with_backoff(3, delay, async || {
+        attempts += 1;
+        Err(io::Error::other("disk gone"))
+    })
+    .await;
+    assert!(result.is_err());
+    assert_eq!(4, attempts);
+}
+
+#[test]
+fn test_webhook_secret() {
+    let secret = webhook_secret().unwrap();
+    assert_eq!(32, secret.len());
+    assert!(secret
+        .bytes()
+        .all(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b'-'));
+    assert_ne!(secret, webhook_secret().unwrap());
+}
+
+#[tokio::test]
+async fn test_webhook_router() {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+    };
+
+    let url = "https://example.com/hook".parse().unwrap();
+    let (mut listener, router) = webhook_router(url, "s3cret".into());
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(server, router).await });
+    let update = r#"{
+        "update_id": 1,
+        "message": {
+            "message_id": 1,
+            "date": 1700000000,
+            "chat": { "id": -1001, "type": "supergroup", "title": "ah" },
+            "from": { "id": 1, "is_bot": false, "first_name": "test" },
+            "text": "啊"
+        }
+    }"#;
+    // Status code of a POST to the hook
+    let post = |token: &'static str, body: String| {
+        tokio::task::spawn_blocking(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(
+                stream,
+                "POST /hook HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+                 X-Telegram-Bot-Api-Secret-Token: {}\r\nContent-Length: {}\r\n\r\n{}",
+                token,
+                body.len(),
+                body
+            )
+            .unwrap();
+            let mut resp = String::new();
+            let _ = stream.read_to_string(&mut resp);
+            resp.split(' ').nth(1).unwrap_or_default().to_string()
+        })
+    };
+
+    assert_eq!("401", post("wrong", update.into()).await.unwrap());
+    let huge = " ".repeat(WEBHOOK_MAX_BODY) + update;
+    assert_eq!("413", post("s3cret", huge).await.unwrap());
+    assert_eq!("200", post("s3cret", update.into()).await.unwrap());
+    let mut updates = Box::pin(listener.as_stream());
+    let received = updates.next().await.unwrap().unwrap();
+    assert_eq!(Some(ChatId(-1001)), received.chat().map(|c| c.id));
+}
@@ -0,0 +1,55 @@
+//! Validate a spam pattern file, and optionally classify sample messages with it
+//!
+//! ./check_patterns [--patterns <patterns.json>] [samples.txt]
+//!
+//! Built-in patterns are used if no pattern file given. Samples are read one
+//! message per line, and printed back as `<tier>\t<spam state>\t<message>`.
+use anyhow::bail;
+use std::{
+    env,
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    path::PathBuf,
+};
+
+use ahgroupbot::SpamPatterns;
+
+fn main() -> anyhow::Result<()> {
+    let mut patterns_path: Option<PathBuf> = None;
+    let mut samples_path: Option<PathBuf> = None;
+    let mut args = env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--patterns" {
+            match args.next() {
+                Some(path) => patterns_path = Some(path.into()),
+                None => bail!("--patterns requires a file path"),
+            }
+        } else if samples_path.is_none() {
+            samples_path = Some(arg.into());
+        } else {
+            bail!("Unexpected argument {:?}", arg);
+        }
+    }
+
+    let patterns = match &patterns_path {
+        Some(path) => {
+            eprintln!("Loading patterns from {:?}", path);
+            SpamPatterns::load(path)?
+        }
+        None => {
+            eprintln!("No pattern file given, checking built-in patterns");
+            SpamPatterns::default()
+        }
+    };
+    eprintln!("All patterns compiled");
+
+    if let Some(path) = samples_path {
+        let mut stdout = io::stdout().lock();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            let tier = patterns.classify_text(&line);
+            writeln!(stdout, "{}\t{:?}\t{}", tier, tier.spam_state(), line)?;
+        }
+    }
+    Ok(())
+}
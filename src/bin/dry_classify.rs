@@ -0,0 +1,78 @@
+//! Run sample messages through the policy, without touching any state
+//!
+//! ./dry_classify [--patterns <patterns.json>] < messages.txt
+//!
+//! Built-in patterns are used if no pattern file given. Messages are read one
+//! per line from stdin, and printed back as
+//! `<tier>\t<new user decision>\t<authentic user decision>\t<message>`.
+use anyhow::bail;
+use std::{
+    env, fs,
+    io::{self, BufRead, Write},
+    path::PathBuf,
+    process,
+};
+
+use ahgroupbot::{PolicyState, SpamPatterns, SpamState};
+
+fn dry_classify(
+    policy: &PolicyState,
+    input: impl BufRead,
+    mut output: impl Write,
+) -> io::Result<()> {
+    for line in input.lines() {
+        let line = line?;
+        let tier = policy.classify_text(&line);
+        let new_user = policy.dry_check_text(SpamState::default(), &line);
+        let authentic = policy.dry_check_text(SpamState::Authentic, &line);
+        writeln!(output, "{}\t{}\t{}\t{}", tier, new_user, authentic, line)?;
+    }
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> anyhow::Result<()> {
+    let mut patterns_path: Option<PathBuf> = None;
+    let mut args = env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--patterns" {
+            match args.next() {
+                Some(path) => patterns_path = Some(path.into()),
+                None => bail!("--patterns requires a file path"),
+            }
+        } else {
+            bail!("Unexpected argument {:?}", arg);
+        }
+    }
+    let patterns = match &patterns_path {
+        Some(path) => SpamPatterns::load(path)?,
+        None => SpamPatterns::default(),
+    };
+
+    // Empty state that is never saved, all users are hypothetical
+    let db_path = env::temp_dir().join(format!("dry_classify.{}.json", process::id()));
+    let policy = PolicyState::builder(&db_path)
+        .patterns(patterns)
+        .build()
+        .await;
+    let _ = fs::remove_file(db_path.with_extension("redb"));
+    dry_classify(&policy?, io::stdin().lock(), io::stdout().lock())?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dry_classify() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let policy = PolicyState::new(temp_dir.path().join("state.json"))
+        .await
+        .unwrap();
+    let input = "啊啊\n你好\nBuy USDT now\n";
+    let mut output = Vec::new();
+    dry_classify(&policy, input.as_bytes(), &mut output).unwrap();
+    assert_eq!(
+        "no-risk\taccept\taccept\t啊啊\n\
+         unknown\tdelete\tdelete\t你好\n\
+         high\tban (spam)\tdelete\tBuy USDT now\n",
+        String::from_utf8(output).unwrap()
+    );
+}
@@ -1,19 +1,28 @@
 //! Initilize bot status from Telegram-client-exported chat history JSONs
 //!
 //! ./parse_chat <group-1.json> [group-2.json ...] > status.json
+//! ./parse_chat --merge <state.json> <group-1.json> [group-2.json ...]
+//!
+//! Users who posted valid ah are marked authentic. Those who posted high-risk
+//! text, or got removed by someone else, are marked spam unless authentic.
+//! With `--merge`, they are added to the bot's current state instead, which
+//! keeps its last ah of each chat. The state is read the way the bot does,
+//! from `state.redb` next to the given path if there is one, and written back
+//! to `state.redb`. Stop the bot first.
 use anyhow::{anyhow, bail};
 use regex::Regex;
 use sonic_rs::{Deserialize, FastStr, Serialize};
 use std::{
+    collections::HashMap,
     env,
     fs::File,
     io::{self, Read, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::LazyLock,
 };
 use teloxide::types::{ChatId, UserId};
 
-use ahgroupbot::{SpamState, StorageData};
+use ahgroupbot::{classify_text, RiskTier, SpamState, StorageData};
 
 static RE_USER_ID: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r"^(user|channel)(\d+)$").unwrap());
@@ -28,8 +37,21 @@ struct ChatHistory {
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type")]
 enum Message {
-    Service,
-    Message { text: Text, from_id: FastStr },
+    Service {
+        #[serde(default)]
+        action: FastStr,
+        #[serde(default)]
+        actor_id: Option<FastStr>,
+        /// Names only, no ids.
+        #[serde(default)]
+        members: Vec<FastStr>,
+    },
+    Message {
+        text: Text,
+        from_id: FastStr,
+        #[serde(default)]
+        from: Option<FastStr>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -53,7 +75,22 @@ fn count_noa(text: &str) -> anyhow::Result<u32> {
     Ok((text.len() / 3).try_into()?)
 }
 
+fn parse_user_id(from_id: &str) -> anyhow::Result<UserId> {
+    let captures = RE_USER_ID
+        .captures(from_id)
+        .ok_or_else(|| anyhow!("from_id ({}) not match regex", from_id))?;
+    let id = captures.get(2).unwrap().as_str();
+    Ok(UserId(id.parse()?))
+}
+
 impl Text {
+    fn to_plain(&self) -> String {
+        match self {
+            Self::Plain(text) => text.to_string(),
+            Self::Formatted(segments) => segments.iter().map(|seg| seg.text().as_str()).collect(),
+        }
+    }
+
     fn count_noa(&self) -> anyhow::Result<u32> {
         match self {
             Self::Plain(text) => count_noa(text),
@@ -76,21 +113,108 @@ impl TextSegment {
 impl Message {
     fn parse_user_noa(&self) -> anyhow::Result<(UserId, u32)> {
         match self {
-            Self::Service => bail!("service message, not a user text message"),
-            Self::Message { text, from_id } => {
+            Self::Service { .. } => bail!("service message, not a user text message"),
+            Self::Message { text, from_id, .. } => {
                 let noa = text.count_noa()?;
-                let captures = RE_USER_ID
-                    .captures(from_id)
-                    .ok_or_else(|| anyhow!("from_id ({}) not match regex", from_id))?;
-                let id = captures.get(2).unwrap().as_str();
-                Ok((UserId(id.parse()?), noa))
+                Ok((parse_user_id(from_id)?, noa))
+            }
+        }
+    }
+}
+
+/// Add users and the last ah of `history` to `state`.
+fn parse_history(history: &ChatHistory, state: &mut StorageData) {
+    let mut last_user_noa: Option<(UserId, u32)> = None;
+    // Removed members are only given by name, `None` if shared by users
+    let mut names: HashMap<FastStr, Option<UserId>> = HashMap::new();
+    for (msg_id, msg) in history.messages.iter().enumerate() {
+        match msg {
+            Message::Message {
+                text,
+                from_id,
+                from,
+            } => {
+                let Ok(user_id) = parse_user_id(from_id) else {
+                    eprintln!("Msg#{:06} - ignored: bad from_id ({})", msg_id, from_id);
+                    continue;
+                };
+                if let Some(name) = from {
+                    let id = names.entry(name.clone()).or_insert(Some(user_id));
+                    if *id != Some(user_id) {
+                        *id = None;
+                    }
+                }
+                match msg.parse_user_noa() {
+                    Ok((user_id, noa)) => {
+                        state.users.insert(user_id, SpamState::Authentic);
+                        last_user_noa = Some((user_id, noa));
+                    }
+                    Err(_) if classify_text(&text.to_plain()) == RiskTier::HighRisk => {
+                        eprintln!("Msg#{:06} - spam from {}", msg_id, user_id);
+                        *state.users.entry(user_id).or_default() += SpamState::Spam;
+                    }
+                    Err(err) => eprintln!("Msg#{:06} - ignored: {}", msg_id, err),
+                }
             }
+            Message::Service {
+                action,
+                actor_id,
+                members,
+            } if action == "remove_members" => {
+                for name in members {
+                    let Some(&Some(user_id)) = names.get(name) else {
+                        eprintln!("Msg#{:06} - removed unknown user {}", msg_id, name);
+                        continue;
+                    };
+                    // Those leaving on their own are removed by themselves
+                    let actor = actor_id.as_deref().and_then(|id| parse_user_id(id).ok());
+                    if actor != Some(user_id) {
+                        eprintln!("Msg#{:06} - user {} removed", msg_id, user_id);
+                        *state.users.entry(user_id).or_default() += SpamState::Spam;
+                    }
+                }
+            }
+            Message::Service { .. } => (),
         }
     }
+    if let Some(user_noa) = last_user_noa {
+        state.chats.insert(history.id, user_noa);
+    }
+}
+
+/// Add users parsed to the bot's state at `path` and save it.
+fn merge_into(path: &Path, parsed: StorageData) -> anyhow::Result<()> {
+    let mut state = StorageData::load(path)?;
+    merge_state(&mut state, parsed);
+    state.save(path)?;
+    Ok(())
+}
+
+/// Add users parsed to `state`, authentic ones stay so. Last ah of chats
+/// already in `state` are kept, as they are more recent than exports.
+fn merge_state(state: &mut StorageData, parsed: StorageData) {
+    for (user_id, spam_state) in parsed.users {
+        *state.users.entry(user_id).or_default() += spam_state;
+    }
+    for (chat_id, user_noa) in parsed.chats {
+        state.chats.entry(chat_id).or_insert(user_noa);
+    }
 }
 
 fn main() -> anyhow::Result<()> {
-    let paths: Vec<PathBuf> = env::args_os().skip(1).map(PathBuf::from).collect();
+    let mut merge_path: Option<PathBuf> = None;
+    let mut paths: Vec<PathBuf> = Vec::new();
+    let mut args = env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--merge" {
+            match args.next() {
+                Some(path) => merge_path = Some(path.into()),
+                None => bail!("--merge requires a file path"),
+            }
+        } else {
+            paths.push(arg.into());
+        }
+    }
     if paths.is_empty() {
         bail!("No input JSON file provided on CLI argument");
     }
@@ -100,26 +224,98 @@ fn main() -> anyhow::Result<()> {
     for path in paths {
         eprintln!("Parsing chat history {:?}", path);
         buf.clear();
-        let mut last_user_noa: Option<(UserId, u32)> = None;
         File::open(&path)?.read_to_end(&mut buf)?;
         let history: ChatHistory = sonic_rs::from_slice(&buf)?;
-        for (msg_id, msg) in history.messages.iter().enumerate() {
-            match msg.parse_user_noa() {
-                Ok((user_id, noa)) => {
-                    output_state.users.insert(user_id, SpamState::Authentic);
-                    last_user_noa = Some((user_id, noa));
-                }
-                Err(err) => {
-                    eprintln!("Msg#{:06} - ignored: {}", msg_id, err);
-                }
-            }
-        }
-        if let Some(user_noa) = last_user_noa {
-            output_state.chats.insert(history.id, user_noa);
-        }
+        parse_history(&history, &mut output_state);
+    }
+    if let Some(path) = merge_path {
+        eprintln!("Merging into state {:?}", path);
+        return merge_into(&path, output_state);
     }
     // TODO: use to_writer_pretty after sonic_rs v0.4 released
     let buf = sonic_rs::to_vec_pretty(&output_state)?;
     io::stdout().write_all(&buf)?;
     Ok(())
 }
+
+#[test]
+fn test_parse_history() {
+    let history: ChatHistory = sonic_rs::from_str(
+        r#"{
+            "id": 1001,
+            "messages": [
+                { "type": "message", "from": "a", "from_id": "user1", "text": "啊啊" },
+                { "type": "message", "from": "b", "from_id": "user2", "text": "Buy USDT now" },
+                { "type": "message", "from": "c", "from_id": "user3", "text": "hello" },
+                { "type": "message", "from": "d", "from_id": "user4", "text": "hi" },
+                { "type": "message", "from": "a", "from_id": "user1",
+                  "text": [{ "type": "link", "text": "t.me/usdt" }, " Buy USDT now"] },
+                { "type": "service", "action": "remove_members",
+                  "actor_id": "user1", "members": ["c", "x"] },
+                { "type": "service", "action": "remove_members",
+                  "actor_id": "user4", "members": ["d"] },
+                { "type": "service", "action": "pin_message", "actor_id": "user1" }
+            ]
+        }"#,
+    )
+    .unwrap();
+    let mut state = StorageData::default();
+    parse_history(&history, &mut state);
+    assert_eq!(
+        Some((UserId(1), 2)),
+        state.chats.get(&ChatId(1001)).copied()
+    );
+    // Authentic ones kept authentic
+    assert_eq!(Some(&SpamState::Authentic), state.users.get(&UserId(1)));
+    assert_eq!(Some(&SpamState::Spam), state.users.get(&UserId(2)));
+    assert_eq!(Some(&SpamState::Spam), state.users.get(&UserId(3)));
+    // Left on their own
+    assert_eq!(None, state.users.get(&UserId(4)));
+}
+
+#[test]
+fn test_merge_state() {
+    let mut state = StorageData::default();
+    state.users.insert(UserId(1), SpamState::Authentic);
+    state.users.insert(UserId(2), SpamState::MaybeSpam(10));
+    state.users.insert(UserId(3), SpamState::Spam);
+    state.chats.insert(ChatId(1001), (UserId(1), 5));
+    state.pending.push_back((
+        0,
+        ahgroupbot::PendingAction::Delete(ChatId(1001), teloxide::types::MessageId(1)),
+    ));
+
+    let mut parsed = StorageData::default();
+    parsed.users.insert(UserId(1), SpamState::Spam);
+    parsed.users.insert(UserId(2), SpamState::Authentic);
+    parsed.users.insert(UserId(4), SpamState::Authentic);
+    parsed.chats.insert(ChatId(1001), (UserId(2), 1));
+    parsed.chats.insert(ChatId(1002), (UserId(4), 2));
+    merge_state(&mut state, parsed);
+
+    for id in [1, 2, 4] {
+        assert_eq!(Some(&SpamState::Authentic), state.users.get(&UserId(id)));
+    }
+    assert_eq!(Some(&SpamState::Spam), state.users.get(&UserId(3)));
+    assert_eq!(Some(&(UserId(1), 5)), state.chats.get(&ChatId(1001)));
+    assert_eq!(Some(&(UserId(4), 2)), state.chats.get(&ChatId(1002)));
+    // Runtime state kept
+    assert_eq!(1, state.pending.len());
+}
+
+#[test]
+fn test_merge_into() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("state.json");
+    let mut state = StorageData::default();
+    state.users.insert(UserId(1), SpamState::Authentic);
+    state.save(&path).unwrap();
+
+    let mut parsed = StorageData::default();
+    parsed.users.insert(UserId(2), SpamState::Spam);
+    merge_into(&path, parsed).unwrap();
+    // Read back the way the bot does
+    let state = StorageData::load(&path).unwrap();
+    assert_eq!(Some(&SpamState::Authentic), state.users.get(&UserId(1)));
+    assert_eq!(Some(&SpamState::Spam), state.users.get(&UserId(2)));
+}
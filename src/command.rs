@@ -0,0 +1,62 @@
+use teloxide::types::UserId;
+
+/// Bot commands sent by group admins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Show what the bot knows about a user, given by id or by replying to them.
+    Whois(Option<UserId>),
+    /// Lift a mistaken ban and trust the user, given like `Whois`.
+    Unban(Option<UserId>),
+    /// Report the replied message as spam, open to authentic members.
+    Report,
+    /// Post the ah leaderboard of the chat, open to trusted members.
+    AhStats,
+}
+
+impl Command {
+    /// Parse `/command[@botname] [user id]`. Return `None` for non-command
+    /// text, including commands with extra words, which is left to be
+    /// screened like any other text.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut words = text.strip_prefix('/')?.split_whitespace();
+        let name = words.next()?;
+        let name = name.split_once('@').map_or(name, |(name, _)| name);
+        let target = match words.next() {
+            Some(id) => Some(UserId(id.parse().ok()?)),
+            None => None,
+        };
+        if words.next().is_some() {
+            return None;
+        }
+        match (name, target) {
+            ("whois", _) => Some(Self::Whois(target)),
+            ("unban", _) => Some(Self::Unban(target)),
+            ("report", None) => Some(Self::Report),
+            ("ahstats", None) => Some(Self::AhStats),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_parse_command() {
+    assert_eq!(Some(Command::Whois(None)), Command::parse("/whois"));
+    assert_eq!(
+        Some(Command::Whois(Some(UserId(42)))),
+        Command::parse("/whois@AhGroupBot  42")
+    );
+    assert_eq!(None, Command::parse("/whois abc"));
+    assert_eq!(
+        Some(Command::Unban(Some(UserId(42)))),
+        Command::parse("/unban 42")
+    );
+    assert_eq!(Some(Command::Unban(None)), Command::parse("/unban"));
+    assert_eq!(Some(Command::Report), Command::parse("/report@AhGroupBot"));
+    assert_eq!(Some(Command::AhStats), Command::parse("/ahstats"));
+    // Nothing to hide behind a command
+    assert_eq!(None, Command::parse("/whois 42 Buy USDT now"));
+    assert_eq!(None, Command::parse("/report 42"));
+    assert_eq!(None, Command::parse("/unknown"));
+    assert_eq!(None, Command::parse("whois"));
+    assert_eq!(None, Command::parse("啊"));
+}
@@ -0,0 +1,201 @@
+use std::{fmt, io, path::Path, time::Duration};
+
+use sonic_rs::Deserialize;
+
+use crate::{
+    action::BatchConfig,
+    antispam::ScoringConfig,
+    policy::{PolicyConfig, StrictWindow},
+};
+
+/// Settings read from a JSON file, each one falls back to its default if
+/// omitted. Durations are given in seconds.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub policy: PolicyConfig,
+    pub scoring: ScoringConfig,
+    pub actions: ActionsConfig,
+    /// File unique ids of stickers allowed in all chats, on top of the
+    /// built-in list.
+    pub allowed_stickers: Vec<String>,
+    /// Names of sticker sets allowed as a whole in all chats.
+    pub allowed_sticker_sets: Vec<String>,
+    /// Hours of the day with a stricter policy, see `StrictWindow`.
+    pub schedule: Vec<StrictWindow>,
+}
+
+/// Limits on requests made to Telegram, see `Actions::new()`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ActionsConfig {
+    pub max_outstanding_requests: usize,
+    /// Share of `max_outstanding_requests` taken by deletions, the rest is
+    /// left to bans and messages.
+    pub max_outstanding_deletions: usize,
+    /// Also used for polling and saving the state.
+    pub max_retry: u32,
+    pub batch: BatchConfig,
+}
+
+impl Default for ActionsConfig {
+    fn default() -> Self {
+        Self {
+            // Not sure if it is necessary, set as a safeguard anyway
+            max_outstanding_requests: 30,
+            max_outstanding_deletions: 10,
+            max_retry: 5,
+            batch: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// Failed to read the config file.
+    Io(io::Error),
+    /// Not valid JSON, or unknown/mistyped fields.
+    Parse(sonic_rs::Error),
+    /// Parsed but makes no sense, e.g. an empty `StrictWindow`.
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "failed to read config file: {}", err),
+            Self::Parse(err) => write!(f, "invalid config file: {}", err),
+            Self::Invalid(reason) => write!(f, "invalid config: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::Parse(err) => Some(err),
+            Self::Invalid(_) => None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let buf = std::fs::read(path).map_err(ConfigError::Io)?;
+        let config: Self = sonic_rs::from_slice(&buf).map_err(ConfigError::Parse)?;
+        if let Some(window) = config.schedule.iter().find(|w| w.is_empty()) {
+            let reason = format!("schedule starts and ends at hour {}", window.start_hour);
+            return Err(ConfigError::Invalid(reason));
+        }
+        let actions = &config.actions;
+        if !(1..actions.max_outstanding_requests).contains(&actions.max_outstanding_deletions) {
+            let reason = "max_outstanding_deletions must leave some of max_outstanding_requests";
+            return Err(ConfigError::Invalid(reason.into()));
+        }
+        Ok(config)
+    }
+}
+
+/// Read an hour of the day, 0 to 23.
+pub(crate) fn hour<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    match u8::deserialize(deserializer)? {
+        hour @ 0..24 => Ok(hour),
+        hour => Err(serde::de::Error::custom(format!(
+            "hour {} out of range",
+            hour
+        ))),
+    }
+}
+
+/// Read a `Duration` from a number of seconds, fractions allowed.
+pub(crate) fn secs<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Duration, D::Error> {
+    let secs = f64::deserialize(deserializer)?;
+    Duration::try_from_secs_f64(secs).map_err(serde::de::Error::custom)
+}
+
+/// Like `secs()`, `null` for `None`.
+pub(crate) fn opt_secs<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    Option::<f64>::deserialize(deserializer)?
+        .map(|secs| Duration::try_from_secs_f64(secs).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+#[test]
+fn test_load_config() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("config.json");
+    std::fs::write(
+        &path,
+        r#"{
+            "policy": {
+                "max_action_age": 3600,
+                "score_half_life": null,
+                "allowed_bots": [42],
+                "allowed_system_messages": ["pinned"]
+            },
+            "scoring": { "spam_threshold": 80 },
+            "actions": { "max_retry": 3, "batch": { "max_batch_delay": 0.2 } },
+            "allowed_stickers": ["AgADsticker"],
+            "allowed_sticker_sets": ["ahahah"],
+            "schedule": [{ "start_hour": 22, "end_hour": 6, "spam_threshold": 50 }]
+        }"#,
+    )
+    .unwrap();
+    let config = Config::load(&path).unwrap();
+    assert_eq!(Duration::from_secs(3600), config.policy.max_action_age);
+    assert_eq!(None, config.policy.score_half_life);
+    assert!(config
+        .policy
+        .allowed_bots
+        .contains(&teloxide::types::UserId(42)));
+    assert_eq!(1, config.policy.allowed_system_messages.len());
+    // Omitted ones keep defaults
+    assert_eq!(PolicyConfig::default().min_noa, config.policy.min_noa);
+    assert_eq!(80, config.scoring.spam_threshold);
+    assert_eq!(
+        ScoringConfig::MEDIUM_RISK_SCORE,
+        config.scoring.medium_risk_score
+    );
+    assert_eq!(3, config.actions.max_retry);
+    assert_eq!(30, config.actions.max_outstanding_requests);
+    assert_eq!(10, config.actions.max_outstanding_deletions);
+    assert_eq!(
+        Duration::from_millis(200),
+        config.actions.batch.max_batch_delay
+    );
+    assert_eq!(vec!["AgADsticker".to_string()], config.allowed_stickers);
+    assert_eq!(vec!["ahahah".to_string()], config.allowed_sticker_sets);
+    assert_eq!(Some(50), config.schedule[0].spam_threshold);
+    assert!(!config.schedule[0].hold_untrusted);
+
+    std::fs::write(&path, r#"{"policy": {"max_action_ages": 1}}"#).unwrap();
+    assert!(matches!(Config::load(&path), Err(ConfigError::Parse(_))));
+    // Hours must make a window
+    for window in [
+        r#"{ "start_hour": 22 }"#,
+        r#"{ "start_hour": 22, "end_hour": 24 }"#,
+    ] {
+        std::fs::write(&path, format!(r#"{{"schedule": [{}]}}"#, window)).unwrap();
+        assert!(matches!(Config::load(&path), Err(ConfigError::Parse(_))));
+    }
+    std::fs::write(
+        &path,
+        r#"{"schedule": [{ "start_hour": 6, "end_hour": 6 }]}"#,
+    )
+    .unwrap();
+    assert!(matches!(Config::load(&path), Err(ConfigError::Invalid(_))));
+    // Both deletions and the rest need some share
+    for share in [0, 30] {
+        let config = format!(
+            r#"{{"actions": {{"max_outstanding_deletions": {}}}}}"#,
+            share
+        );
+        std::fs::write(&path, config).unwrap();
+        assert!(matches!(Config::load(&path), Err(ConfigError::Invalid(_))));
+    }
+}
@@ -1,9 +1,26 @@
 mod action;
 mod antispam;
+mod audit;
+mod command;
+mod config;
 mod policy;
 mod storage;
 
-pub use action::Actions;
-pub use antispam::SpamState;
-pub use policy::PolicyState;
-pub use storage::Data as StorageData;
+pub use action::{backoff_delay, ActionMode, Actions, BatchConfig, Delivered};
+#[cfg(feature = "remote-classifier")]
+pub use antispam::RemoteClassifier;
+pub use antispam::{
+    check_full_name_likely_spammer, check_message_text, classify_text, MatchStats, PatternError,
+    PatternSources, RiskTier, ScoringConfig, SpamClassifier, SpamPatterns, SpamState,
+};
+pub use audit::{text_hash, AuditAction, AuditEntry, AuditLog};
+pub use command::Command;
+pub use config::{ActionsConfig, Config, ConfigError};
+pub use policy::{
+    challenge_answer, Action, AhLeaderboard, BanReason, Decision, PolicyConfig, PolicyState,
+    PolicyStateBuilder, Signals, UserReport, Verdict,
+};
+pub use storage::{
+    unix_now, AhFloodReason, AhStats, Data as StorageData, PendingAction, ReportedMessage,
+    StorageError, UserProfile,
+};
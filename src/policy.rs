@@ -1,18 +1,34 @@
-use log::{debug, info};
-use std::{collections::HashSet, convert::TryInto, path::Path, sync::LazyLock};
+use log::{debug, info, warn};
+use sonic_rs::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet, VecDeque},
+    convert::TryInto,
+    fmt, mem,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::LazyLock,
+    time::Duration,
+};
 use teloxide::{
     dispatching::dialogue::GetChatId,
     types::{
-        ChatId, ChatKind, Message, MessageEntityKind, MessageId, MessageKind, Update, UpdateKind,
-        UserId,
+        CallbackQuery, ChatId, ChatKind, Message, MessageEntityKind, MessageEntityRef, MessageId,
+        MessageKind, MessageOrigin, Sticker, Update, UpdateKind, UserId,
     },
 };
 
 use crate::{
-    antispam::{check_full_name_likely_spammer, check_message_text, SpamState},
-    storage::Storage,
+    action::Delivered,
+    antispam::{MatchStats, RiskTier, ScoringConfig, SpamClassifier, SpamPatterns, SpamState},
+    command::Command,
+    config,
+    storage::{self, unix_now, PendingAction, Storage},
 };
 
+/// Callback data of challenge buttons, followed by the user id.
+const CHALLENGE_ANSWER_PREFIX: &str = "challenge:";
+
 static ALLOWED_STICKER_FILE_IDS: LazyLock<HashSet<&'static str>> = LazyLock::new(|| {
     include_str!("stickers.txt")
         .lines()
@@ -24,21 +40,499 @@ static ALLOWED_STICKER_FILE_IDS: LazyLock<HashSet<&'static str>> = LazyLock::new
 pub enum Action {
     Accept,
     Delete(ChatId, MessageId),
-    DeleteAndBan(ChatId, MessageId, UserId),
+    /// Delete the message and ban its sender.
+    DeleteAndBan(ChatId, MessageId, UserId, BanReason),
+    /// Ban the user, on no message in particular.
+    Ban(ChatId, UserId, BanReason),
+    /// Delete the message and mute its sender for a while, see
+    /// `PolicyConfig::mute_threshold`.
+    Restrict(ChatId, MessageId, UserId, Duration),
+    /// Delete the command message, then tell the requester (if privileged)
+    /// about the target user: `(chat, command message, requester, target)`.
+    Whois(ChatId, MessageId, UserId, UserId),
+    /// Delete the command message, then unban the target if the requester
+    /// is privileged, see `pardon_user()`. Same fields as `Whois`.
+    Unban(ChatId, MessageId, UserId, UserId),
+    /// Restrict the new member and post a challenge, see
+    /// `PolicyConfig::captcha_timeout`. The join message is deleted if given.
+    Challenge(ChatId, Option<MessageId>, UserId),
+    /// Lift the restriction, and delete the challenge message if given.
+    ChallengePassed(ChatId, Option<MessageId>, UserId),
+    /// Delete the command message, then post the ah leaderboard of the chat.
+    AhStats(ChatId, MessageId),
+    /// Delete the command message and the reported one, whose sender is
+    /// trusted so not banned: `(chat, command message, reported message)`.
+    DeleteReported(ChatId, MessageId, MessageId),
 }
 
 impl Action {
     pub fn get_delete(&self) -> Option<(ChatId, MessageId)> {
         match self {
             Self::Accept => None,
-            Self::Delete(chat, msg) | Self::DeleteAndBan(chat, msg, _) => Some((*chat, *msg)),
+            Self::Delete(chat, msg)
+            | Self::DeleteAndBan(chat, msg, _, _)
+            | Self::Restrict(chat, msg, _, _)
+            | Self::Whois(chat, msg, _, _)
+            | Self::Unban(chat, msg, _, _)
+            | Self::AhStats(chat, msg)
+            | Self::DeleteReported(chat, msg, _)
+            | Self::Challenge(chat, Some(msg), _)
+            | Self::ChallengePassed(chat, Some(msg), _) => Some((*chat, *msg)),
+            Self::Challenge(_, None, _) | Self::ChallengePassed(_, None, _) | Self::Ban(..) => None,
+        }
+    }
+
+    /// Return `(chat, user, reason)` of a ban.
+    pub fn get_ban(&self) -> Option<(ChatId, UserId, BanReason)> {
+        match self {
+            Self::Accept
+            | Self::Delete(_, _)
+            | Self::Whois(..)
+            | Self::Unban(..)
+            | Self::Restrict(..)
+            | Self::AhStats(..)
+            | Self::DeleteReported(..)
+            | Self::Challenge(..)
+            | Self::ChallengePassed(..) => None,
+            Self::DeleteAndBan(chat, _, user, reason) | Self::Ban(chat, user, reason) => {
+                Some((*chat, *user, *reason))
+            }
+        }
+    }
+
+    /// Return `(chat, user, duration)` of a mute.
+    pub fn get_restrict(&self) -> Option<(ChatId, UserId, Duration)> {
+        match self {
+            Self::Restrict(chat, _, user, duration) => Some((*chat, *user, *duration)),
+            _ => None,
+        }
+    }
+
+    /// Return `(chat, requester, target)` of a whois query.
+    pub fn get_whois(&self) -> Option<(ChatId, UserId, UserId)> {
+        match self {
+            Self::Whois(chat, _, requester, target) => Some((*chat, *requester, *target)),
+            _ => None,
+        }
+    }
+
+    /// Return `(chat, user)` of a new challenge.
+    pub fn get_challenge(&self) -> Option<(ChatId, UserId)> {
+        match self {
+            Self::Challenge(chat, _, user) => Some((*chat, *user)),
+            _ => None,
+        }
+    }
+
+    /// Return `(chat, user)` of a passed challenge.
+    pub fn get_challenge_passed(&self) -> Option<(ChatId, UserId)> {
+        match self {
+            Self::ChallengePassed(chat, _, user) => Some((*chat, *user)),
+            _ => None,
+        }
+    }
+
+    /// Return the chat of an ah leaderboard request.
+    pub fn get_ah_stats(&self) -> Option<ChatId> {
+        match self {
+            Self::AhStats(chat, _) => Some(*chat),
+            _ => None,
+        }
+    }
+
+    /// Return `(chat, reported message)` of a report on a trusted user.
+    pub fn get_delete_reported(&self) -> Option<(ChatId, MessageId)> {
+        match self {
+            Self::DeleteReported(chat, _, reported) => Some((*chat, *reported)),
+            _ => None,
+        }
+    }
+
+    /// Return `(chat, requester, target)` of an unban request.
+    pub fn get_unban(&self) -> Option<(ChatId, UserId, UserId)> {
+        match self {
+            Self::Unban(chat, _, requester, target) => Some((*chat, *requester, *target)),
+            _ => None,
+        }
+    }
+}
+
+/// Why a user got banned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BanReason {
+    /// Joined with a name looks like spammer.
+    SpamName,
+    /// Spam score reached the threshold.
+    SpamText,
+    /// Too many disallowed messages in a row.
+    TooManyDeletions,
+    /// Reported by enough authentic members, see `PolicyConfig::report_quorum`.
+    Reported,
+    /// Spam in their bio, see `PolicyConfig::screen_bio`.
+    SpamBio,
+    /// Got suspicious again after being muted.
+    RepeatOffense,
+}
+
+impl BanReason {
+    /// Whether to also delete all their messages in the chat.
+    pub fn revoke_messages(&self) -> bool {
+        // Confident enough to clean up
+        matches!(self, Self::SpamName | Self::SpamBio)
+    }
+}
+
+impl fmt::Display for BanReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::SpamName => "spammer name",
+            Self::SpamText => "spam",
+            Self::TooManyDeletions => "too many disallowed messages",
+            Self::Reported => "reported as spam",
+            Self::SpamBio => "spam in bio",
+            Self::RepeatOffense => "suspicious again after mute",
+        })
+    }
+}
+
+/// Kinds of service messages, see `PolicyConfig::allowed_system_messages`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SystemMessage {
+    NewChatMembers,
+    LeftChatMember,
+    NewChatTitle,
+    NewChatPhoto,
+    DeleteChatPhoto,
+    Pinned,
+    AutoDeleteTimerChanged,
+    /// Any of video chat scheduled, started, ended or invited.
+    VideoChat,
+    /// Any of forum topic created, edited, closed or reopened.
+    ForumTopic,
+}
+
+impl SystemMessage {
+    const ALL: [Self; 9] = [
+        Self::NewChatMembers,
+        Self::LeftChatMember,
+        Self::NewChatTitle,
+        Self::NewChatPhoto,
+        Self::DeleteChatPhoto,
+        Self::Pinned,
+        Self::AutoDeleteTimerChanged,
+        Self::VideoChat,
+        Self::ForumTopic,
+    ];
+
+    pub const DEFAULT_ALLOWED: [Self; 4] = [
+        Self::NewChatTitle,
+        Self::NewChatPhoto,
+        Self::DeleteChatPhoto,
+        Self::Pinned,
+    ];
+
+    fn from_kind(kind: &MessageKind) -> Option<Self> {
+        Some(match kind {
+            MessageKind::NewChatMembers(_) => Self::NewChatMembers,
+            MessageKind::LeftChatMember(_) => Self::LeftChatMember,
+            MessageKind::NewChatTitle(_) => Self::NewChatTitle,
+            MessageKind::NewChatPhoto(_) => Self::NewChatPhoto,
+            MessageKind::DeleteChatPhoto(_) => Self::DeleteChatPhoto,
+            MessageKind::Pinned(_) => Self::Pinned,
+            MessageKind::MessageAutoDeleteTimerChanged(_) => Self::AutoDeleteTimerChanged,
+            MessageKind::VideoChatScheduled(_)
+            | MessageKind::VideoChatStarted(_)
+            | MessageKind::VideoChatEnded(_)
+            | MessageKind::VideoChatParticipantsInvited(_) => Self::VideoChat,
+            MessageKind::ForumTopicCreated(_)
+            | MessageKind::ForumTopicEdited(_)
+            | MessageKind::ForumTopicClosed(_)
+            | MessageKind::ForumTopicReopened(_) => Self::ForumTopic,
+            _ => return None,
+        })
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::NewChatMembers => "new_chat_members",
+            Self::LeftChatMember => "left_chat_member",
+            Self::NewChatTitle => "new_chat_title",
+            Self::NewChatPhoto => "new_chat_photo",
+            Self::DeleteChatPhoto => "delete_chat_photo",
+            Self::Pinned => "pinned",
+            Self::AutoDeleteTimerChanged => "auto_delete_timer_changed",
+            Self::VideoChat => "video_chat",
+            Self::ForumTopic => "forum_topic",
         }
     }
+}
+
+impl fmt::Display for SystemMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for SystemMessage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == s)
+            .ok_or_else(|| format!("unknown system message `{}`", s))
+    }
+}
+
+/// Outcome of `PolicyState::dry_check_text()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Accept,
+    Delete,
+    Mute,
+    Ban(BanReason),
+}
 
-    pub fn get_ban(&self) -> Option<(ChatId, UserId)> {
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Accept | Self::Delete(_, _) => None,
-            Self::DeleteAndBan(chat, _, user) => Some((*chat, *user)),
+            Self::Accept => f.write_str("accept"),
+            Self::Delete => f.write_str("delete"),
+            Self::Mute => f.write_str("mute"),
+            Self::Ban(reason) => write!(f, "ban ({})", reason),
+        }
+    }
+}
+
+/// Signals about a user that are not kept in the state, e.g. seen on the
+/// update being checked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Signals {
+    /// Their name looks like a spammer.
+    pub name_match: bool,
+}
+
+/// Whether to ban a user, after putting all signals together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verdict {
+    pub banned: bool,
+    pub state: SpamState,
+    /// Why they should be banned, most confident first.
+    pub reasons: Vec<BanReason>,
+}
+
+/// What the bot knows about a user, as replied to `/whois`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserReport {
+    pub user_id: UserId,
+    pub name: Option<String>,
+    pub state: SpamState,
+    /// Number of ah in their post, if they are the last one posted in the chat.
+    pub last_ah: Option<u32>,
+    /// Their accepted ah within the chat's recent history, oldest first.
+    pub recent_ah: Vec<u32>,
+}
+
+impl fmt::Display for UserReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => writeln!(f, "User {} ({})", self.user_id, name)?,
+            None => writeln!(f, "User {}", self.user_id)?,
+        }
+        match self.state {
+            SpamState::Authentic => writeln!(f, "State: authentic")?,
+            SpamState::Trusted { since_ts, score } => {
+                writeln!(f, "State: trusted since {}, score {}", since_ts, score)?
+            }
+            SpamState::MaybeSpam(score) => writeln!(f, "State: maybe spam, score {}", score)?,
+            SpamState::Spam => writeln!(f, "State: spam")?,
+        }
+        match self.last_ah {
+            Some(noa) => writeln!(f, "Last ah: {} (latest in chat)", noa)?,
+            None => writeln!(f, "Last ah: not the latest in chat")?,
+        }
+        let recent: Vec<_> = self.recent_ah.iter().map(u32::to_string).collect();
+        if recent.is_empty() {
+            write!(f, "Recent ah: none")
+        } else {
+            write!(f, "Recent ah: {}", recent.join(", "))
+        }
+    }
+}
+
+/// Stricter policy during some hours of the day, when spam waves come.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StrictWindow {
+    /// UTC hour the window starts, inclusive.
+    #[serde(deserialize_with = "config::hour")]
+    pub start_hour: u8,
+    /// UTC hour the window ends, exclusive. Wraps past midnight if before
+    /// `start_hour`, e.g. 22 to 6. Empty if the same as `start_hour`.
+    #[serde(deserialize_with = "config::hour")]
+    pub end_hour: u8,
+    /// Replaces `ScoringConfig::spam_threshold` within the window.
+    #[serde(default)]
+    pub spam_threshold: Option<u8>,
+    /// Delete all messages of members not yet trusted within the window,
+    /// after scoring them for spam. They don't count as deletions in a row.
+    #[serde(default)]
+    pub hold_untrusted: bool,
+}
+
+impl StrictWindow {
+    pub fn is_empty(&self) -> bool {
+        self.start_hour == self.end_hour
+    }
+
+    fn contains(&self, hour: u8) -> bool {
+        if self.is_empty() {
+            false
+        } else if self.start_hour < self.end_hour {
+            (self.start_hour..self.end_hour).contains(&hour)
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        }
+    }
+}
+
+/// Most ah-ers of a chat, as replied to `/ahstats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AhLeaderboard {
+    pub total: u64,
+    pub longest_streak: u32,
+    /// `(user, name, number of ah)`, most first.
+    pub top: Vec<(UserId, Option<String>, u64)>,
+}
+
+impl fmt::Display for AhLeaderboard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Total ah: {}", self.total)?;
+        write!(f, "Longest streak: {}", self.longest_streak)?;
+        for (rank, (user_id, name, noa)) in self.top.iter().enumerate() {
+            match name {
+                Some(name) => write!(f, "\n{}. {}: {}", rank + 1, name, noa)?,
+                None => write!(f, "\n{}. {}: {}", rank + 1, user_id, noa)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// Messages older than this are left alone, e.g. backlog replayed after
+    /// a long downtime. Their spam score still counts.
+    #[serde(deserialize_with = "config::secs")]
+    pub max_action_age: Duration,
+    /// Leave edits from authentic users alone instead of deleting them.
+    pub exempt_authentic_edits: bool,
+    /// Bots allowed to post in the group, other bots' messages get deleted.
+    pub allowed_bots: HashSet<UserId>,
+    /// Accept anything from `allowed_bots`, rather than applying the ah policy on them.
+    pub exempt_allowed_bots: bool,
+    /// Channels allowed to post in the group as themselves, e.g. the linked
+    /// one. Its posts forwarded by Telegram are always accepted.
+    pub linked_channels: HashSet<ChatId>,
+    /// Ban users once this many of their messages in a row got deleted.
+    pub max_consecutive_deletions: Option<u32>,
+    /// Number of valid ah a user has to post before becoming `Authentic`.
+    /// They are still subject to spam scoring until then.
+    pub min_ah_to_authentic: u32,
+    /// Trusted users may post links to these domains and their subdomains.
+    pub allowed_domains: HashSet<String>,
+    /// Don't ban on a spammer-like name alone, but give them a score just
+    /// below the threshold, so that any risky message gets them banned.
+    pub cautious_name_check: bool,
+    /// Score channel posts for early warning of spam campaigns, without
+    /// taking any action on the channel.
+    pub monitor_channels: bool,
+    /// Number of accepted ah remembered for each chat.
+    pub ah_history_len: usize,
+    /// Log each accepted ah at info level, for checking the bot's decisions.
+    pub log_accepted: bool,
+    /// Score users toward spam once they send more than this many dice or
+    /// games within `dice_window`. Such messages are deleted either way.
+    pub max_dice_per_window: Option<u32>,
+    #[serde(deserialize_with = "config::secs")]
+    pub dice_window: Duration,
+    /// Accept the first dice within `dice_window` from authentic users.
+    pub allow_authentic_dice: bool,
+    /// Delete text longer than this many characters, unless from authentic users.
+    pub max_message_len: Option<usize>,
+    /// Also score such text toward spam like medium-risk text.
+    pub score_long_messages: bool,
+    /// Spam scores of users not yet spam halve every this long, `None` to
+    /// keep them forever.
+    #[serde(deserialize_with = "config::opt_secs")]
+    pub score_half_life: Option<Duration>,
+    /// Forget the least recently scored users not yet trusted once more
+    /// users than this are stored, to bound the state size.
+    pub max_users: Option<usize>,
+    /// Fewest ah in a message to count, fewer are deleted. An allowed
+    /// sticker counts as one.
+    pub min_noa: u32,
+    /// Service messages to keep, others are deleted. New members are
+    /// screened whether or not their join message is kept.
+    pub allowed_system_messages: HashSet<SystemMessage>,
+    /// Restrict members joining by themselves until they press the 啊
+    /// button, kick them if not pressed in time. `None` to let them in.
+    #[serde(deserialize_with = "config::opt_secs")]
+    pub captcha_timeout: Option<Duration>,
+    /// Ban the sender of a message once this many authentic members have
+    /// replied `/report` to it. `None` to ignore reports.
+    pub report_quorum: Option<u32>,
+    /// Fewest valid ah an authentic member has to have posted for their
+    /// reports to count.
+    pub min_reporter_ah: u32,
+    /// Fetch the bio of new members not yet trusted, and screen it like
+    /// text. Takes one more request per join.
+    pub screen_bio: bool,
+    /// Mute users for `mute_duration` once their spam score reaches this,
+    /// but not yet the spam threshold. Banned if it happens again. `None`
+    /// to only ban at the spam threshold.
+    pub mute_threshold: Option<u8>,
+    #[serde(deserialize_with = "config::secs")]
+    pub mute_duration: Duration,
+    /// Answer `/ahstats` at most once this long in each chat, deleting the
+    /// command otherwise.
+    #[serde(deserialize_with = "config::secs")]
+    pub ah_stats_cooldown: Duration,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        Self {
+            max_action_age: Duration::from_secs(24 * 3600),
+            exempt_authentic_edits: false,
+            allowed_bots: Default::default(),
+            exempt_allowed_bots: false,
+            linked_channels: Default::default(),
+            max_consecutive_deletions: None,
+            min_ah_to_authentic: 1,
+            allowed_domains: Default::default(),
+            cautious_name_check: false,
+            monitor_channels: false,
+            ah_history_len: storage::AH_HISTORY_LEN,
+            log_accepted: false,
+            max_dice_per_window: None,
+            dice_window: Duration::from_secs(600),
+            allow_authentic_dice: false,
+            max_message_len: Some(2000),
+            score_long_messages: false,
+            score_half_life: Some(Duration::from_secs(24 * 3600)),
+            max_users: None,
+            min_noa: 1,
+            allowed_system_messages: SystemMessage::DEFAULT_ALLOWED.into_iter().collect(),
+            captcha_timeout: None,
+            report_quorum: None,
+            min_reporter_ah: 10,
+            screen_bio: false,
+            mute_threshold: None,
+            mute_duration: Duration::from_secs(3600),
+            ah_stats_cooldown: Duration::from_secs(300),
         }
     }
 }
@@ -46,128 +540,2828 @@ impl Action {
 #[derive(Debug)]
 pub struct PolicyState {
     db: Storage,
+    config: PolicyConfig,
+    patterns: SpamPatterns,
+    /// Replaces `patterns` on text if set, names are still left to them.
+    classifier: Option<Box<dyn SpamClassifier>>,
+    scoring: ScoringConfig,
+    /// Spam score of channel posts, see `PolicyConfig::monitor_channels`.
+    channel_scores: HashMap<ChatId, SpamState>,
+    /// Time of recent dice from each user, see `PolicyConfig::max_dice_per_window`.
+    recent_dice: HashMap<UserId, VecDeque<i64>>,
+    /// Time of the last `/ahstats` answered in each chat, see
+    /// `PolicyConfig::ah_stats_cooldown`.
+    ah_stats_answered: HashMap<ChatId, i64>,
+    match_stats: MatchStats,
+    schedule: Vec<StrictWindow>,
+    /// Medium-risk text for a second opinion, `None` if not asked for, see
+    /// `take_remote_checks()`.
+    remote_checks: Option<Vec<(ChatId, UserId, String)>>,
 }
 
-impl PolicyState {
-    pub async fn new<P: AsRef<Path>>(db_path: P) -> anyhow::Result<Self> {
-        Ok(Self {
-            db: Storage::open(db_path).await?,
+/// Build a `PolicyState` with anything not given left as default.
+#[derive(Debug)]
+pub struct PolicyStateBuilder {
+    db_path: PathBuf,
+    config: PolicyConfig,
+    patterns: SpamPatterns,
+    classifier: Option<Box<dyn SpamClassifier>>,
+    scoring: ScoringConfig,
+    schedule: Vec<StrictWindow>,
+    remote_check: bool,
+    dry_run: bool,
+}
+
+impl PolicyStateBuilder {
+    pub fn config(mut self, config: PolicyConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn patterns(mut self, patterns: SpamPatterns) -> Self {
+        self.patterns = patterns;
+        self
+    }
+
+    /// Classify text with `classifier` instead of the patterns.
+    pub fn classifier(mut self, classifier: impl SpamClassifier + 'static) -> Self {
+        self.classifier = Some(Box::new(classifier));
+        self
+    }
+
+    pub fn scoring(mut self, scoring: ScoringConfig) -> Self {
+        self.scoring = scoring;
+        self
+    }
+
+    /// Tighten the policy during these windows, the first one matched wins.
+    pub fn schedule(mut self, schedule: Vec<StrictWindow>) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// Queue medium-risk text for a second opinion, see
+    /// `PolicyState::take_remote_checks()`.
+    pub fn remote_check(mut self, enabled: bool) -> Self {
+        self.remote_check = enabled;
+        self
+    }
+
+    /// Never save the state file, so that what a dry run decides (e.g.
+    /// spam scores and mutes) is not kept once back to enforcing.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Open or create the state file, and get the policy ready.
+    pub async fn build(self) -> storage::Result<PolicyState> {
+        let mut db = Storage::open(self.db_path).await?;
+        db.set_ah_history_len(self.config.ah_history_len);
+        db.set_max_users(self.config.max_users);
+        db.set_score_half_life(self.config.score_half_life);
+        db.set_spam_threshold(self.scoring.spam_threshold);
+        db.set_read_only(self.dry_run);
+        Ok(PolicyState {
+            db,
+            config: self.config,
+            patterns: self.patterns,
+            classifier: self.classifier,
+            scoring: self.scoring,
+            channel_scores: Default::default(),
+            recent_dice: Default::default(),
+            ah_stats_answered: Default::default(),
+            match_stats: Default::default(),
+            schedule: self.schedule,
+            remote_checks: self.remote_check.then(Vec::new),
         })
     }
+}
+
+impl PolicyState {
+    pub fn builder<P: AsRef<Path>>(db_path: P) -> PolicyStateBuilder {
+        PolicyStateBuilder {
+            db_path: db_path.as_ref().to_path_buf(),
+            config: Default::default(),
+            patterns: Default::default(),
+            classifier: None,
+            scoring: Default::default(),
+            schedule: Vec::new(),
+            remote_check: false,
+            dry_run: false,
+        }
+    }
+
+    pub async fn new<P: AsRef<Path>>(db_path: P) -> storage::Result<Self> {
+        Self::builder(db_path).build().await
+    }
+
+    pub async fn with_config<P: AsRef<Path>>(
+        db_path: P,
+        config: PolicyConfig,
+    ) -> storage::Result<Self> {
+        Self::builder(db_path).config(config).build().await
+    }
+
+    fn is_system_message_allowed(&self, kind: SystemMessage) -> bool {
+        self.config.allowed_system_messages.contains(&kind)
+    }
+
+    fn is_sticker_allowed(&self, chat_id: ChatId, sticker: &Sticker) -> bool {
+        let file_unique_id = sticker.file.unique_id.as_str();
+        ALLOWED_STICKER_FILE_IDS.contains(file_unique_id)
+            || self.db.is_sticker_allowed(&chat_id, file_unique_id)
+            || sticker
+                .set_name
+                .as_ref()
+                .is_some_and(|name| self.db.is_sticker_set_allowed(&chat_id, name))
+    }
+
+    /// Allow the sticker, by its file unique id, on top of the built-in list.
+    /// Only in the given chat, or everywhere if `None`.
+    pub fn allow_sticker(&mut self, chat_id: Option<ChatId>, file_unique_id: &str) {
+        if self
+            .db
+            .add_allowed_sticker(chat_id.as_ref(), file_unique_id)
+        {
+            match chat_id {
+                Some(chat_id) => info!("[{}] Sticker [{}] allowed", chat_id, file_unique_id),
+                None => info!("Sticker [{}] allowed", file_unique_id),
+            }
+        }
+    }
+
+    /// Allow all stickers of the set, by its name, in the given chat or
+    /// everywhere if `None`. Stickers added to the set later are included.
+    pub fn allow_sticker_set(&mut self, chat_id: Option<ChatId>, set_name: &str) {
+        if self.db.add_allowed_sticker_set(chat_id.as_ref(), set_name) {
+            match chat_id {
+                Some(chat_id) => info!("[{}] Sticker set [{}] allowed", chat_id, set_name),
+                None => info!("Sticker set [{}] allowed", set_name),
+            }
+        }
+    }
+
+    fn text_classifier(&self) -> &dyn SpamClassifier {
+        match &self.classifier {
+            Some(classifier) => classifier.as_ref(),
+            None => &self.patterns,
+        }
+    }
+
+    /// Tier of the text as the policy sees it, not counted on `match_stats()`.
+    pub fn classify_text(&self, text: &str) -> RiskTier {
+        self.text_classifier().classify_tier(text)
+    }
+
+    /// Like `classify_text()`, counted on `match_stats()`.
+    fn classify_counted(&self, text: &str) -> RiskTier {
+        let tier = self.classify_text(text);
+        self.match_stats.record(tier, unix_now());
+        tier
+    }
+
+    /// How the patterns matched on messages checked so far.
+    pub fn match_stats(&self) -> &MatchStats {
+        &self.match_stats
+    }
+
+    pub fn patterns(&self) -> &SpamPatterns {
+        &self.patterns
+    }
+
+    /// Replace spam patterns, e.g. after reloading the pattern file.
+    pub fn set_patterns(&mut self, patterns: SpamPatterns) {
+        self.patterns = patterns;
+    }
+
+    /// Like `PolicyStateBuilder::classifier()`, e.g. to rebuild it with
+    /// reloaded patterns.
+    pub fn set_classifier(&mut self, classifier: impl SpamClassifier + 'static) {
+        self.classifier = Some(Box::new(classifier));
+    }
 
-    pub async fn save(&mut self) -> anyhow::Result<()> {
+    pub async fn save(&mut self) -> storage::Result<()> {
         self.db.save().await
     }
 
+    /// Keep deletions and bans of `action` until `confirm_delivered()`, so
+    /// they can be replayed with `pending_actions()` after a crash.
+    pub fn add_pending(&mut self, action: &Action) {
+        let now = unix_now();
+        if let Some((chat_id, message_id)) = action.get_delete() {
+            self.db
+                .add_pending(now, PendingAction::Delete(chat_id, message_id));
+        }
+        if let Some((chat_id, user_id, reason)) = action.get_ban() {
+            let ban = PendingAction::Ban {
+                chat_id,
+                user_id,
+                revoke_messages: reason.revoke_messages(),
+                reason: reason.to_string(),
+            };
+            self.db.add_pending(now, ban);
+        }
+    }
+
+    pub fn confirm_delivered(&mut self, delivered: Delivered) {
+        self.db.remove_pending(|action| match (action, delivered) {
+            (PendingAction::Delete(chat, msg), Delivered::Deleted(chat_id, msg_id)) => {
+                (*chat, *msg) == (chat_id, msg_id)
+            }
+            (
+                PendingAction::Ban {
+                    chat_id, user_id, ..
+                },
+                Delivered::Banned(chat, user),
+            ) => (*chat_id, *user_id) == (chat, user),
+            _ => false,
+        });
+    }
+
+    /// Actions not confirmed yet, to be sent again. Those older than
+    /// `PolicyConfig::max_action_age` are given up.
+    pub fn pending_actions(&mut self) -> Vec<PendingAction> {
+        let since = unix_now().saturating_sub(self.config.max_action_age.as_secs());
+        self.db.pending_since(since)
+    }
+
     fn check_message(&mut self, chat_id: ChatId, message: &Message) -> Action {
         let action_delete = Action::Delete(chat_id, message.id);
         match message.kind {
-            // Allow some of system messages
-            MessageKind::NewChatTitle(_)
-            | MessageKind::NewChatPhoto(_)
-            | MessageKind::DeleteChatPhoto(_)
-            | MessageKind::Pinned(_) => return Action::Accept,
             // Screen new user for spammer
             MessageKind::NewChatMembers(ref members) => {
                 for member in &members.new_chat_members {
                     let fullname = member.full_name();
+                    self.db.update_name(&member.id, &fullname);
                     info!(
                         "[{}] New user [{}]({}) join",
                         message.chat.id, member.id, fullname,
                     );
-                    if check_full_name_likely_spammer(&fullname) {
-                        // Fast path to ban
-                        info!("Ban user [{}] with fire emoji", fullname);
-                        return Action::DeleteAndBan(chat_id, message.id, member.id);
+                    if !self
+                        .patterns
+                        .check_name(&fullname, member.username.as_deref())
+                    {
+                        continue;
+                    }
+                    if self.config.cautious_name_check {
+                        // Wait for a second signal, e.g. any risky message
+                        info!("Suspect user [{}] by name", fullname);
+                        let score = self.scoring.spam_threshold.saturating_sub(1);
+                        self.db.raise_score(&member.id, score);
                     }
+                    let signals = Signals { name_match: true };
+                    if let Some(&reason) = self.evaluate_user(member.id, &signals).reasons.first() {
+                        info!("Ban user [{}] for {}", fullname, reason);
+                        return Action::DeleteAndBan(chat_id, message.id, member.id, reason);
+                    }
+                }
+                if let Some(action) = self.challenge_member(chat_id, message) {
+                    return action;
+                }
+                if self.is_system_message_allowed(SystemMessage::NewChatMembers) {
+                    return Action::Accept;
                 }
             }
             // Check normal messages
             MessageKind::Common(_) => (),
-            // Delete others
-            _ => return action_delete,
+            // Allowed in moderation, see `check_dice`
+            MessageKind::Dice(_) => (),
+            // Allow some of system messages, delete others
+            ref kind => {
+                return match SystemMessage::from_kind(kind) {
+                    Some(kind) if self.is_system_message_allowed(kind) => Action::Accept,
+                    _ => action_delete,
+                }
+            }
+        }
+        // Sent on behalf of a chat: the group itself by anonymous admins, the
+        // linked channel, or anyone posting as their own channel
+        if let Some(sender_chat) = &message.sender_chat {
+            if sender_chat.id == chat_id
+                || message.is_automatic_forward()
+                || self.config.linked_channels.contains(&sender_chat.id)
+            {
+                return Action::Accept;
+            }
+            info!("[{}] Message sent as [{}]", chat_id, sender_chat.id);
+            return action_delete;
         }
         let uid = match &message.from {
             // No (other) bots
-            Some(user) if user.is_bot => return action_delete,
-            Some(user) => user.id,
+            Some(user) if user.is_bot && !self.config.allowed_bots.contains(&user.id) => {
+                return action_delete
+            }
+            Some(user) if user.is_bot && self.config.exempt_allowed_bots => return Action::Accept,
+            Some(user) => {
+                self.db.update_name(&user.id, &user.full_name());
+                user.id
+            }
             None => return Action::Accept,
         };
 
-        // Check for spammer
+        // Admin commands
+        let replied_user = || message.reply_to_message()?.from.as_ref().map(|u| u.id);
+        if let Some(command) = message.text().and_then(Command::parse) {
+            return match command {
+                Command::Whois(target) => match target.or_else(replied_user) {
+                    Some(target) => Action::Whois(chat_id, message.id, uid, target),
+                    None => action_delete,
+                },
+                Command::Unban(target) => match target.or_else(replied_user) {
+                    Some(target) => Action::Unban(chat_id, message.id, uid, target),
+                    None => action_delete,
+                },
+                Command::Report => self.check_report(chat_id, uid, message),
+                // Not to be a way for spammers to get the bot talking
+                Command::AhStats if self.db.get_user(&uid).is_trusted() => {
+                    self.check_ah_stats(chat_id, message)
+                }
+                Command::AhStats => action_delete,
+            };
+        }
+
+        if message.dice().is_some() || message.game().is_some() {
+            return self.check_dice(chat_id, uid, message);
+        }
+
+        // Check for spammer, on their own text only. Quotes are someone
+        // else's, possibly quoted to report the spam.
+        let tier = self.message_tier(uid, message, |text| self.classify_counted(text));
+        let scoring = self.scoring_at(message);
+        if let (Some(RiskTier::MediumRisk), Some(queue)) = (tier, &mut self.remote_checks) {
+            if let Some(text) = message.text().or_else(|| message.caption()) {
+                queue.push((chat_id, uid, text.to_string()));
+            }
+        }
+        if let Some(tier) = tier {
+            let verdict = self.verdict_on_text(uid, tier, &scoring);
+            // Trusted users may get their account stolen. Give them the
+            // benefit of the doubt once, but take back the trust.
+            if tier == RiskTier::HighRisk && self.db.demote_user(&uid) {
+                info!("[{}] Demote user [{}] on high-risk text", chat_id, uid);
+                return action_delete;
+            }
+            let added = tier.spam_state_with(&scoring);
+            let state = self
+                .db
+                .update_user_with(&uid, added, scoring.spam_threshold);
+            if let Some(&reason) = verdict.reasons.first() {
+                return Action::DeleteAndBan(chat_id, message.id, uid, reason);
+            }
+            if tier != RiskTier::NoRisk {
+                let muted = self.mute_if_suspicious(chat_id, message.id, uid, state, added);
+                if let Some(action) = muted {
+                    return action;
+                }
+            }
+        }
+        // Hard cap on walls of text, after scoring so padding hides no spam
         if let Some(text) = message.text() {
-            let state = check_message_text(text);
-            let state = self.db.update_user(&uid, state);
-            if state.is_spam() {
-                return Action::DeleteAndBan(chat_id, message.id, uid);
+            if self.is_too_long(text, self.db.get_user(&uid)) {
+                debug!("[{}] Too long message from [{}]", chat_id, uid);
+                if self.config.score_long_messages {
+                    let score = SpamState::MaybeSpam(scoring.medium_risk_score);
+                    self.db
+                        .update_user_with(&uid, score, scoring.spam_threshold);
+                    let verdict = self.evaluate_user(uid, &Default::default());
+                    if let Some(&reason) = verdict.reasons.first() {
+                        return Action::DeleteAndBan(chat_id, message.id, uid, reason);
+                    }
+                }
+                return action_delete;
             }
         }
 
+        if self.is_held(uid, message) {
+            debug!("[{}] Hold message from [{}] in strict window", chat_id, uid);
+            return action_delete;
+        }
+
         if message.reply_to_message().is_some() {
             return action_delete; // No reply
         }
-        if message.entities().unwrap_or(&[]).iter().any(|entity| {
-            !matches!(
-                entity.kind,
+        // Whitelist stylish text but no clickable things like URL, mention, etc.
+        let mut has_link = false;
+        for entity in message.parse_entities().unwrap_or_default() {
+            match entity.kind() {
                 MessageEntityKind::Bold
-                    | MessageEntityKind::Underline
-                    | MessageEntityKind::Italic
-                    | MessageEntityKind::Code
-                    | MessageEntityKind::Strikethrough
-                    | MessageEntityKind::Spoiler
-            )
-        }) {
-            // Whitelist stylish text but no clickable things like URL, mention, etc.
-            return action_delete;
+                | MessageEntityKind::Underline
+                | MessageEntityKind::Italic
+                | MessageEntityKind::Code
+                | MessageEntityKind::Strikethrough
+                | MessageEntityKind::Spoiler => (),
+                MessageEntityKind::Url | MessageEntityKind::TextLink { .. }
+                    if self.is_allowed_link(&entity) =>
+                {
+                    has_link = true
+                }
+                _ => return action_delete,
+            }
+        }
+        if has_link {
+            // Except links to allowed domains from trusted users, not counted as ah
+            return if self.db.get_user(&uid).is_trusted() {
+                Action::Accept
+            } else {
+                action_delete
+            };
         }
         // Count the number of ah (noa)
         let noa = match message.text() {
             None => match message.sticker() {
                 // Treat allowed sticker as single 啊
-                Some(sticker) if ALLOWED_STICKER_FILE_IDS.contains(&*sticker.file.unique_id) => 1,
+                Some(sticker) if self.is_sticker_allowed(chat_id, sticker) => 1,
                 // No neither-text-or-allowed-sticker messages
                 _ => return action_delete,
             },
-            // 啊+ only
-            Some(text) if !text.chars().all(|c| c == '啊') => return action_delete,
-            // Each 啊 takes 3 bytes as UTF-8
-            Some(text) => (text.len() / 3).try_into().expect("Toooooo mmmany ah"),
+            Some(text) => match count_ah(text) {
+                Some(noa) => noa,
+                None => return action_delete,
+            },
         };
 
+        if noa < self.config.min_noa {
+            debug!("Reject message from [{}]: only {} ah", uid, noa);
+            return action_delete;
+        }
         if let Err(err) = self.db.update_chat(&chat_id, (uid, noa)) {
             debug!("Reject message from [{}]: {}", uid, err);
             return action_delete;
         }
-        // Now they're a trusted user
-        self.db.update_user(&uid, SpamState::Authentic);
+        // Now they're a trusted user, fully if they have been around long enough
+        let state = if self.db.record_valid_ah(&uid) >= self.config.min_ah_to_authentic {
+            SpamState::Authentic
+        } else {
+            SpamState::Trusted {
+                since_ts: message.date.timestamp().try_into().unwrap_or_default(),
+                score: 0,
+            }
+        };
+        self.db.update_user(&uid, state);
+        if let Some(line) = self.accepted_log_line(uid, noa, message) {
+            info!("{}", line);
+        }
         Action::Accept
     }
 
-    pub fn check_update(&mut self, update: &Update) -> Action {
-        if let UpdateKind::Error(value) = &update.kind {
-            info!(
-                "Unsupported update [{:?}/{}]: {}",
-                update.chat_id(),
-                update.id.0,
-                value
-            );
-            // TODO: try to extract message id from `value`
-            // return Some((update.chat_id()?, MessageId(update.id)));
-            return Action::Accept;
+    /// What to log on an accepted ah, if `PolicyConfig::log_accepted`.
+    fn accepted_log_line(&self, uid: UserId, noa: u32, message: &Message) -> Option<String> {
+        if !self.config.log_accepted {
+            return None;
         }
-        let chat = match update.chat() {
-            Some(chat) => chat,
-            None => return Action::Accept,
+        let preview: String = match message.text() {
+            Some(text) => text.chars().take(16).collect(),
+            None => "<sticker>".into(),
         };
-        if let ChatKind::Public(_) = chat.kind {
-            match update.kind {
-                UpdateKind::Message(ref msg) => self.check_message(chat.id, msg),
-                UpdateKind::EditedMessage(ref msg) => Action::Delete(chat.id, msg.id),
-                _ => Action::Accept,
-            }
-        } else {
-            // Take action on groups only
-            Action::Accept
+        Some(format!(
+            "[{}] Accept [{:?}] from [{}] with {} ah: {}",
+            message.chat.id, message.id, uid, noa, preview
+        ))
+    }
+
+    /// Put together the user's state and `signals` to decide on a ban.
+    /// All ban decisions go through here.
+    pub fn evaluate_user(&self, user_id: UserId, signals: &Signals) -> Verdict {
+        let state = self.db.get_user(&user_id);
+        let deletions = self
+            .db
+            .get_profile(&user_id)
+            .map_or(0, |p| p.consecutive_deletions);
+        self.evaluate(state, deletions, signals)
+    }
+
+    /// The verdict `check_message()` acts on for `message` from the user,
+    /// given what's stored now. Nothing is recorded, not even match stats.
+    pub fn classify_user(&self, user_id: UserId, message: &Message) -> Verdict {
+        match self.message_tier(user_id, message, |text| self.classify_text(text)) {
+            Some(tier) => self.verdict_on_text(user_id, tier, &self.scoring_at(message)),
+            None => self.evaluate_user(user_id, &Default::default()),
+        }
+    }
+
+    /// Risk of what the user sent: its text or caption, bare media, and where
+    /// it was forwarded from. `None` if there is nothing to judge.
+    fn message_tier(
+        &self,
+        user_id: UserId,
+        message: &Message,
+        classify: impl Fn(&str) -> RiskTier,
+    ) -> Option<RiskTier> {
+        let tier = match message.text().or_else(|| message.caption()) {
+            Some(text) => Some(classify(text)),
+            None if is_bare_media(message) => Some(RiskTier::MediumRisk),
+            None => None,
+        };
+        let forward = self.forward_tier(user_id, message, classify);
+        match (tier, forward) {
+            (Some(tier), Some(forward)) if forward.rank() > tier.rank() => Some(forward),
+            (None, forward) => forward,
+            (tier, _) => tier,
+        }
+    }
+
+    /// Spam is often forwarded from channels or users hiding themselves,
+    /// that alone is high risk unless the user is authentic. Names of
+    /// the origin chats are checked too, only risky ones count.
+    fn forward_tier(
+        &self,
+        user_id: UserId,
+        message: &Message,
+        classify: impl Fn(&str) -> RiskTier,
+    ) -> Option<RiskTier> {
+        let origin = message.forward_origin()?;
+        let hidden = matches!(
+            origin,
+            MessageOrigin::Channel { .. } | MessageOrigin::HiddenUser { .. }
+        );
+        if hidden && self.db.get_user(&user_id) != SpamState::Authentic {
+            return Some(RiskTier::HighRisk);
+        }
+        let chat = match origin {
+            MessageOrigin::Channel { chat, .. } => chat,
+            MessageOrigin::Chat { sender_chat, .. } => sender_chat,
+            MessageOrigin::User { .. } | MessageOrigin::HiddenUser { .. } => return None,
+        };
+        let tier = classify(chat.title()?);
+        matches!(tier, RiskTier::HighRisk | RiskTier::MediumRisk).then_some(tier)
+    }
+
+    /// Verdict on the user after scoring their text of `tier`.
+    fn verdict_on_text(&self, user_id: UserId, tier: RiskTier, scoring: &ScoringConfig) -> Verdict {
+        let deletions = self
+            .db
+            .get_profile(&user_id)
+            .map_or(0, |p| p.consecutive_deletions);
+        self.verdict_on_state(self.db.get_user(&user_id), deletions, tier, scoring)
+    }
+
+    /// Like `verdict_on_text()`, on a user in `state`.
+    fn verdict_on_state(
+        &self,
+        state: SpamState,
+        deletions: u32,
+        tier: RiskTier,
+        scoring: &ScoringConfig,
+    ) -> Verdict {
+        let state = match state {
+            // Demoted, see `check_message()`
+            SpamState::Authentic if tier == RiskTier::HighRisk => SpamState::MaybeSpam(0),
+            state => state.add_with(tier.spam_state_with(scoring), scoring.spam_threshold),
+        };
+        self.evaluate(state, deletions, &Default::default())
+    }
+
+    /// Over `PolicyConfig::max_message_len`, for a user in `state`.
+    fn is_too_long(&self, text: &str, state: SpamState) -> bool {
+        state != SpamState::Authentic
+            && self
+                .config
+                .max_message_len
+                .is_some_and(|max| text.chars().count() > max)
+    }
+
+    fn evaluate(&self, state: SpamState, deletions: u32, signals: &Signals) -> Verdict {
+        let mut reasons = Vec::new();
+        // Cautious mode scores the name instead, see `check_message()`
+        if signals.name_match && !self.config.cautious_name_check {
+            reasons.push(BanReason::SpamName);
+        }
+        if state.is_spam() {
+            reasons.push(BanReason::SpamText);
+        }
+        if self
+            .config
+            .max_consecutive_deletions
+            .is_some_and(|max| deletions >= max)
+        {
+            reasons.push(BanReason::TooManyDeletions);
+        }
+        Verdict {
+            banned: !reasons.is_empty(),
+            state,
+            reasons,
         }
     }
+
+    /// What `check_message()` would decide on a plain text message from a
+    /// user in `state`, supposing it's their turn to ah. Nothing is recorded,
+    /// for trying out the policy on sample messages. Goes through the same
+    /// steps in the same order, on the helpers `check_message()` uses.
+    pub fn dry_check_text(&self, state: SpamState, text: &str) -> Decision {
+        let scoring = self.scoring;
+        let tier = self.classify_text(text);
+        let verdict = self.verdict_on_state(state, 0, tier, &scoring);
+        if tier == RiskTier::HighRisk && state == SpamState::Authentic {
+            return Decision::Delete; // Demoted
+        }
+        if let Some(&reason) = verdict.reasons.first() {
+            return Decision::Ban(reason);
+        }
+        let state = verdict.state;
+        // Never muted before, as far as we know
+        let muted = self.config.mute_threshold.is_some_and(|threshold| {
+            tier != RiskTier::NoRisk && state.score().is_some_and(|score| score >= threshold)
+        });
+        if muted {
+            return Decision::Mute;
+        }
+        if self.is_too_long(text, state) {
+            if self.config.score_long_messages {
+                let score = SpamState::MaybeSpam(scoring.medium_risk_score);
+                let state = state.add_with(score, scoring.spam_threshold);
+                if let Some(&reason) = self.evaluate(state, 0, &Default::default()).reasons.first()
+                {
+                    return Decision::Ban(reason);
+                }
+            }
+            return Decision::Delete;
+        }
+        match count_ah(text) {
+            Some(noa) if noa >= self.config.min_noa => Decision::Accept,
+            _ => Decision::Delete,
+        }
+    }
+
+    fn is_allowed_link(&self, entity: &MessageEntityRef) -> bool {
+        let host = match entity.kind() {
+            MessageEntityKind::TextLink { url } => url.host_str().map(str::to_lowercase),
+            _ => url_host(entity.text()),
+        };
+        host.is_some_and(|host| {
+            self.config.allowed_domains.iter().any(|domain| {
+                host == *domain
+                    || host
+                        .strip_suffix(domain.as_str())
+                        .is_some_and(|sub| sub.ends_with('.'))
+            })
+        })
+    }
+
+    fn monitor_channel_post(&mut self, chat_id: ChatId, message: &Message) {
+        let Some(text) = message.text().or_else(|| message.caption()) else {
+            return;
+        };
+        let tier = self.classify_counted(text);
+        let score = self.channel_scores.entry(chat_id).or_default();
+        let was_spam = score.is_spam();
+        *score = score.add_with(
+            tier.spam_state_with(&self.scoring),
+            self.scoring.spam_threshold,
+        );
+        debug!("[{}] Channel post [{:?}] is {}", chat_id, message.id, tier);
+        if score.is_spam() && !was_spam {
+            warn!("[{}] Channel is posting spam", chat_id);
+        }
+    }
+
+    /// Spam score of a monitored channel, if it has ever posted text.
+    pub fn channel_score(&self, chat_id: ChatId) -> Option<SpamState> {
+        self.channel_scores.get(&chat_id).copied()
+    }
+
+    /// Count the report on the replied message, ban its sender on quorum.
+    /// Trusted senders are not banned, only their message is deleted.
+    /// The command message is deleted anyway.
+    fn check_report(&mut self, chat_id: ChatId, reporter: UserId, message: &Message) -> Action {
+        let action_delete = Action::Delete(chat_id, message.id);
+        let Some(quorum) = self.config.report_quorum else {
+            return action_delete;
+        };
+        // Only members with some history, or spammers would report everyone else
+        let valid_ah = self.db.get_profile(&reporter).map_or(0, |p| p.valid_ah);
+        if !self.db.get_user(&reporter).is_authentic() || valid_ah < self.config.min_reporter_ah {
+            return action_delete;
+        }
+        let Some(reported) = message.reply_to_message() else {
+            return action_delete;
+        };
+        let user_id = match &reported.from {
+            Some(user) if !user.is_bot && user.id != reporter => user.id,
+            _ => return action_delete,
+        };
+        let count = self.db.add_report(&chat_id, reported.id, user_id, reporter);
+        info!(
+            "[{}] User [{}] reported [{}] ({}/{})",
+            chat_id, reporter, user_id, count, quorum
+        );
+        if count < quorum as usize {
+            return action_delete;
+        }
+        self.db.remove_report(&chat_id, reported.id);
+        if self.db.get_user(&user_id).is_trusted() {
+            warn!(
+                "[{}] Trusted user [{}] reported, delete the message only",
+                chat_id, user_id
+            );
+            return Action::DeleteReported(chat_id, message.id, reported.id);
+        }
+        self.db.update_user(&user_id, SpamState::new_spam());
+        // Their messages, the reported one included, go with the ban
+        Action::DeleteAndBan(chat_id, message.id, user_id, BanReason::Reported)
+    }
+
+    /// New members of the update whose bio should be fetched for
+    /// `check_bio()`, see `PolicyConfig::screen_bio`. Each is screened once
+    /// only, so that joining again adds nothing to their score.
+    pub fn bios_to_screen(&self, update: &Update) -> Vec<(ChatId, UserId)> {
+        let message = match &update.kind {
+            UpdateKind::Message(msg) if self.config.screen_bio => msg,
+            _ => return vec![],
+        };
+        let members = message.new_chat_members().unwrap_or_default();
+        members
+            .iter()
+            .filter(|member| !member.is_bot)
+            .filter(|member| {
+                let state = self.db.get_user(&member.id);
+                let screened = self
+                    .db
+                    .get_profile(&member.id)
+                    .is_some_and(|p| p.bio_screened);
+                !state.is_trusted() && !state.is_spam() && !screened
+            })
+            .map(|member| (message.chat.id, member.id))
+            .collect()
+    }
+
+    /// Screen a new member by their bio. Only risky bios count, most
+    /// people write nothing the patterns know.
+    pub fn check_bio(&mut self, chat_id: ChatId, user_id: UserId, bio: &str) -> Action {
+        // Fetched twice if they joined again before the first came back
+        if !self.db.record_bio_screened(&user_id) {
+            return Action::Accept;
+        }
+        let tier = self.classify_text(bio);
+        if !matches!(tier, RiskTier::HighRisk | RiskTier::MediumRisk) {
+            return Action::Accept;
+        }
+        info!("[{}] User [{}] has {:?} bio", chat_id, user_id, tier);
+        self.db
+            .update_user(&user_id, tier.spam_state_with(&self.scoring));
+        if self
+            .evaluate_user(user_id, &Default::default())
+            .reasons
+            .is_empty()
+        {
+            Action::Accept
+        } else {
+            Action::Ban(chat_id, user_id, BanReason::SpamBio)
+        }
+    }
+
+    /// Medium-risk text queued since the last call, as `(chat, sender, text)`.
+    /// Each is to be classified elsewhere, and the verdict given back to
+    /// `check_remote_verdict()`. Always empty unless enabled by
+    /// `PolicyStateBuilder::remote_check()`.
+    pub fn take_remote_checks(&mut self) -> Vec<(ChatId, UserId, String)> {
+        self.remote_checks
+            .as_mut()
+            .map(mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Score the sender as high-risk if their text from
+    /// `take_remote_checks()` turned out to be spam. Authentic users are
+    /// left alone, as their message is already dealt with.
+    pub fn check_remote_verdict(&mut self, chat_id: ChatId, user_id: UserId, spam: bool) -> Action {
+        if !spam || self.db.get_user(&user_id).is_authentic() {
+            return Action::Accept;
+        }
+        info!(
+            "[{}] Remote classifier found spam from [{}]",
+            chat_id, user_id
+        );
+        self.db
+            .update_user(&user_id, RiskTier::HighRisk.spam_state_with(&self.scoring));
+        match self
+            .evaluate_user(user_id, &Default::default())
+            .reasons
+            .first()
+        {
+            Some(&reason) => Action::Ban(chat_id, user_id, reason),
+            None => Action::Accept,
+        }
+    }
+
+    /// Challenge the member joined by themselves, if not trusted already.
+    /// Members added by others are vouched for.
+    fn challenge_member(&mut self, chat_id: ChatId, message: &Message) -> Option<Action> {
+        let timeout = self.config.captcha_timeout?;
+        let joined = message.from.as_ref()?;
+        let member = message
+            .new_chat_members()?
+            .iter()
+            .find(|member| member.id == joined.id)?;
+        if member.is_bot || self.db.get_user(&member.id).is_trusted() {
+            return None;
+        }
+        info!("[{}] Challenge new user [{}]", chat_id, member.id);
+        let deadline = unix_now() + timeout.as_secs();
+        self.db.add_challenge(&chat_id, &member.id, deadline);
+        let join_message =
+            (!self.is_system_message_allowed(SystemMessage::NewChatMembers)).then_some(message.id);
+        Some(Action::Challenge(chat_id, join_message, member.id))
+    }
+
+    fn check_callback_query(&mut self, chat_id: ChatId, query: &CallbackQuery) -> Action {
+        let Some(user_id) = query.data.as_deref().and_then(parse_challenge_answer) else {
+            return Action::Accept;
+        };
+        // Only the one challenged can answer
+        if query.from.id != user_id || !self.db.pass_challenge(&chat_id, &user_id, unix_now()) {
+            return Action::Accept;
+        }
+        info!("[{}] User [{}] passed challenge", chat_id, user_id);
+        let message = query.message.as_ref().map(|msg| msg.id());
+        Action::ChallengePassed(chat_id, message, user_id)
+    }
+
+    /// Challenges not answered in time, to kick the users out. They are
+    /// forgotten once returned.
+    pub fn expired_challenges(&mut self) -> Vec<(ChatId, UserId)> {
+        self.db.take_expired_challenges(unix_now())
+    }
+
+    /// Forget the challenge that failed to be posted, so the user won't be
+    /// kicked for not answering it.
+    pub fn cancel_challenge(&mut self, chat_id: ChatId, user_id: UserId) {
+        info!("[{}] Cancel challenge of [{}]", chat_id, user_id);
+        self.db.remove_challenge(&chat_id, &user_id);
+    }
+
+    /// Trust a user banned by mistake, so they won't be banned again.
+    /// Only to be called on request of a group admin.
+    pub fn pardon_user(&mut self, user_id: UserId) {
+        info!("Pardon user [{}]", user_id);
+        self.db.pardon_user(&user_id);
+        // Or they get banned again on restart by `pending_actions()`
+        self.db.remove_pending(
+            |action| matches!(action, PendingAction::Ban { user_id: user, .. } if *user == user_id),
+        );
+    }
+
+    /// The top `len` ah-ers of the chat and its totals.
+    pub fn ah_leaderboard(&self, chat_id: ChatId, len: usize) -> AhLeaderboard {
+        let Some(stats) = self.db.get_ah_stats(&chat_id) else {
+            return AhLeaderboard {
+                total: 0,
+                longest_streak: 0,
+                top: Vec::new(),
+            };
+        };
+        let mut users: Vec<_> = self.db.ah_counts(&chat_id).collect();
+        // Ties by user id, to be stable
+        users.sort_by_key(|&(uid, noa)| (Reverse(noa), uid.0));
+        let top = users
+            .into_iter()
+            .take(len)
+            .map(|(uid, noa)| {
+                let name = self
+                    .db
+                    .get_profile(&uid)
+                    .and_then(|p| p.last_name_seen.clone());
+                (uid, name, noa)
+            })
+            .collect();
+        AhLeaderboard {
+            total: stats.total,
+            longest_streak: stats.longest_streak,
+            top,
+        }
+    }
+
+    pub fn whois(&self, chat_id: ChatId, user_id: UserId) -> UserReport {
+        let last_ah = match self.db.get_chat(&chat_id) {
+            Some((last_user, noa)) if last_user == user_id => Some(noa),
+            _ => None,
+        };
+        UserReport {
+            user_id,
+            name: self
+                .db
+                .get_profile(&user_id)
+                .and_then(|p| p.last_name_seen.clone()),
+            state: self.db.get_user(&user_id),
+            last_ah,
+            recent_ah: self
+                .db
+                .recent_ah(&chat_id, usize::MAX)
+                .filter(|(uid, _)| *uid == user_id)
+                .map(|(_, noa)| noa)
+                .collect(),
+        }
+    }
+
+    fn check_edited_message(&self, chat_id: ChatId, message: &Message) -> Action {
+        match &message.from {
+            Some(user)
+                if self.config.exempt_authentic_edits
+                    && self.db.get_user(&user.id).is_authentic() =>
+            {
+                Action::Accept
+            }
+            _ => Action::Delete(chat_id, message.id), // No editing
+        }
+    }
+
+    /// The window of `schedule` the message was sent in, if any.
+    fn strict_window(&self, message: &Message) -> Option<&StrictWindow> {
+        let hour = (message.date.timestamp().rem_euclid(24 * 3600) / 3600) as u8;
+        self.schedule.iter().find(|window| window.contains(hour))
+    }
+
+    /// Scoring for the message, tightened if in a strict window.
+    fn scoring_at(&self, message: &Message) -> ScoringConfig {
+        let mut scoring = self.scoring;
+        if let Some(threshold) = self.strict_window(message).and_then(|w| w.spam_threshold) {
+            scoring.spam_threshold = threshold;
+        }
+        scoring
+    }
+
+    /// Whether the message is deleted for `StrictWindow::hold_untrusted`.
+    fn is_held(&self, user_id: UserId, message: &Message) -> bool {
+        self.strict_window(message)
+            .is_some_and(|window| window.hold_untrusted)
+            && !self.db.get_user(&user_id).is_trusted()
+    }
+
+    /// Mute the user if `state` is over `PolicyConfig::mute_threshold`. If
+    /// they have been muted before, ban them only if `added`, the score of
+    /// this message alone, is over it too: the score left from the message
+    /// they got muted for is not enough.
+    fn mute_if_suspicious(
+        &mut self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        user_id: UserId,
+        state: SpamState,
+        added: SpamState,
+    ) -> Option<Action> {
+        let threshold = self.config.mute_threshold?;
+        state.score().filter(|&score| score >= threshold)?;
+        if self.db.get_profile(&user_id).is_some_and(|p| p.mutes > 0) {
+            added.score().filter(|&score| score >= threshold)?;
+            info!("[{}] Ban user [{}] suspicious after mute", chat_id, user_id);
+            let reason = BanReason::RepeatOffense;
+            return Some(Action::DeleteAndBan(chat_id, message_id, user_id, reason));
+        }
+        self.db.record_mute(&user_id);
+        let duration = self.config.mute_duration;
+        info!("[{}] Mute suspicious user [{}]", chat_id, user_id);
+        Some(Action::Restrict(chat_id, message_id, user_id, duration))
+    }
+
+    /// Answer `/ahstats` unless done recently in the chat, so that it can't
+    /// be used to flood the chat.
+    fn check_ah_stats(&mut self, chat_id: ChatId, message: &Message) -> Action {
+        let now = message.date.timestamp();
+        let cooldown = self.config.ah_stats_cooldown.as_secs() as i64;
+        match self.ah_stats_answered.get(&chat_id) {
+            Some(&last) if now < last + cooldown => {
+                debug!("[{}] Skip /ahstats, answered recently", chat_id);
+                Action::Delete(chat_id, message.id)
+            }
+            _ => {
+                self.ah_stats_answered.insert(chat_id, now);
+                Action::AhStats(chat_id, message.id)
+            }
+        }
+    }
+
+    /// Dice and games are not ah, but only count toward spam when repeated.
+    fn check_dice(&mut self, chat_id: ChatId, uid: UserId, message: &Message) -> Action {
+        let action_delete = Action::Delete(chat_id, message.id);
+        let now = message.date.timestamp();
+        let window_start = now - self.config.dice_window.as_secs() as i64;
+        // Forget those done with the window, as well as old dice of this one
+        self.recent_dice
+            .retain(|_, recent| recent.back().is_some_and(|&ts| ts > window_start));
+        let recent = self.recent_dice.entry(uid).or_default();
+        recent.retain(|&ts| ts > window_start);
+        recent.push_back(now);
+        let count = recent.len() as u32;
+
+        if self
+            .config
+            .max_dice_per_window
+            .is_some_and(|max| count > max)
+        {
+            debug!("[{}] Dice flood from [{}]", chat_id, uid);
+            let score = SpamState::with_score(self.scoring.medium_risk_score);
+            self.db.update_user(&uid, score);
+            let verdict = self.evaluate_user(uid, &Default::default());
+            if let Some(&reason) = verdict.reasons.first() {
+                return Action::DeleteAndBan(chat_id, message.id, uid, reason);
+            }
+            return action_delete;
+        }
+        let authentic = self.db.get_user(&uid) == SpamState::Authentic;
+        if self.config.allow_authentic_dice && authentic && count == 1 {
+            Action::Accept
+        } else {
+            action_delete
+        }
+    }
+
+    /// Count deleted messages in a row for the sender, upgrade to a ban if
+    /// they keep posting disallowed messages.
+    fn escalate_deletions(&mut self, message: &Message, action: Action) -> Action {
+        let user_id = match &message.from {
+            Some(user) if !user.is_bot => user.id,
+            _ => return action,
+        };
+        if self.is_held(user_id, message) {
+            return action;
+        }
+        match action {
+            Action::Accept => self.db.reset_deletions(&user_id),
+            Action::Delete(chat_id, msg_id) => {
+                let count = self.db.record_deletion(&user_id);
+                let verdict = self.evaluate_user(user_id, &Default::default());
+                if let Some(&reason) = verdict.reasons.first() {
+                    info!(
+                        "[{}] Ban user [{}] after {} deleted messages in a row",
+                        chat_id, user_id, count
+                    );
+                    return Action::DeleteAndBan(chat_id, msg_id, user_id, reason);
+                }
+            }
+            Action::DeleteAndBan(..)
+            | Action::Ban(..)
+            | Action::Restrict(..)
+            | Action::AhStats(..)
+            | Action::DeleteReported(..)
+            | Action::Whois(..)
+            | Action::Unban(..)
+            | Action::Challenge(..)
+            | Action::ChallengePassed(..) => (),
+        }
+        action
+    }
+
+    /// Downgrade `action` to `Accept` if the message was sent (or edited) too long ago.
+    fn skip_if_stale(&self, message: &Message, action: Action) -> Action {
+        if action == Action::Accept {
+            return action;
+        }
+        let date = message.edit_date().unwrap_or(&message.date).timestamp();
+        let now: i64 = unix_now().try_into().unwrap_or(i64::MAX);
+        let max_age: i64 = self
+            .config
+            .max_action_age
+            .as_secs()
+            .try_into()
+            .unwrap_or(i64::MAX);
+        if now.saturating_sub(date) > max_age {
+            info!(
+                "[{}] Skip stale message [{}]: {:?}",
+                message.chat.id, message.id, action
+            );
+            Action::Accept
+        } else {
+            action
+        }
+    }
+
+    pub fn check_update(&mut self, update: &Update) -> Action {
+        if let UpdateKind::Error(value) = &update.kind {
+            info!(
+                "Unsupported update [{:?}/{}]: {}",
+                update.chat_id(),
+                update.id.0,
+                value
+            );
+            // TODO: try to extract message id from `value`
+            // return Some((update.chat_id()?, MessageId(update.id)));
+            return Action::Accept;
+        }
+        let chat = match update.chat() {
+            Some(chat) => chat,
+            None => return Action::Accept,
+        };
+        if let ChatKind::Public(_) = chat.kind {
+            match update.kind {
+                UpdateKind::Message(ref msg) => {
+                    let action = self.check_message(chat.id, msg);
+                    let action = self.escalate_deletions(msg, action);
+                    self.skip_if_stale(msg, action)
+                }
+                UpdateKind::EditedMessage(ref msg) => {
+                    let action = self.check_edited_message(chat.id, msg);
+                    self.skip_if_stale(msg, action)
+                }
+                UpdateKind::CallbackQuery(ref query) => self.check_callback_query(chat.id, query),
+                UpdateKind::ChannelPost(ref msg) if self.config.monitor_channels => {
+                    self.monitor_channel_post(chat.id, msg);
+                    Action::Accept
+                }
+                _ => Action::Accept,
+            }
+        } else {
+            // Take action on groups only
+            Action::Accept
+        }
+    }
+}
+
+/// Host part of a URL as written in text, which may lack the scheme.
+fn url_host(url: &str) -> Option<String> {
+    let url = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let host = url.split(['/', '?', '#']).next()?;
+    // Drop userinfo and port
+    let host = host.rsplit('@').next()?.split(':').next()?;
+    (!host.is_empty()).then(|| host.to_lowercase())
+}
+
+/// Callback data of the button on challenge for the user.
+pub fn challenge_answer(user_id: UserId) -> String {
+    format!("{}{}", CHALLENGE_ANSWER_PREFIX, user_id)
+}
+
+fn parse_challenge_answer(data: &str) -> Option<UserId> {
+    let id = data.strip_prefix(CHALLENGE_ANSWER_PREFIX)?;
+    Some(UserId(id.parse().ok()?))
+}
+
+/// Number of 啊 in text of 啊 only, `None` for anything else. Nothing but
+/// entities is no ah either.
+fn count_ah(text: &str) -> Option<u32> {
+    if text.is_empty() || !text.chars().all(|c| c == '啊') {
+        return None;
+    }
+    // Each 啊 takes 3 bytes as UTF-8
+    Some((text.len() / 3).try_into().expect("Toooooo mmmany ah"))
+}
+
+/// Photo, video or document without caption. Spam in images can't be
+/// matched, so they are scored as medium risk instead.
+fn is_bare_media(message: &Message) -> bool {
+    message.caption().is_none()
+        && (message.photo().is_some() || message.video().is_some() || message.document().is_some())
+}
+
+#[cfg(test)]
+fn text_update(date: i64, user_id: u64, text: &str) -> Update {
+    let message = message_json(-1001, 1, date, Some((user_id, false)), &text_field(text));
+    message_update("message", &message)
+}
+
+#[cfg(test)]
+fn edited_update(date: i64, user_id: u64, text: &str) -> Update {
+    let message = message_json(-1001, 1, date, Some((user_id, false)), &text_field(text));
+    message_update("edited_message", &message)
+}
+
+#[cfg(test)]
+fn bot_update(date: i64, user_id: u64, text: &str) -> Update {
+    let message = message_json(-1001, 1, date, Some((user_id, true)), &text_field(text));
+    message_update("message", &message)
+}
+
+#[cfg(test)]
+fn message_update(kind: &str, message: &str) -> Update {
+    sonic_rs::from_str(&format!(r#"{{ "update_id": 1, "{kind}": {message} }}"#)).unwrap()
+}
+
+/// JSON of a message in supergroup `chat_id`, from user `from` (a bot if
+/// set so) unless `None`. `fields` are the rest of it, see `text_field()`.
+#[cfg(test)]
+fn message_json(
+    chat_id: i64,
+    message_id: i32,
+    date: i64,
+    from: Option<(u64, bool)>,
+    fields: &str,
+) -> String {
+    let from = from.map_or(String::new(), |(id, is_bot)| {
+        format!(r#""from": {{ "id": {id}, "is_bot": {is_bot}, "first_name": "test" }},"#)
+    });
+    format!(
+        r#"{{
+            "message_id": {message_id},
+            "date": {date},
+            "chat": {{ "id": {chat_id}, "type": "supergroup", "title": "ah" }},
+            {from}
+            {fields}
+        }}"#
+    )
+}
+
+#[cfg(test)]
+fn text_field(text: &str) -> String {
+    format!(r#""text": {:?}"#, text)
+}
+
+#[tokio::test]
+async fn test_max_action_age() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config = PolicyConfig {
+        max_action_age: Duration::from_secs(60),
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("state.json"), config)
+        .await
+        .unwrap();
+    let now = now_ts();
+
+    let old = text_update(now - 3600, 1, "hello");
+    assert_eq!(Action::Accept, policy.check_update(&old));
+    let fresh = text_update(now, 2, "hello");
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&fresh)
+    );
+}
+
+#[cfg(test)]
+fn now_ts() -> i64 {
+    unix_now() as i64
+}
+
+#[tokio::test]
+async fn test_exempt_authentic_edits() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config = PolicyConfig {
+        exempt_authentic_edits: true,
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("state.json"), config)
+        .await
+        .unwrap();
+    let now = now_ts();
+
+    // User 1 become authentic
+    assert_eq!(
+        Action::Accept,
+        policy.check_update(&text_update(now, 1, "啊"))
+    );
+    assert_eq!(
+        Action::Accept,
+        policy.check_update(&edited_update(now, 1, "啊啊"))
+    );
+    // User 2 is not
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&edited_update(now, 2, "啊啊"))
+    );
+    // Nor is user 3, only trusted
+    let trusted = SpamState::Trusted {
+        since_ts: now as u64,
+        score: 0,
+    };
+    policy.db.update_user(&UserId(3), trusted);
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&edited_update(now, 3, "啊啊"))
+    );
+}
+
+#[tokio::test]
+async fn test_allowed_bots() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let delete = Action::Delete(ChatId(-1001), MessageId(1));
+    let now = now_ts();
+
+    let config = PolicyConfig {
+        allowed_bots: [UserId(10)].into(),
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("1.json"), config)
+        .await
+        .unwrap();
+    assert_eq!(delete, policy.check_update(&bot_update(now, 11, "啊")));
+    assert_eq!(
+        Action::Accept,
+        policy.check_update(&bot_update(now, 10, "啊"))
+    );
+    // Still under ah policy
+    assert_eq!(delete, policy.check_update(&bot_update(now, 10, "hello")));
+
+    let config = PolicyConfig {
+        allowed_bots: [UserId(10)].into(),
+        exempt_allowed_bots: true,
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("2.json"), config)
+        .await
+        .unwrap();
+    assert_eq!(delete, policy.check_update(&bot_update(now, 11, "hello")));
+    assert_eq!(
+        Action::Accept,
+        policy.check_update(&bot_update(now, 10, "hello"))
+    );
+}
+
+#[cfg(test)]
+fn sender_chat_update(date: i64, sender_chat_id: i64, automatic_forward: bool) -> Update {
+    let fields = format!(
+        r#""sender_chat": {{ "id": {sender_chat_id}, "type": "channel", "title": "c" }},
+        "is_automatic_forward": {automatic_forward},
+        "text": "hello""#
+    );
+    let message = message_json(-1001, 1, date, Some((136817688, true)), &fields);
+    message_update("message", &message)
+}
+
+#[tokio::test]
+async fn test_sender_chat() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config = PolicyConfig {
+        linked_channels: [ChatId(-1003)].into(),
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("state.json"), config)
+        .await
+        .unwrap();
+    let now = now_ts();
+
+    // Anonymous admins, linked channels and their automatic forwards
+    for (sender_chat, automatic_forward) in [(-1001, false), (-1003, false), (-1002, true)] {
+        assert_eq!(
+            Action::Accept,
+            policy.check_update(&sender_chat_update(now, sender_chat, automatic_forward))
+        );
+    }
+    // Anyone else posting as a channel
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&sender_chat_update(now, -1002, false))
+    );
+}
+
+#[tokio::test]
+async fn test_remote_checks() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut policy = PolicyState::builder(temp_dir.path().join("state.json"))
+        .remote_check(true)
+        .build()
+        .await
+        .unwrap();
+    let now = now_ts();
+
+    // Only medium-risk text is queued
+    for (uid, text) in [(1, "啊"), (2, "hello"), (3, "5k"), (4, "5k")] {
+        policy.check_update(&text_update(now, uid, text));
+    }
+    assert_eq!(
+        vec![
+            (ChatId(-1001), UserId(3), "5k".to_string()),
+            (ChatId(-1001), UserId(4), "5k".to_string())
+        ],
+        policy.take_remote_checks()
+    );
+    assert!(policy.take_remote_checks().is_empty());
+    assert_eq!(
+        Action::Ban(ChatId(-1001), UserId(3), BanReason::SpamText),
+        policy.check_remote_verdict(ChatId(-1001), UserId(3), true)
+    );
+    assert_eq!(
+        Action::Accept,
+        policy.check_remote_verdict(ChatId(-1001), UserId(4), false)
+    );
+    assert!(!policy.whois(ChatId(-1001), UserId(4)).state.is_spam());
+    // Authentic ones are left alone
+    assert_eq!(
+        Action::Accept,
+        policy.check_remote_verdict(ChatId(-1001), UserId(1), true)
+    );
+    // Trusted ones are not
+    let trusted = SpamState::Trusted {
+        since_ts: now as u64,
+        score: 0,
+    };
+    policy.db.update_user(&UserId(5), trusted);
+    assert_eq!(
+        Action::Ban(ChatId(-1001), UserId(5), BanReason::SpamText),
+        policy.check_remote_verdict(ChatId(-1001), UserId(5), true)
+    );
+}
+
+#[tokio::test]
+async fn test_whois() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut policy = PolicyState::new(temp_dir.path().join("state.json"))
+        .await
+        .unwrap();
+    let now = now_ts();
+
+    policy.check_update(&text_update(now, 1, "啊啊"));
+    policy.check_update(&text_update(now, 2, "5k"));
+    assert_eq!(
+        Action::Whois(ChatId(-1001), MessageId(1), UserId(3), UserId(1)),
+        policy.check_update(&text_update(now, 3, "/whois 1"))
+    );
+    assert_eq!(
+        UserReport {
+            user_id: UserId(1),
+            name: Some("test".into()),
+            state: SpamState::Authentic,
+            last_ah: Some(2),
+            recent_ah: vec![2],
+        },
+        policy.whois(ChatId(-1001), UserId(1))
+    );
+    let report = policy.whois(ChatId(-1001), UserId(2));
+    assert_eq!(SpamState::MaybeSpam(50), report.state);
+    assert_eq!(None, report.last_ah);
+    // Requester is not scored for the command
+    assert_eq!(
+        SpamState::MaybeSpam(0),
+        policy.whois(ChatId(-1001), UserId(3)).state
+    );
+    // Missing target
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&text_update(now, 3, "/whois"))
+    );
+    // Spam after a command is scored as usual
+    assert_eq!(
+        Action::DeleteAndBan(ChatId(-1001), MessageId(1), UserId(4), BanReason::SpamText),
+        policy.check_update(&text_update(now, 4, "/whois 1 Buy USDT now"))
+    );
+}
+
+#[tokio::test]
+async fn test_unban() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut policy = PolicyState::new(temp_dir.path().join("state.json"))
+        .await
+        .unwrap();
+    let now = now_ts();
+
+    assert_eq!(
+        Action::DeleteAndBan(ChatId(-1001), MessageId(1), UserId(1), BanReason::SpamText),
+        policy.check_update(&text_update(now, 1, "Buy USDT now"))
+    );
+    let action = policy.check_update(&text_update(now, 2, "/unban 1"));
+    assert_eq!(
+        Action::Unban(ChatId(-1001), MessageId(1), UserId(2), UserId(1)),
+        action
+    );
+    assert_eq!(
+        Some((ChatId(-1001), UserId(2), UserId(1))),
+        action.get_unban()
+    );
+    // Nothing changes until the requester is confirmed as admin
+    assert!(policy.whois(ChatId(-1001), UserId(1)).state.is_spam());
+    policy.pardon_user(UserId(1));
+    assert!(policy.whois(ChatId(-1001), UserId(1)).state.is_authentic());
+    // No spam behind the command
+    assert_eq!(
+        Action::DeleteAndBan(ChatId(-1001), MessageId(1), UserId(3), BanReason::SpamText),
+        policy.check_update(&text_update(now, 3, "/unban 1 Buy USDT now"))
+    );
+}
+
+#[tokio::test]
+async fn test_remember_name() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut policy = PolicyState::new(temp_dir.path().join("state.json"))
+        .await
+        .unwrap();
+    assert_eq!(None, policy.whois(ChatId(-1001), UserId(1)).name);
+    // Rejected messages count too
+    policy.check_update(&text_update(now_ts(), 1, "hello"));
+    assert_eq!(
+        Some("test"),
+        policy.whois(ChatId(-1001), UserId(1)).name.as_deref()
+    );
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+struct KeywordClassifier(&'static str);
+
+#[cfg(test)]
+impl SpamClassifier for KeywordClassifier {
+    fn classify_tier(&self, text: &str) -> RiskTier {
+        if text.contains(self.0) {
+            RiskTier::HighRisk
+        } else {
+            RiskTier::Unknown
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_custom_classifier() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut policy = PolicyState::builder(temp_dir.path().join("state.json"))
+        .classifier(KeywordClassifier("spam"))
+        .build()
+        .await
+        .unwrap();
+    let now = now_ts();
+    assert_eq!(RiskTier::HighRisk, policy.classify_text("spam here"));
+    assert_eq!(SpamState::Spam, KeywordClassifier("x").classify("x"));
+    // Built-in patterns no longer apply to text
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&text_update(now, 1, "Buy USDT now"))
+    );
+    assert_eq!(
+        Action::DeleteAndBan(ChatId(-1001), MessageId(1), UserId(2), BanReason::SpamText),
+        policy.check_update(&text_update(now, 2, "spam"))
+    );
+}
+
+#[tokio::test]
+async fn test_set_patterns() {
+    use crate::antispam::PatternSources;
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut policy = PolicyState::new(temp_dir.path().join("state.json"))
+        .await
+        .unwrap();
+    let now = now_ts();
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&text_update(now, 1, "foo"))
+    );
+
+    let sources = PatternSources {
+        high_risk: "foo".into(),
+        ..Default::default()
+    };
+    policy.set_patterns(SpamPatterns::compile(&sources).unwrap());
+    assert_eq!(
+        Action::DeleteAndBan(ChatId(-1001), MessageId(1), UserId(1), BanReason::SpamText),
+        policy.check_update(&text_update(now, 1, "foo"))
+    );
+}
+
+#[tokio::test]
+async fn test_consecutive_deletions() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config = PolicyConfig {
+        max_consecutive_deletions: Some(5),
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("state.json"), config)
+        .await
+        .unwrap();
+    let now = now_ts();
+    let delete = Action::Delete(ChatId(-1001), MessageId(1));
+
+    // Valid ah resets the counter
+    for _ in 0..4 {
+        assert_eq!(delete, policy.check_update(&text_update(now, 1, "好")));
+    }
+    assert_eq!(
+        Action::Accept,
+        policy.check_update(&text_update(now, 1, "啊"))
+    );
+    for _ in 0..4 {
+        assert_eq!(delete, policy.check_update(&text_update(now, 1, "好")));
+    }
+    assert_eq!(
+        Action::DeleteAndBan(
+            ChatId(-1001),
+            MessageId(1),
+            UserId(1),
+            BanReason::TooManyDeletions
+        ),
+        policy.check_update(&text_update(now, 1, "好"))
+    );
+}
+
+#[tokio::test]
+async fn test_min_ah_to_authentic() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config = PolicyConfig {
+        min_ah_to_authentic: 3,
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("state.json"), config)
+        .await
+        .unwrap();
+    let now = now_ts();
+    for uid in [2, 1, 2, 1, 2] {
+        assert_eq!(
+            Action::Accept,
+            policy.check_update(&text_update(now, uid, "啊"))
+        );
+    }
+    assert!(policy.whois(ChatId(-1001), UserId(2)).state.is_authentic());
+    let trusted = SpamState::Trusted {
+        since_ts: now as u64,
+        score: 0,
+    };
+    assert_eq!(trusted, policy.whois(ChatId(-1001), UserId(1)).state);
+
+    // Not fully trusted yet, risky text is scored
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&text_update(now + 1, 1, "5k"))
+    );
+    let scored = SpamState::Trusted {
+        since_ts: now as u64,
+        score: ScoringConfig::MEDIUM_RISK_SCORE,
+    };
+    assert_eq!(scored, policy.whois(ChatId(-1001), UserId(1)).state);
+    // Until they are spam, e.g. on a stolen account
+    assert_eq!(
+        Action::DeleteAndBan(ChatId(-1001), MessageId(1), UserId(1), BanReason::SpamText),
+        policy.check_update(&text_update(now, 1, "5k"))
+    );
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&text_update(now, 2, "Buy USDT now"))
+    );
+}
+
+#[tokio::test]
+async fn test_demote_authentic() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut policy = PolicyState::new(temp_dir.path().join("state.json"))
+        .await
+        .unwrap();
+    let now = now_ts();
+    policy.check_update(&text_update(now, 1, "啊"));
+    assert!(policy.whois(ChatId(-1001), UserId(1)).state.is_authentic());
+
+    // Medium risk is still absorbed
+    policy.check_update(&text_update(now, 1, "进群了解"));
+    assert!(policy.whois(ChatId(-1001), UserId(1)).state.is_authentic());
+
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&text_update(now, 1, "Buy USDT now"))
+    );
+    assert_eq!(
+        SpamState::MaybeSpam(0),
+        policy.whois(ChatId(-1001), UserId(1)).state
+    );
+    assert_eq!(
+        Action::DeleteAndBan(ChatId(-1001), MessageId(1), UserId(1), BanReason::SpamText),
+        policy.check_update(&text_update(now, 1, "Buy USDT now"))
+    );
+}
+
+#[tokio::test]
+async fn test_quote_not_scored() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut policy = PolicyState::new(temp_dir.path().join("state.json"))
+        .await
+        .unwrap();
+    let now = now_ts();
+    let spam = message_json(
+        -1001,
+        1,
+        0,
+        Some((2, false)),
+        &text_field("Buy USDT now, 日结 5k"),
+    );
+    let fields = format!(
+        r#""text": "啊", "reply_to_message": {spam},
+        "quote": {{ "text": "Buy USDT now", "position": 0 }}"#
+    );
+    let quote_update = message_update(
+        "message",
+        &message_json(-1001, 2, now, Some((3, false)), &fields),
+    );
+
+    // Replies are still deleted, but quoting spam never makes one a spammer
+    for _ in 0..3 {
+        assert_eq!(
+            Action::Delete(ChatId(-1001), MessageId(2)),
+            policy.check_update(&quote_update)
+        );
+    }
+    assert_eq!(
+        SpamState::MaybeSpam(0),
+        policy.whois(ChatId(-1001), UserId(3)).state
+    );
+}
+
+#[cfg(test)]
+fn forward_update(date: i64, user_id: u64, text: &str, origin: &str) -> Update {
+    let fields = format!(r#""forward_origin": {origin}, {}"#, text_field(text));
+    message_update(
+        "message",
+        &message_json(-1001, 1, date, Some((user_id, false)), &fields),
+    )
+}
+
+#[tokio::test]
+async fn test_forward_origin() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut policy = PolicyState::new(temp_dir.path().join("state.json"))
+        .await
+        .unwrap();
+    let now = now_ts();
+    let channel = |title: &str| {
+        format!(
+            r#"{{ "type": "channel", "date": 0, "message_id": 5,
+                "chat": {{ "id": -1002, "type": "channel", "title": {:?} }} }}"#,
+            title
+        )
+    };
+    let hidden = r#"{ "type": "hidden_user", "date": 0, "sender_user_name": "x" }"#;
+
+    // Forwarded from channels or hidden users by new users
+    assert_eq!(
+        Action::DeleteAndBan(ChatId(-1001), MessageId(1), UserId(1), BanReason::SpamText),
+        policy.check_update(&forward_update(now, 1, "hello", &channel("News")))
+    );
+    assert_eq!(
+        Action::DeleteAndBan(ChatId(-1001), MessageId(1), UserId(2), BanReason::SpamText),
+        policy.check_update(&forward_update(now, 2, "hello", hidden))
+    );
+    // Authentic users may forward, unless from a spammy channel
+    policy.check_update(&text_update(now, 3, "啊"));
+    let delete = Action::Delete(ChatId(-1001), MessageId(1));
+    assert_eq!(
+        delete,
+        policy.check_update(&forward_update(now, 3, "hello", &channel("News")))
+    );
+    assert!(policy.db.get_user(&UserId(3)).is_authentic());
+    assert_eq!(
+        delete,
+        policy.check_update(&forward_update(now, 3, "hello", &channel("Buy USDT now")))
+    );
+    assert!(!policy.db.get_user(&UserId(3)).is_trusted());
+}
+
+#[tokio::test]
+async fn test_check_bio() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config = PolicyConfig {
+        screen_bio: true,
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("state.json"), config)
+        .await
+        .unwrap();
+    let now = now_ts();
+
+    policy.check_update(&join_update(now, 1, "Alice"));
+    assert_eq!(
+        vec![(ChatId(-1001), UserId(1))],
+        policy.bios_to_screen(&join_update(now, 1, "Alice"))
+    );
+    assert_eq!(
+        Action::Accept,
+        policy.check_bio(ChatId(-1001), UserId(1), "I like cats")
+    );
+    assert_eq!(
+        SpamState::MaybeSpam(0),
+        policy.whois(ChatId(-1001), UserId(1)).state
+    );
+    // Screened once only, however many times they join
+    assert!(policy
+        .bios_to_screen(&join_update(now, 1, "Alice"))
+        .is_empty());
+    policy.check_update(&join_update(now, 3, "Carol"));
+    for _ in 0..2 {
+        assert_eq!(
+            Action::Accept,
+            policy.check_bio(ChatId(-1001), UserId(3), "5k")
+        );
+    }
+    assert_eq!(
+        SpamState::MaybeSpam(ScoringConfig::MEDIUM_RISK_SCORE),
+        policy.whois(ChatId(-1001), UserId(3)).state
+    );
+    policy.check_update(&join_update(now, 4, "Dave"));
+    assert_eq!(
+        Action::Ban(ChatId(-1001), UserId(4), BanReason::SpamBio),
+        policy.check_bio(ChatId(-1001), UserId(4), "Buy USDT now")
+    );
+    // Not for trusted ones
+    policy.check_update(&text_update(now, 2, "啊"));
+    assert!(policy
+        .bios_to_screen(&join_update(now, 2, "Bob"))
+        .is_empty());
+}
+
+#[cfg(test)]
+fn report_update(date: i64, reporter: u64, reported: u64) -> Update {
+    let reported = message_json(
+        -1001,
+        1,
+        date,
+        Some((reported, false)),
+        &text_field("nice pic"),
+    );
+    let fields = format!(r#""text": "/report", "reply_to_message": {reported}"#);
+    message_update(
+        "message",
+        &message_json(-1001, 2, date, Some((reporter, false)), &fields),
+    )
+}
+
+#[tokio::test]
+async fn test_schedule() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let now = now_ts();
+    // Start of the last hour, and the one before
+    let strict = now - now.rem_euclid(3600);
+    let normal = strict - 3600;
+    let hour = (strict.rem_euclid(24 * 3600) / 3600) as u8;
+    let window = StrictWindow {
+        start_hour: hour,
+        end_hour: (hour + 1) % 24,
+        spam_threshold: Some(ScoringConfig::MEDIUM_RISK_SCORE),
+        hold_untrusted: true,
+    };
+    let mut policy = PolicyState::builder(temp_dir.path().join("state.json"))
+        .schedule(vec![window])
+        .build()
+        .await
+        .unwrap();
+    let delete = Action::Delete(ChatId(-1001), MessageId(1));
+
+    assert_eq!(delete, policy.check_update(&text_update(normal, 1, "5k")));
+    assert_eq!(
+        Action::DeleteAndBan(ChatId(-1001), MessageId(1), UserId(2), BanReason::SpamText),
+        policy.check_update(&text_update(strict, 2, "5k"))
+    );
+    // Valid ah held for the untrusted, not counted as deletions
+    assert_eq!(
+        Action::Accept,
+        policy.check_update(&text_update(normal, 3, "啊"))
+    );
+    assert_eq!(delete, policy.check_update(&text_update(strict, 4, "啊")));
+    assert_eq!(
+        0,
+        policy
+            .db
+            .get_profile(&UserId(4))
+            .unwrap()
+            .consecutive_deletions
+    );
+    assert_eq!(
+        Action::Accept,
+        policy.check_update(&text_update(normal, 5, "啊啊"))
+    );
+    assert_eq!(
+        Action::Accept,
+        policy.check_update(&text_update(strict, 3, "啊啊啊"))
+    );
+
+    // Scores add up to the window's threshold
+    for _ in 0..3 {
+        assert_eq!(
+            delete,
+            policy.check_update(&text_update(strict, 6, "hello"))
+        );
+    }
+    assert_eq!(
+        Action::DeleteAndBan(ChatId(-1001), MessageId(1), UserId(6), BanReason::SpamText),
+        policy.check_update(&text_update(strict, 6, "hello"))
+    );
+
+    let wrapping = StrictWindow {
+        start_hour: 22,
+        end_hour: 6,
+        ..Default::default()
+    };
+    assert!(wrapping.contains(23) && wrapping.contains(0) && wrapping.contains(5));
+    assert!(!wrapping.contains(6) && !wrapping.contains(21));
+    let empty = StrictWindow {
+        start_hour: 6,
+        end_hour: 6,
+        ..Default::default()
+    };
+    assert!(!empty.contains(6) && !empty.contains(0));
+}
+
+#[tokio::test]
+async fn test_ah_stats() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut policy = PolicyState::new(temp_dir.path().join("state.json"))
+        .await
+        .unwrap();
+    let now = now_ts();
+    for (uid, text) in [(1, "啊"), (2, "啊啊"), (1, "啊啊啊")] {
+        assert_eq!(
+            Action::Accept,
+            policy.check_update(&text_update(now, uid, text))
+        );
+    }
+    assert_eq!(
+        Action::AhStats(ChatId(-1001), MessageId(1)),
+        policy.check_update(&text_update(now, 2, "/ahstats"))
+    );
+    // Only for those posted ah
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&text_update(now, 3, "/ahstats"))
+    );
+    // Once in a while
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&text_update(now + 10, 1, "/ahstats"))
+    );
+    assert_eq!(
+        Action::AhStats(ChatId(-1001), MessageId(1)),
+        policy.check_update(&text_update(now + 300, 1, "/ahstats"))
+    );
+    let leaderboard = policy.ah_leaderboard(ChatId(-1001), 10);
+    assert_eq!(6, leaderboard.total);
+    assert_eq!(3, leaderboard.longest_streak);
+    assert_eq!(
+        vec![
+            (UserId(1), Some("test".into()), 4),
+            (UserId(2), Some("test".into()), 2)
+        ],
+        leaderboard.top
+    );
+    assert_eq!(
+        "Total ah: 6\nLongest streak: 3\n1. test: 4\n2. test: 2",
+        leaderboard.to_string()
+    );
+    assert_eq!(1, policy.ah_leaderboard(ChatId(-1001), 1).top.len());
+    assert!(policy.ah_leaderboard(ChatId(-1002), 10).top.is_empty());
+}
+
+#[tokio::test]
+async fn test_dry_run_not_saved() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("state.json");
+    let mut policy = PolicyState::builder(&path)
+        .dry_run(true)
+        .build()
+        .await
+        .unwrap();
+    let now = now_ts();
+    policy.check_update(&text_update(now, 1, "Buy USDT now"));
+    assert!(policy.db.get_user(&UserId(1)).is_spam());
+    policy.save().await.unwrap();
+    drop(policy);
+
+    let policy = PolicyState::new(&path).await.unwrap();
+    assert!(!policy.db.get_user(&UserId(1)).is_spam());
+}
+
+#[tokio::test]
+async fn test_pending_actions() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut policy = PolicyState::new(temp_dir.path().join("state.json"))
+        .await
+        .unwrap();
+    let (chat, msg, user) = (ChatId(-1001), MessageId(1), UserId(1));
+    policy.add_pending(&Action::Accept);
+    policy.add_pending(&Action::DeleteAndBan(chat, msg, user, BanReason::SpamName));
+    let ban = PendingAction::Ban {
+        chat_id: chat,
+        user_id: user,
+        revoke_messages: true,
+        reason: BanReason::SpamName.to_string(),
+    };
+    assert_eq!(
+        vec![PendingAction::Delete(chat, msg), ban.clone()],
+        policy.pending_actions()
+    );
+    policy.confirm_delivered(Delivered::Deleted(chat, msg));
+    policy.confirm_delivered(Delivered::Banned(ChatId(-1002), user));
+    assert_eq!(vec![ban], policy.pending_actions());
+    policy.confirm_delivered(Delivered::Banned(chat, user));
+    assert!(policy.pending_actions().is_empty());
+
+    // Pardon drops bans not sent yet
+    policy.add_pending(&Action::DeleteAndBan(chat, msg, user, BanReason::SpamName));
+    policy.pardon_user(user);
+    assert_eq!(
+        vec![PendingAction::Delete(chat, msg)],
+        policy.pending_actions()
+    );
+}
+
+#[tokio::test]
+async fn test_dry_check_text() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config = PolicyConfig {
+        min_noa: 2,
+        min_ah_to_authentic: 3,
+        mute_threshold: Some(ScoringConfig::MEDIUM_RISK_SCORE),
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("state.json"), config)
+        .await
+        .unwrap();
+    let now = now_ts();
+    let decision = |action| match action {
+        Action::Accept => Decision::Accept,
+        Action::Delete(..) => Decision::Delete,
+        Action::Restrict(..) => Decision::Mute,
+        Action::DeleteAndBan(_, _, _, reason) => Decision::Ban(reason),
+        other => panic!("unexpected {:?}", other),
+    };
+    // Same as the real thing, on a fresh user each
+    for (uid, text) in [(1, "啊"), (2, "啊啊"), (3, "5k"), (4, "Buy USDT now")] {
+        let dry = policy.dry_check_text(SpamState::default(), text);
+        assert_eq!(
+            decision(policy.check_update(&text_update(now, uid, text))),
+            dry
+        );
+    }
+    // And on one now trusted
+    let trusted = policy.db.get_user(&UserId(2));
+    assert!(matches!(trusted, SpamState::Trusted { .. }));
+    let dry = policy.dry_check_text(trusted, "5k");
+    assert_eq!(
+        decision(policy.check_update(&text_update(now, 2, "5k"))),
+        dry
+    );
+}
+
+#[tokio::test]
+async fn test_mute() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config = PolicyConfig {
+        mute_threshold: Some(30),
+        ..Default::default()
+    };
+    // Low enough for two medium-risk messages to stay below spam
+    let scoring = ScoringConfig {
+        medium_risk_score: 30,
+        ..Default::default()
+    };
+    let mut policy = PolicyState::builder(temp_dir.path().join("state.json"))
+        .config(config)
+        .scoring(scoring)
+        .build()
+        .await
+        .unwrap();
+    let now = now_ts();
+    let hour = Duration::from_secs(3600);
+    assert_eq!(
+        Action::Restrict(ChatId(-1001), MessageId(1), UserId(1), hour),
+        policy.check_update(&text_update(now, 1, "5k"))
+    );
+    assert_eq!(1, policy.db.get_profile(&UserId(1)).unwrap().mutes);
+    // Not for the score left from the last one, deleted as usual
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&text_update(now, 1, "你好"))
+    );
+    assert_eq!(1, policy.db.get_profile(&UserId(1)).unwrap().mutes);
+    // Banned on the next risky message, though still below spam
+    assert_eq!(
+        Action::DeleteAndBan(
+            ChatId(-1001),
+            MessageId(1),
+            UserId(1),
+            BanReason::RepeatOffense
+        ),
+        policy.check_update(&text_update(now, 1, "6k"))
+    );
+    // Below the threshold, deleted as usual
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&text_update(now, 2, "你好"))
+    );
+}
+
+#[tokio::test]
+async fn test_report() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config = PolicyConfig {
+        report_quorum: Some(2),
+        min_reporter_ah: 2,
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("state.json"), config)
+        .await
+        .unwrap();
+    let now = now_ts();
+    let delete = Action::Delete(ChatId(-1001), MessageId(2));
+    for (uid, text) in [
+        (2, "啊"),
+        (3, "啊啊"),
+        (4, "啊"),
+        (2, "啊啊"),
+        (3, "啊"),
+        (7, "啊"),
+    ] {
+        policy.check_update(&text_update(now, uid, text));
+    }
+
+    // Reports from the untrusted, or those with little history, don't count
+    assert_eq!(delete, policy.check_update(&report_update(now, 5, 1)));
+    assert_eq!(delete, policy.check_update(&report_update(now, 6, 1)));
+    assert_eq!(delete, policy.check_update(&report_update(now, 7, 1)));
+    assert_eq!(delete, policy.check_update(&report_update(now, 2, 1)));
+    // Nor do repeated ones
+    assert_eq!(delete, policy.check_update(&report_update(now, 2, 1)));
+    assert_eq!(
+        Action::DeleteAndBan(ChatId(-1001), MessageId(2), UserId(1), BanReason::Reported),
+        policy.check_update(&report_update(now, 3, 1))
+    );
+    assert!(policy.whois(ChatId(-1001), UserId(1)).state.is_spam());
+    assert!(!BanReason::Reported.revoke_messages());
+    // Reporting oneself does nothing
+    assert_eq!(delete, policy.check_update(&report_update(now, 2, 2)));
+    // Trusted members only get the message deleted
+    assert_eq!(delete, policy.check_update(&report_update(now, 2, 4)));
+    assert_eq!(
+        Action::DeleteReported(ChatId(-1001), MessageId(2), MessageId(1)),
+        policy.check_update(&report_update(now, 3, 4))
+    );
+    assert!(policy.whois(ChatId(-1001), UserId(4)).state.is_authentic());
+}
+
+#[test]
+fn test_url_host() {
+    assert_eq!(
+        Some("github.com".into()),
+        url_host("https://GitHub.com/sorz")
+    );
+    assert_eq!(Some("github.com".into()), url_host("github.com"));
+    assert_eq!(
+        Some("a.b.example".into()),
+        url_host("http://u@a.b.example:80?x")
+    );
+    assert_eq!(None, url_host("https:///path"));
+}
+
+#[tokio::test]
+async fn test_allowed_domains() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config = PolicyConfig {
+        allowed_domains: ["github.com".to_string()].into(),
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("state.json"), config)
+        .await
+        .unwrap();
+    let now = now_ts();
+    let link_update = |uid: u64, text: &str| -> Update {
+        let url_len = text.encode_utf16().count();
+        let mut update = text_update(now, uid, text);
+        let json = format!(r#"[{{"type":"url","offset":0,"length":{}}}]"#, url_len);
+        if let UpdateKind::Message(ref mut msg) = update.kind {
+            if let MessageKind::Common(ref mut common) = msg.kind {
+                if let teloxide::types::MediaKind::Text(ref mut media) = common.media_kind {
+                    media.entities = sonic_rs::from_str(&json).unwrap();
+                }
+            }
+        }
+        update
+    };
+    let delete = Action::Delete(ChatId(-1001), MessageId(1));
+
+    // Only for trusted users
+    assert_eq!(
+        delete,
+        policy.check_update(&link_update(1, "https://github.com/sorz"))
+    );
+    assert_eq!(
+        Action::Accept,
+        policy.check_update(&text_update(now, 1, "啊"))
+    );
+    assert_eq!(
+        Action::Accept,
+        policy.check_update(&link_update(1, "https://github.com/sorz"))
+    );
+    assert_eq!(
+        Action::Accept,
+        policy.check_update(&link_update(1, "gist.github.com"))
+    );
+    assert_eq!(
+        delete,
+        policy.check_update(&link_update(1, "https://example.com"))
+    );
+    assert_eq!(
+        delete,
+        policy.check_update(&link_update(1, "notgithub.com"))
+    );
+}
+
+#[cfg(test)]
+fn join_update(date: i64, user_id: u64, name: &str) -> Update {
+    let fields = format!(
+        r#""new_chat_members": [{{ "id": {user_id}, "is_bot": false, "first_name": {name:?} }}]"#
+    );
+    message_update(
+        "message",
+        &message_json(-1001, 1, date, Some((user_id, false)), &fields),
+    )
+}
+
+#[cfg(test)]
+fn callback_update(user_id: u64, data: &str) -> Update {
+    let message = message_json(
+        -1001,
+        2,
+        now_ts(),
+        Some((100, true)),
+        &text_field("press 啊"),
+    );
+    sonic_rs::from_str(&format!(
+        r#"{{
+            "update_id": 1,
+            "callback_query": {{
+                "id": "42",
+                "from": {{ "id": {user_id}, "is_bot": false, "first_name": "test" }},
+                "message": {message},
+                "chat_instance": "1",
+                "data": {data:?}
+            }}
+        }}"#,
+    ))
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_captcha() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config = PolicyConfig {
+        captcha_timeout: Some(Duration::from_secs(60)),
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("state.json"), config)
+        .await
+        .unwrap();
+    let now = now_ts();
+
+    let action = policy.check_update(&join_update(now, 1, "Alice"));
+    assert_eq!(
+        Action::Challenge(ChatId(-1001), Some(MessageId(1)), UserId(1)),
+        action
+    );
+    assert_eq!(Some((ChatId(-1001), UserId(1))), action.get_challenge());
+    // Only the one challenged can answer
+    assert_eq!(
+        Action::Accept,
+        policy.check_update(&callback_update(2, &challenge_answer(UserId(1))))
+    );
+    assert_eq!(
+        Action::ChallengePassed(ChatId(-1001), Some(MessageId(2)), UserId(1)),
+        policy.check_update(&callback_update(1, &challenge_answer(UserId(1))))
+    );
+    assert_eq!(
+        Action::Accept,
+        policy.check_update(&callback_update(1, &challenge_answer(UserId(1))))
+    );
+    assert!(policy.expired_challenges().is_empty());
+
+    // Not answered in time
+    policy.check_update(&join_update(now, 2, "Bob"));
+    policy.db.add_challenge(&ChatId(-1001), &UserId(2), 0);
+    assert_eq!(
+        vec![(ChatId(-1001), UserId(2))],
+        policy.expired_challenges()
+    );
+
+    // Never posted
+    policy.check_update(&join_update(now, 4, "Dave"));
+    policy.db.add_challenge(&ChatId(-1001), &UserId(4), 0);
+    policy.cancel_challenge(ChatId(-1001), UserId(4));
+    assert!(policy.expired_challenges().is_empty());
+
+    // Trusted ones are let in
+    policy.check_update(&text_update(now, 3, "啊"));
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&join_update(now, 3, "Carol"))
+    );
+}
+
+#[tokio::test]
+async fn test_cautious_name_check() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut policy = PolicyState::new(temp_dir.path().join("state.json"))
+        .await
+        .unwrap();
+    let now = now_ts();
+    assert_eq!(
+        Action::DeleteAndBan(ChatId(-1001), MessageId(1), UserId(1), BanReason::SpamName),
+        policy.check_update(&join_update(now, 1, "🔥 Alice"))
+    );
+
+    let config = PolicyConfig {
+        cautious_name_check: true,
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("cautious.json"), config)
+        .await
+        .unwrap();
+    // Name alone is not enough
+    for uid in [1, 2] {
+        assert_eq!(
+            Action::Delete(ChatId(-1001), MessageId(1)),
+            policy.check_update(&join_update(now, uid, "🔥 Alice"))
+        );
+    }
+    // Nor joining again
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&join_update(now, 1, "🔥 Alice"))
+    );
+    assert!(!policy.whois(ChatId(-1001), UserId(1)).state.is_spam());
+    // Along with a risky message
+    assert_eq!(
+        Action::DeleteAndBan(ChatId(-1001), MessageId(1), UserId(1), BanReason::SpamText),
+        policy.check_update(&text_update(now, 1, "hello"))
+    );
+    // Or not
+    assert_eq!(
+        Action::Accept,
+        policy.check_update(&text_update(now, 2, "啊"))
+    );
+    assert!(policy.whois(ChatId(-1001), UserId(2)).state.is_authentic());
+}
+
+#[tokio::test]
+async fn test_monitor_channels() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config = PolicyConfig {
+        monitor_channels: true,
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("state.json"), config)
+        .await
+        .unwrap();
+    let post: Update = sonic_rs::from_str(&format!(
+        r#"{{
+            "update_id": 1,
+            "channel_post": {{
+                "message_id": 1,
+                "date": {},
+                "chat": {{ "id": -1002, "type": "channel", "title": "ah" }},
+                "text": "Buy USDT now"
+            }}
+        }}"#,
+        now_ts()
+    ))
+    .unwrap();
+    assert_eq!(None, policy.channel_score(ChatId(-1002)));
+    assert_eq!(Action::Accept, policy.check_update(&post));
+    assert_eq!(Some(SpamState::Spam), policy.channel_score(ChatId(-1002)));
+
+    let mut policy = PolicyState::new(temp_dir.path().join("off.json"))
+        .await
+        .unwrap();
+    assert_eq!(Action::Accept, policy.check_update(&post));
+    assert_eq!(None, policy.channel_score(ChatId(-1002)));
+}
+
+#[tokio::test]
+async fn test_builder() {
+    use crate::antispam::PatternSources;
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let sources = PatternSources {
+        medium_risk: "foo".into(),
+        ..Default::default()
+    };
+    let mut policy = PolicyState::builder(temp_dir.path().join("state.json"))
+        .patterns(SpamPatterns::compile(&sources).unwrap())
+        .scoring(ScoringConfig {
+            medium_risk_score: 30,
+            ..Default::default()
+        })
+        .build()
+        .await
+        .unwrap();
+    policy.check_update(&text_update(now_ts(), 1, "foo"));
+    assert_eq!(
+        SpamState::MaybeSpam(30),
+        policy.whois(ChatId(-1001), UserId(1)).state
+    );
+    assert_eq!(1, policy.config.min_ah_to_authentic);
+}
+
+#[tokio::test]
+async fn test_spam_threshold() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut policy = PolicyState::builder(temp_dir.path().join("state.json"))
+        .scoring(ScoringConfig {
+            spam_threshold: 40,
+            ..Default::default()
+        })
+        .build()
+        .await
+        .unwrap();
+    let now = now_ts();
+    // Scores add up to the configured threshold, not the default one
+    for _ in 0..2 {
+        assert_eq!(
+            Action::Delete(ChatId(-1001), MessageId(1)),
+            policy.check_update(&text_update(now, 1, "hello"))
+        );
+    }
+    assert_eq!(
+        Action::DeleteAndBan(ChatId(-1001), MessageId(1), UserId(1), BanReason::SpamText),
+        policy.check_update(&text_update(now, 1, "hello"))
+    );
+}
+
+#[tokio::test]
+async fn test_match_stats() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut policy = PolicyState::new(temp_dir.path().join("state.json"))
+        .await
+        .unwrap();
+    let other = PolicyState::new(temp_dir.path().join("other.json"))
+        .await
+        .unwrap();
+    policy.check_update(&text_update(now_ts(), 1, "Buy USDT now"));
+    assert!(policy
+        .match_stats()
+        .last_match(RiskTier::HighRisk)
+        .is_some());
+    // Not shared, and not counted when only asked
+    other.classify_text("Buy USDT now");
+    assert_eq!(None, other.match_stats().last_match(RiskTier::MediumRisk));
+    assert_eq!(None, other.match_stats().last_match(RiskTier::HighRisk));
+}
+
+#[tokio::test]
+async fn test_log_accepted() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config = PolicyConfig {
+        log_accepted: true,
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("state.json"), config)
+        .await
+        .unwrap();
+    let update = text_update(now_ts(), 4242, "啊啊");
+    let UpdateKind::Message(message) = &update.kind else {
+        unreachable!()
+    };
+    assert_eq!(
+        Some("[-1001] Accept [MessageId(1)] from [4242] with 2 ah: 啊啊".into()),
+        policy.accepted_log_line(UserId(4242), 2, message)
+    );
+    policy.config.log_accepted = false;
+    assert_eq!(None, policy.accepted_log_line(UserId(4242), 2, message));
+}
+
+#[tokio::test]
+async fn test_evaluate_user() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config = PolicyConfig {
+        max_consecutive_deletions: Some(2),
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("state.json"), config)
+        .await
+        .unwrap();
+    let name_match = Signals { name_match: true };
+    let verdict = policy.evaluate_user(UserId(1), &Default::default());
+    assert_eq!(
+        Verdict {
+            banned: false,
+            state: SpamState::MaybeSpam(0),
+            reasons: vec![],
+        },
+        verdict
+    );
+    let verdict = policy.evaluate_user(UserId(1), &name_match);
+    assert!(verdict.banned);
+    assert_eq!(vec![BanReason::SpamName], verdict.reasons);
+
+    // Spam text and deletions add up
+    policy.db.update_user(&UserId(2), SpamState::Spam);
+    policy.db.record_deletion(&UserId(2));
+    policy.db.record_deletion(&UserId(2));
+    let verdict = policy.evaluate_user(UserId(2), &name_match);
+    assert_eq!(
+        vec![
+            BanReason::SpamName,
+            BanReason::SpamText,
+            BanReason::TooManyDeletions
+        ],
+        verdict.reasons
+    );
+
+    // Authentic users may still be banned for posting junk
+    policy.db.update_user(&UserId(3), SpamState::Authentic);
+    assert!(!policy.evaluate_user(UserId(3), &Default::default()).banned);
+    policy.db.record_deletion(&UserId(3));
+    policy.db.record_deletion(&UserId(3));
+    let verdict = policy.evaluate_user(UserId(3), &Default::default());
+    assert_eq!(vec![BanReason::TooManyDeletions], verdict.reasons);
+
+    // Name alone is not enough in cautious mode
+    policy.config.cautious_name_check = true;
+    assert!(!policy.evaluate_user(UserId(1), &name_match).banned);
+}
+
+#[cfg(test)]
+fn dice_update(date: i64, user_id: u64) -> Update {
+    let fields = r#""dice": { "emoji": "🎰", "value": 64 }"#;
+    message_update(
+        "message",
+        &message_json(-1001, 1, date, Some((user_id, false)), fields),
+    )
+}
+
+#[cfg(test)]
+fn photo_update(date: i64, user_id: u64, caption: Option<&str>) -> Update {
+    let caption = caption.map_or(String::new(), |c| format!(r#", "caption": {:?}"#, c));
+    let fields = format!(
+        r#""photo": [{{
+            "file_id": "AgAD", "file_unique_id": "AgADp", "file_size": 1024,
+            "width": 90, "height": 90
+        }}]{caption}"#
+    );
+    message_update(
+        "message",
+        &message_json(-1001, 1, date, Some((user_id, false)), &fields),
+    )
+}
+
+#[tokio::test]
+async fn test_media() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut policy = PolicyState::new(temp_dir.path().join("state.json"))
+        .await
+        .unwrap();
+    let now = now_ts();
+    let delete = Action::Delete(ChatId(-1001), MessageId(1));
+
+    // Captions are checked like text
+    assert_eq!(
+        Action::DeleteAndBan(ChatId(-1001), MessageId(1), UserId(1), BanReason::SpamText),
+        policy.check_update(&photo_update(now, 1, Some("Buy USDT now")))
+    );
+    // Bare images are medium risk for new users...
+    assert_eq!(delete, policy.check_update(&photo_update(now, 2, None)));
+    assert_eq!(
+        SpamState::MaybeSpam(ScoringConfig::MEDIUM_RISK_SCORE),
+        policy.db.get_user(&UserId(2))
+    );
+    let update = photo_update(now, 2, None);
+    let UpdateKind::Message(message) = &update.kind else {
+        unreachable!()
+    };
+    assert_eq!(
+        vec![BanReason::SpamText],
+        policy.classify_user(UserId(2), message).reasons
+    );
+    assert_eq!(
+        Action::DeleteAndBan(ChatId(-1001), MessageId(1), UserId(2), BanReason::SpamText),
+        policy.check_update(&update)
+    );
+    // ...but not for authentic ones
+    policy.check_update(&text_update(now, 3, "啊"));
+    assert_eq!(delete, policy.check_update(&photo_update(now, 3, None)));
+    assert!(policy.db.get_user(&UserId(3)).is_authentic());
+}
+
+#[tokio::test]
+async fn test_dice_flood() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config = PolicyConfig {
+        max_dice_per_window: Some(2),
+        allow_authentic_dice: true,
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("state.json"), config)
+        .await
+        .unwrap();
+    let now = now_ts();
+    let delete = Action::Delete(ChatId(-1001), MessageId(1));
+
+    // One dice from an authentic user is fine, more are deleted
+    policy.check_update(&text_update(now, 1, "啊"));
+    assert_eq!(Action::Accept, policy.check_update(&dice_update(now, 1)));
+    assert_eq!(delete, policy.check_update(&dice_update(now, 1)));
+    assert_eq!(delete, policy.check_update(&dice_update(now, 1)));
+    // A new window starts afresh
+    let later = now + 3600;
+    assert_eq!(Action::Accept, policy.check_update(&dice_update(later, 1)));
+
+    // Unknown users are banned on repetition
+    assert_eq!(delete, policy.check_update(&dice_update(now, 2)));
+    assert_eq!(delete, policy.check_update(&dice_update(now, 2)));
+    assert_eq!(delete, policy.check_update(&dice_update(now, 2)));
+    assert_eq!(
+        Action::DeleteAndBan(ChatId(-1001), MessageId(1), UserId(2), BanReason::SpamText),
+        policy.check_update(&dice_update(now, 2))
+    );
+    // Those done with their window are forgotten
+    policy.check_update(&dice_update(now + 7200, 3));
+    assert_eq!(
+        vec![&UserId(3)],
+        policy.recent_dice.keys().collect::<Vec<_>>()
+    );
+}
+
+#[tokio::test]
+async fn test_max_message_len() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config = PolicyConfig {
+        max_message_len: Some(10),
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("state.json"), config)
+        .await
+        .unwrap();
+    let now = now_ts();
+    let long = "啊".repeat(11);
+
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&text_update(now, 1, &long))
+    );
+    // Padded spam is still scored
+    let padded = format!("Buy USDT now {}", "x".repeat(20));
+    assert_eq!(
+        Action::DeleteAndBan(ChatId(-1001), MessageId(1), UserId(3), BanReason::SpamText),
+        policy.check_update(&text_update(now, 3, &padded))
+    );
+    // Authentic users are exempt, when it's their turn for that many ah
+    for noa in 1..=10 {
+        let uid = 2 - noa % 2;
+        let text = "啊".repeat(noa as usize);
+        assert_eq!(
+            Action::Accept,
+            policy.check_update(&text_update(now, uid, &text))
+        );
+    }
+    assert_eq!(
+        Action::Accept,
+        policy.check_update(&text_update(now, 1, &long))
+    );
+}
+
+#[cfg(test)]
+fn pinned_update(date: i64, user_id: u64) -> Update {
+    let pinned = message_json(-1001, 1, date, None, &text_field("啊"));
+    let fields = format!(r#""pinned_message": {pinned}"#);
+    message_update(
+        "message",
+        &message_json(-1001, 2, date, Some((user_id, false)), &fields),
+    )
+}
+
+/// Sticker message from the user, in set `set_name` if any.
+#[cfg(test)]
+fn sticker_update(
+    chat_id: i64,
+    user_id: u64,
+    file_unique_id: &str,
+    set_name: Option<&str>,
+) -> Update {
+    let set_name = set_name.map_or(String::new(), |s| format!(r#", "set_name": {:?}"#, s));
+    let fields = format!(
+        r#""sticker": {{
+            "file_id": "CAACAgUAAx0", "file_unique_id": {file_unique_id:?}, "type": "regular",
+            "width": 512, "height": 512, "is_animated": false, "is_video": false{set_name}
+        }}"#
+    );
+    let message = message_json(chat_id, 1, now_ts(), Some((user_id, false)), &fields);
+    message_update("message", &message)
+}
+
+#[tokio::test]
+async fn test_allowed_system_messages() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut policy = PolicyState::new(temp_dir.path().join("state.json"))
+        .await
+        .unwrap();
+    let now = now_ts();
+    assert_eq!(Action::Accept, policy.check_update(&pinned_update(now, 1)));
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&join_update(now, 2, "test"))
+    );
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config = PolicyConfig {
+        allowed_system_messages: ["new_chat_members".parse().unwrap()].into(),
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("state.json"), config)
+        .await
+        .unwrap();
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(2)),
+        policy.check_update(&pinned_update(now, 1))
+    );
+    assert_eq!(
+        Action::Accept,
+        policy.check_update(&join_update(now, 2, "test"))
+    );
+    assert!("pinned_message".parse::<SystemMessage>().is_err());
+}
+
+#[tokio::test]
+async fn test_min_noa() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config = PolicyConfig {
+        min_noa: 2,
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("state.json"), config)
+        .await
+        .unwrap();
+    let now = now_ts();
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&text_update(now, 1, "啊"))
+    );
+    assert_eq!(
+        Action::Accept,
+        policy.check_update(&text_update(now, 1, "啊啊"))
+    );
+    assert_eq!(Some((UserId(1), 2)), policy.db.get_chat(&ChatId(-1001)));
+    // Step rule still applies
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&text_update(now, 2, "啊啊啊啊"))
+    );
+}
+
+#[tokio::test]
+async fn test_empty_text_with_entities() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config = PolicyConfig {
+        min_noa: 0,
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("state.json"), config)
+        .await
+        .unwrap();
+    let fields = r#""text": "", "entities": [{ "type": "bold", "offset": 0, "length": 0 }]"#;
+    let update = message_update(
+        "message",
+        &message_json(-1001, 1, now_ts(), Some((1, false)), fields),
+    );
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&update)
+    );
+    assert_eq!(None, policy.db.get_chat(&ChatId(-1001)));
+}
+
+#[tokio::test]
+async fn test_classify_user() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut policy = PolicyState::new(temp_dir.path().join("state.json"))
+        .await
+        .unwrap();
+    let now = now_ts();
+    let spam = text_update(now, 1, "Buy USDT now");
+    let UpdateKind::Message(ref message) = spam.kind else {
+        unreachable!()
+    };
+
+    policy.check_update(&text_update(now, 1, "你好"));
+    let before = policy.whois(ChatId(-1001), UserId(1));
+    let verdict = policy.classify_user(UserId(1), message);
+    assert!(verdict.banned);
+    assert_eq!(vec![BanReason::SpamText], verdict.reasons);
+    assert_eq!(before, policy.whois(ChatId(-1001), UserId(1)));
+
+    // Same as acted on
+    assert_eq!(
+        Action::DeleteAndBan(ChatId(-1001), MessageId(1), UserId(1), BanReason::SpamText),
+        policy.check_update(&spam)
+    );
+    assert_eq!(verdict.state, policy.db.get_user(&UserId(1)));
+}
+
+#[tokio::test]
+async fn test_chat_stickers() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut policy = PolicyState::new(temp_dir.path().join("state.json"))
+        .await
+        .unwrap();
+    let sticker_update = |chat_id| sticker_update(chat_id, 1, "AgADnew", None);
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&sticker_update(-1001))
+    );
+    policy.allow_sticker(Some(ChatId(-1001)), "AgADnew");
+    assert_eq!(Action::Accept, policy.check_update(&sticker_update(-1001)));
+    assert_eq!(
+        Action::Delete(ChatId(-1002), MessageId(1)),
+        policy.check_update(&sticker_update(-1002))
+    );
+    policy.allow_sticker(None, "AgADnew");
+    assert_eq!(Action::Accept, policy.check_update(&sticker_update(-1002)));
+}
+
+#[tokio::test]
+async fn test_sticker_sets() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut policy = PolicyState::new(temp_dir.path().join("state.json"))
+        .await
+        .unwrap();
+    let sticker_update =
+        |user_id, file_unique_id| sticker_update(-1001, user_id, file_unique_id, Some("ahahah"));
+    assert_eq!(
+        Action::Delete(ChatId(-1001), MessageId(1)),
+        policy.check_update(&sticker_update(1, "AgADone"))
+    );
+    policy.allow_sticker_set(Some(ChatId(-1001)), "ahahah");
+    // Any sticker in the set, whatever its file id
+    assert_eq!(
+        Action::Accept,
+        policy.check_update(&sticker_update(1, "AgADone"))
+    );
+    assert_eq!(
+        Action::Accept,
+        policy.check_update(&sticker_update(2, "AgADtwo"))
+    );
 }
@@ -1,106 +1,978 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    fmt, fs, io,
     path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::anyhow;
+use log::info;
+use redb::{Database, ReadableTable, TableDefinition, TableError};
 use sonic_rs::{Deserialize, Serialize};
-use teloxide::types::{ChatId, UserId};
-use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom},
-};
+use teloxide::types::{ChatId, MessageId, UserId};
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+use crate::antispam::{SpamState, SPAM_THREHOLD};
+
+/// Bump on any incompatible change to `Data`, with a step in `migrate()`.
+pub const DATA_VERSION: u32 = 4;
 
-use crate::antispam::SpamState;
+/// Spam state and profile of each user, by user id, each user written on
+/// its own once changed.
+const USERS: TableDefinition<u64, &[u8]> = TableDefinition::new("users");
+const PROFILES: TableDefinition<u64, &[u8]> = TableDefinition::new("profiles");
+/// The rest of `Data`, written as a whole on each save.
+const REST: TableDefinition<&str, &[u8]> = TableDefinition::new("rest");
+const REST_KEY: &str = "data";
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+/// The whole state, as kept in memory. Also the JSON format of the state
+/// file before the database, still used to import one and by `parse_chat`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Data {
+    /// Files written before versioning are v1.
+    #[serde(default = "version_1")]
+    pub version: u32,
     pub chats: HashMap<ChatId, (UserId, u32)>,
+    /// Empty in the `REST` of the database.
+    #[serde(default)]
     pub users: HashMap<UserId, SpamState>,
+    #[serde(default)]
+    pub profiles: HashMap<UserId, UserProfile>,
+    /// Recently accepted ah of each chat, oldest first.
+    #[serde(default)]
+    pub ah_history: HashMap<ChatId, VecDeque<(UserId, u32)>>,
+    /// File unique ids of stickers allowed on top of the built-in list.
+    #[serde(default)]
+    pub allowed_stickers: HashSet<String>,
+    /// Same as `allowed_stickers` but only in the chat.
+    #[serde(default)]
+    pub chat_stickers: HashMap<ChatId, HashSet<String>>,
+    /// Names of sticker sets with all their stickers allowed.
+    #[serde(default)]
+    pub allowed_sticker_sets: HashSet<String>,
+    /// Same as `allowed_sticker_sets` but only in the chat.
+    #[serde(default)]
+    pub chat_sticker_sets: HashMap<ChatId, HashSet<String>>,
+    /// New members yet to answer the join challenge, with the unix
+    /// timestamp they have to answer by.
+    #[serde(default)]
+    pub challenges: HashMap<ChatId, HashMap<UserId, u64>>,
+    /// Recently reported messages of each chat, oldest first.
+    #[serde(default)]
+    pub reports: HashMap<ChatId, VecDeque<ReportedMessage>>,
+    /// Accepted ah of each chat so far, see `AhStats`.
+    #[serde(default)]
+    pub ah_stats: HashMap<ChatId, AhStats>,
+    /// Deletions and bans not yet confirmed by Telegram, with the unix
+    /// timestamp they were decided on, oldest first.
+    #[serde(default)]
+    pub pending: VecDeque<(u64, PendingAction)>,
+}
+
+impl Default for Data {
+    fn default() -> Self {
+        Self {
+            version: DATA_VERSION,
+            chats: Default::default(),
+            users: Default::default(),
+            profiles: Default::default(),
+            ah_history: Default::default(),
+            allowed_stickers: Default::default(),
+            chat_stickers: Default::default(),
+            allowed_sticker_sets: Default::default(),
+            chat_sticker_sets: Default::default(),
+            challenges: Default::default(),
+            reports: Default::default(),
+            ah_stats: Default::default(),
+            pending: Default::default(),
+        }
+    }
+}
+
+impl Data {
+    /// Read the state at `path` as `Storage::open()` does: from the database
+    /// next to it if there is one, else from the JSON, migrated either way.
+    /// Nothing is written, e.g. for tools working on a copy of the state.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let db_path = path.with_extension("redb");
+        let data = if db_path.exists() {
+            read_db(&Database::open(&db_path).map_err(db_err)?)?
+        } else {
+            read_json(path)?
+        };
+        migrate(data)
+    }
+
+    /// Write all of it to the database next to `path`, where the bot reads
+    /// the state from then on. Fails while the bot has it open.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let db = Database::create(path.as_ref().with_extension("redb")).map_err(db_err)?;
+        let users = self.users.keys().chain(self.profiles.keys()).copied();
+        write_db(&db, self, &users.collect())
+    }
+}
+
+/// `Data` but users and profiles, to be written to `REST`.
+#[derive(Serialize)]
+struct DataRest<'a> {
+    version: u32,
+    chats: &'a HashMap<ChatId, (UserId, u32)>,
+    ah_history: &'a HashMap<ChatId, VecDeque<(UserId, u32)>>,
+    allowed_stickers: &'a HashSet<String>,
+    chat_stickers: &'a HashMap<ChatId, HashSet<String>>,
+    allowed_sticker_sets: &'a HashSet<String>,
+    chat_sticker_sets: &'a HashMap<ChatId, HashSet<String>>,
+    challenges: &'a HashMap<ChatId, HashMap<UserId, u64>>,
+    reports: &'a HashMap<ChatId, VecDeque<ReportedMessage>>,
+    ah_stats: &'a HashMap<ChatId, AhStats>,
+    pending: &'a VecDeque<(u64, PendingAction)>,
+}
+
+impl<'a> From<&'a Data> for DataRest<'a> {
+    fn from(data: &'a Data) -> Self {
+        Self {
+            version: data.version,
+            chats: &data.chats,
+            ah_history: &data.ah_history,
+            allowed_stickers: &data.allowed_stickers,
+            chat_stickers: &data.chat_stickers,
+            allowed_sticker_sets: &data.allowed_sticker_sets,
+            chat_sticker_sets: &data.chat_sticker_sets,
+            challenges: &data.challenges,
+            reports: &data.reports,
+            ah_stats: &data.ah_stats,
+            pending: &data.pending,
+        }
+    }
+}
+
+/// Seconds since the Unix epoch, 0 if the clock is set before it.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn version_1() -> u32 {
+    1
+}
+
+/// Bring `data` read from disk up to `DATA_VERSION`, one version at a time.
+fn migrate(mut data: Data) -> Result<Data> {
+    if data.version > DATA_VERSION {
+        return Err(StorageError::UnsupportedVersion(data.version));
+    }
+    while data.version < DATA_VERSION {
+        match data.version {
+            // v2 added `profiles`, already filled in by serde default
+            ..=1 => (),
+            // v3 added `SpamState::Trusted`, nothing to convert
+            2 => (),
+            // v4 moved ah counts of each user to their profile
+            3 => {
+                for (chat_id, stats) in &mut data.ah_stats {
+                    for (user_id, noa) in stats.users.drain() {
+                        let profile = data.profiles.entry(user_id).or_default();
+                        profile.ah_count.insert(*chat_id, noa);
+                    }
+                }
+            }
+            version => unreachable!("no migration from v{}", version),
+        }
+        data.version += 1;
+    }
+    Ok(data)
+}
+
+/// Extra facts about a user, other than their spam state.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct UserProfile {
+    /// Full name seen on their last message or join.
+    pub last_name_seen: Option<String>,
+    /// Number of their messages deleted since the last accepted one.
+    pub consecutive_deletions: u32,
+    /// Number of their valid ah accepted so far.
+    pub valid_ah: u32,
+    /// Unix time their spam state last changed, 0 if unknown.
+    pub state_updated_ts: u64,
+    /// Number of times they got muted, see `PolicyConfig::mute_threshold`.
+    pub mutes: u32,
+    /// Their bio has been screened, see `PolicyConfig::screen_bio`.
+    pub bio_screened: bool,
+    /// Number of 啊 accepted from them in each chat, see `AhStats`. Kept
+    /// here so that each ah writes their row only, not all users'.
+    pub ah_count: HashMap<ChatId, u64>,
+}
+
+/// Accepted ah of a chat, as shown by `/ahstats`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct AhStats {
+    /// Number of 啊 in all accepted messages, allowed stickers count as one.
+    /// That of each user is `UserProfile::ah_count`.
+    pub total: u64,
+    /// Accepted messages in a row, each with one more 啊 than the last.
+    pub streak: u32,
+    pub longest_streak: u32,
+    /// Counts of each user up to v3, moved out by `migrate()`.
+    #[serde(skip_serializing)]
+    users: HashMap<UserId, u64>,
+}
+
+impl AhStats {
+    fn record(&mut self, noa: u32, last_noa: Option<u32>) {
+        self.total = self.total.saturating_add(noa as u64);
+        self.streak = match last_noa {
+            Some(last_noa) if noa == last_noa + 1 => self.streak.saturating_add(1),
+            _ => 1,
+        };
+        self.longest_streak = self.longest_streak.max(self.streak);
+    }
+}
+
+/// A message reported as spam with `/report`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReportedMessage {
+    pub message_id: MessageId,
+    /// Sender of the reported message.
+    pub user_id: UserId,
+    pub reporters: HashSet<UserId>,
+}
+
+/// A deletion or ban that may not have reached Telegram, replayed on start
+/// in case the bot stopped before it was done.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PendingAction {
+    Delete(ChatId, MessageId),
+    Ban {
+        chat_id: ChatId,
+        user_id: UserId,
+        revoke_messages: bool,
+        reason: String,
+    },
 }
 
 #[derive(Debug)]
+pub enum StorageError {
+    /// Failed to read or write the state file.
+    Io(io::Error),
+    /// Failed to read or write the state database.
+    Database(Box<redb::Error>),
+    /// State file exists but cannot be parsed.
+    Deserialize(sonic_rs::Error),
+    /// Failed to encode the state.
+    Serialize(sonic_rs::Error),
+    /// State file written by a newer version of the bot.
+    UnsupportedVersion(u32),
+    /// The ah count got rejected by the group policy.
+    AhFlood { reason: AhFloodReason },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AhFloodReason {
+    /// Same user posted twice in a row.
+    SingleUser,
+    /// More ah than the last post plus one.
+    TooManyAh { noa: u32, last_noa: u32 },
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "state file I/O error: {}", err),
+            Self::Database(err) => write!(f, "state database error: {}", err),
+            Self::Deserialize(err) => write!(f, "corrupt state file: {}", err),
+            Self::Serialize(err) => write!(f, "failed to serialize state: {}", err),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "state file version {} is too new", version)
+            }
+            Self::AhFlood { reason } => reason.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for AhFloodReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SingleUser => write!(f, "No single-user flooding"),
+            Self::TooManyAh { noa, last_noa } => write!(
+                f,
+                "No too many ah in a single message ({} after {})",
+                noa, last_noa
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::Database(err) => Some(err),
+            Self::Deserialize(err) | Self::Serialize(err) => Some(err),
+            Self::UnsupportedVersion(_) | Self::AhFlood { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+fn db_err(err: impl Into<redb::Error>) -> StorageError {
+    StorageError::Database(Box::new(err.into()))
+}
+
+impl From<AhFloodReason> for StorageError {
+    fn from(reason: AhFloodReason) -> Self {
+        Self::AhFlood { reason }
+    }
+}
+
+pub type Result<T, E = StorageError> = std::result::Result<T, E>;
+
+/// Reported messages kept for each chat, older ones are forgotten.
+const REPORTS_KEPT: usize = 50;
+
+/// Pending actions kept at most, older ones are given up.
+const PENDING_KEPT: usize = 1000;
+
+/// Default length of `Data::ah_history` kept for each chat.
+pub(crate) const AH_HISTORY_LEN: usize = 20;
+
 pub(crate) struct Storage {
-    file: File,
+    db: Database,
     data: Data,
-    buf: Vec<u8>,
+    /// Users with their state or profile changed since the last save.
+    dirty: HashSet<UserId>,
+    ah_history_len: usize,
+    max_users: Option<usize>,
+    score_half_life: Option<Duration>,
+    spam_threshold: u8,
+    /// Never write the file, see `set_read_only()`.
+    read_only: bool,
+}
+
+impl fmt::Debug for Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Storage")
+            .field("users", &self.data.users.len())
+            .field("chats", &self.data.chats.len())
+            .field("dirty", &self.dirty.len())
+            .finish_non_exhaustive()
+    }
 }
 
 impl Storage {
-    pub(crate) async fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
-        let mut file = File::options()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(false)
-            .open(path)
-            .await?;
-
-        let mut buf = Vec::new();
-        file.read_to_end(&mut buf).await?;
-        let data: Data = if buf.is_empty() {
-            Default::default()
-        } else {
-            sonic_rs::from_slice(&buf)?
-        };
+    /// Open the database next to `path`, with the extension `.redb`, or
+    /// create it. A JSON state file at `path` from before the database is
+    /// imported if there is no database yet, then left as is.
+    pub(crate) async fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let json_path = path.as_ref().to_path_buf();
+        let db_path = json_path.with_extension("redb");
+        // Loading a large state may take a while, keep it off the runtime
+        let (db, data) = tokio::task::spawn_blocking(move || -> Result<_> {
+            if db_path.exists() || !json_path.exists() {
+                let db = Database::create(&db_path).map_err(db_err)?;
+                let data = read_db(&db)?;
+                let version = data.version;
+                let data = migrate(data)?;
+                if version < DATA_VERSION {
+                    // Rows changed by the migration
+                    write_db(&db, &data, &data.profiles.keys().copied().collect())?;
+                }
+                return Ok((db, data));
+            }
+            let data = migrate(read_json(&json_path)?)?;
+            let db = Database::create(&db_path).map_err(db_err)?;
+            let users = data.users.keys().chain(data.profiles.keys()).copied();
+            write_db(&db, &data, &users.collect())?;
+            info!(
+                "Imported {} into {}, the former is no longer used",
+                json_path.display(),
+                db_path.display()
+            );
+            Ok((db, data))
+        })
+        .await
+        .expect("state loading panicked")?;
+        info!(
+            "State loaded: {} users, {} chats",
+            data.users.len(),
+            data.chats.len()
+        );
 
-        Ok(Self { file, data, buf })
+        Ok(Self {
+            db,
+            data,
+            dirty: Default::default(),
+            ah_history_len: AH_HISTORY_LEN,
+            max_users: None,
+            score_half_life: None,
+            spam_threshold: SPAM_THREHOLD,
+            read_only: false,
+        })
     }
 
-    pub(crate) async fn save(&mut self) -> anyhow::Result<()> {
-        self.buf.clear();
-        sonic_rs::to_writer(&mut self.buf, &self.data)?;
-        self.file.seek(SeekFrom::Start(0)).await?;
-        self.file.write_all(&self.buf).await?;
-        self.file.set_len(self.buf.len().try_into()?).await?;
+    pub(crate) async fn save(&mut self) -> Result<()> {
+        self.evict_users();
+        if self.read_only {
+            return Ok(());
+        }
+        // Only users changed are written. Those stay marked should it
+        // fail, so the next save writes them again.
+        let dirty = &self.dirty;
+        match Handle::current().runtime_flavor() {
+            RuntimeFlavor::MultiThread => {
+                tokio::task::block_in_place(|| write_db(&self.db, &self.data, dirty))?
+            }
+            _ => write_db(&self.db, &self.data, dirty)?,
+        }
+        self.dirty.clear();
         Ok(())
     }
 
     pub(crate) fn update_user(&mut self, user_id: &UserId, new_state: SpamState) -> SpamState {
-        *self
-            .data
-            .users
-            .entry(*user_id)
-            .and_modify(|e| *e += new_state)
-            .or_insert(new_state)
+        self.update_user_with(user_id, new_state, self.spam_threshold)
+    }
+
+    /// Like `update_user()`, with a threshold other than the default one,
+    /// e.g. within a `StrictWindow`.
+    pub(crate) fn update_user_with(
+        &mut self,
+        user_id: &UserId,
+        new_state: SpamState,
+        threshold: u8,
+    ) -> SpamState {
+        let now = unix_now();
+        let state = self
+            .get_user_at(user_id, now)
+            .add_with(new_state, threshold);
+        self.dirty.insert(*user_id);
+        self.data.users.insert(*user_id, state);
+        // Users seen on a message always have a profile, see `update_name()`
+        if let Some(profile) = self.data.profiles.get_mut(user_id) {
+            profile.state_updated_ts = now;
+        }
+        state
+    }
+
+    /// Raise the score of the user to at least `score`, rather than adding
+    /// to it like `update_user()`. Users not `MaybeSpam` are kept as is.
+    pub(crate) fn raise_score(&mut self, user_id: &UserId, score: u8) -> SpamState {
+        let now = unix_now();
+        let state = match self.get_user_at(user_id, now) {
+            SpamState::MaybeSpam(current) if current < score => SpamState::MaybeSpam(score),
+            state => return state,
+        };
+        self.dirty.insert(*user_id);
+        self.data.users.insert(*user_id, state);
+        if let Some(profile) = self.data.profiles.get_mut(user_id) {
+            profile.state_updated_ts = now;
+        }
+        state
+    }
+
+    /// Mark the user `Authentic` whatever they did, and forget their
+    /// deletions, e.g. after a mistaken ban.
+    pub(crate) fn pardon_user(&mut self, user_id: &UserId) {
+        self.dirty.insert(*user_id);
+        self.data.users.insert(*user_id, SpamState::Authentic);
+        self.reset_deletions(user_id);
+    }
+
+    /// Take back the trust of an `Authentic` user, so they get scored again
+    /// and have to earn it back. Return false if they were not trusted.
+    pub(crate) fn demote_user(&mut self, user_id: &UserId) -> bool {
+        match self.data.users.get_mut(user_id) {
+            Some(state @ SpamState::Authentic) => {
+                self.dirty.insert(*user_id);
+                *state = SpamState::MaybeSpam(0);
+                if let Some(profile) = self.data.profiles.get_mut(user_id) {
+                    profile.valid_ah = 0;
+                }
+                true
+            }
+            _ => false,
+        }
     }
 
     pub(crate) fn get_user(&self, user_id: &UserId) -> SpamState {
-        self.data.users.get(user_id).cloned().unwrap_or_default()
+        self.get_user_at(user_id, unix_now())
+    }
+
+    /// With `MaybeSpam` and `Trusted` scores decayed, see
+    /// `set_score_half_life()`.
+    fn get_user_at(&self, user_id: &UserId, now: u64) -> SpamState {
+        let state = self.data.users.get(user_id).cloned().unwrap_or_default();
+        let score = match state {
+            SpamState::MaybeSpam(score) | SpamState::Trusted { score, .. } if score > 0 => score,
+            _ => return state,
+        };
+        let Some(half_life) = self.score_half_life else {
+            return state;
+        };
+        let updated_ts = match self.data.profiles.get(user_id) {
+            Some(profile) if profile.state_updated_ts > 0 => profile.state_updated_ts,
+            _ => return state, // Unknown age
+        };
+        let elapsed = now.saturating_sub(updated_ts) as f64;
+        let factor = 0.5f64.powf(elapsed / half_life.as_secs_f64().max(1.0));
+        let score = (score as f64 * factor) as u8;
+        match state {
+            SpamState::Trusted { since_ts, .. } => SpamState::Trusted { since_ts, score },
+            _ => SpamState::MaybeSpam(score),
+        }
+    }
+
+    pub(crate) fn update_name(&mut self, user_id: &UserId, name: &str) {
+        let profile = self.data.profiles.entry(*user_id).or_default();
+        if profile.last_name_seen.as_deref() != Some(name) {
+            profile.last_name_seen = Some(name.to_string());
+            self.dirty.insert(*user_id);
+        }
+    }
+
+    /// Count a deleted message, return the number of deletions in a row.
+    pub(crate) fn record_deletion(&mut self, user_id: &UserId) -> u32 {
+        let profile = self.profile_mut(user_id);
+        profile.consecutive_deletions = profile.consecutive_deletions.saturating_add(1);
+        profile.consecutive_deletions
+    }
+
+    pub(crate) fn reset_deletions(&mut self, user_id: &UserId) {
+        if let Some(profile) = self.data.profiles.get_mut(user_id) {
+            if profile.consecutive_deletions > 0 {
+                profile.consecutive_deletions = 0;
+                self.dirty.insert(*user_id);
+            }
+        }
+    }
+
+    /// Count a mute, return the number of mutes so far.
+    pub(crate) fn record_mute(&mut self, user_id: &UserId) -> u32 {
+        let profile = self.profile_mut(user_id);
+        profile.mutes = profile.mutes.saturating_add(1);
+        profile.mutes
+    }
+
+    /// Mark the bio of the user as screened, return false if it already was.
+    pub(crate) fn record_bio_screened(&mut self, user_id: &UserId) -> bool {
+        if self.get_profile(user_id).is_some_and(|p| p.bio_screened) {
+            return false;
+        }
+        self.profile_mut(user_id).bio_screened = true;
+        true
+    }
+
+    /// Count an accepted ah, return the number of valid ah so far.
+    pub(crate) fn record_valid_ah(&mut self, user_id: &UserId) -> u32 {
+        let profile = self.profile_mut(user_id);
+        profile.valid_ah = profile.valid_ah.saturating_add(1);
+        profile.valid_ah
+    }
+
+    /// Profile of the user to change, created if none, to be saved.
+    fn profile_mut(&mut self, user_id: &UserId) -> &mut UserProfile {
+        self.dirty.insert(*user_id);
+        self.data.profiles.entry(*user_id).or_default()
+    }
+
+    pub(crate) fn get_profile(&self, user_id: &UserId) -> Option<&UserProfile> {
+        self.data.profiles.get(user_id)
+    }
+
+    pub(crate) fn get_ah_stats(&self, chat_id: &ChatId) -> Option<&AhStats> {
+        self.data.ah_stats.get(chat_id)
+    }
+
+    /// Number of 啊 accepted from each user in the chat, in no order.
+    pub(crate) fn ah_counts(&self, chat_id: &ChatId) -> impl Iterator<Item = (UserId, u64)> + '_ {
+        let chat_id = *chat_id;
+        self.data
+            .profiles
+            .iter()
+            .filter_map(move |(user_id, profile)| {
+                Some((*user_id, *profile.ah_count.get(&chat_id)?))
+            })
     }
 
     pub(crate) fn get_chat(&self, chat_id: &ChatId) -> Option<(UserId, u32)> {
         self.data.chats.get(chat_id).cloned()
     }
 
+    pub(crate) fn is_sticker_allowed(&self, chat_id: &ChatId, file_unique_id: &str) -> bool {
+        self.data.allowed_stickers.contains(file_unique_id)
+            || self
+                .data
+                .chat_stickers
+                .get(chat_id)
+                .is_some_and(|stickers| stickers.contains(file_unique_id))
+    }
+
+    /// Allow in the chat, or all chats if `None`. Return false if already allowed.
+    pub(crate) fn add_allowed_sticker(
+        &mut self,
+        chat_id: Option<&ChatId>,
+        file_unique_id: &str,
+    ) -> bool {
+        let stickers = match chat_id {
+            Some(chat_id) => self.data.chat_stickers.entry(*chat_id).or_default(),
+            None => &mut self.data.allowed_stickers,
+        };
+        stickers.insert(file_unique_id.to_string())
+    }
+
+    pub(crate) fn is_sticker_set_allowed(&self, chat_id: &ChatId, set_name: &str) -> bool {
+        self.data.allowed_sticker_sets.contains(set_name)
+            || self
+                .data
+                .chat_sticker_sets
+                .get(chat_id)
+                .is_some_and(|sets| sets.contains(set_name))
+    }
+
+    /// Like `add_allowed_sticker()`, for the whole set.
+    pub(crate) fn add_allowed_sticker_set(
+        &mut self,
+        chat_id: Option<&ChatId>,
+        set_name: &str,
+    ) -> bool {
+        let sets = match chat_id {
+            Some(chat_id) => self.data.chat_sticker_sets.entry(*chat_id).or_default(),
+            None => &mut self.data.allowed_sticker_sets,
+        };
+        sets.insert(set_name.to_string())
+    }
+
+    pub(crate) fn add_challenge(&mut self, chat_id: &ChatId, user_id: &UserId, deadline: u64) {
+        let challenges = self.data.challenges.entry(*chat_id).or_default();
+        challenges.insert(*user_id, deadline);
+    }
+
+    /// Remove the challenge if answered in time, return false if there was
+    /// none or it has expired.
+    pub(crate) fn pass_challenge(&mut self, chat_id: &ChatId, user_id: &UserId, now: u64) -> bool {
+        let Some(challenges) = self.data.challenges.get_mut(chat_id) else {
+            return false;
+        };
+        match challenges.get(user_id) {
+            Some(&deadline) if now <= deadline => {
+                challenges.remove(user_id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Remove the challenge whether answered or not.
+    pub(crate) fn remove_challenge(&mut self, chat_id: &ChatId, user_id: &UserId) {
+        if let Some(challenges) = self.data.challenges.get_mut(chat_id) {
+            challenges.remove(user_id);
+            if challenges.is_empty() {
+                self.data.challenges.remove(chat_id);
+            }
+        }
+    }
+
+    /// Remove and return challenges not answered by `now`.
+    pub(crate) fn take_expired_challenges(&mut self, now: u64) -> Vec<(ChatId, UserId)> {
+        let mut expired = Vec::new();
+        for (chat_id, challenges) in self.data.challenges.iter_mut() {
+            challenges.retain(|user_id, deadline| {
+                if *deadline < now {
+                    expired.push((*chat_id, *user_id));
+                }
+                *deadline >= now
+            });
+        }
+        self.data
+            .challenges
+            .retain(|_, challenges| !challenges.is_empty());
+        expired
+    }
+
+    /// Record `reporter` reporting the message sent by `user_id`, return
+    /// the number of distinct reporters so far.
+    pub(crate) fn add_report(
+        &mut self,
+        chat_id: &ChatId,
+        message_id: MessageId,
+        user_id: UserId,
+        reporter: UserId,
+    ) -> usize {
+        let reports = self.data.reports.entry(*chat_id).or_default();
+        let index = match reports.iter().position(|r| r.message_id == message_id) {
+            Some(index) => index,
+            None => {
+                if reports.len() >= REPORTS_KEPT {
+                    reports.pop_front();
+                }
+                reports.push_back(ReportedMessage {
+                    message_id,
+                    user_id,
+                    reporters: Default::default(),
+                });
+                reports.len() - 1
+            }
+        };
+        let report = &mut reports[index];
+        report.reporters.insert(reporter);
+        report.reporters.len()
+    }
+
+    /// Forget reports on the message, e.g. once acted on.
+    pub(crate) fn remove_report(&mut self, chat_id: &ChatId, message_id: MessageId) {
+        if let Some(reports) = self.data.reports.get_mut(chat_id) {
+            reports.retain(|r| r.message_id != message_id);
+        }
+    }
+
+    /// Keep the action until `remove_pending()`, unless already kept.
+    pub(crate) fn add_pending(&mut self, ts: u64, action: PendingAction) {
+        if self.data.pending.iter().any(|(_, a)| *a == action) {
+            return;
+        }
+        if self.data.pending.len() >= PENDING_KEPT {
+            self.data.pending.pop_front();
+        }
+        self.data.pending.push_back((ts, action));
+    }
+
+    /// Forget pending actions matching `done`, return the number removed.
+    pub(crate) fn remove_pending(&mut self, done: impl Fn(&PendingAction) -> bool) -> usize {
+        let len = self.data.pending.len();
+        self.data.pending.retain(|(_, action)| !done(action));
+        len - self.data.pending.len()
+    }
+
+    /// Give up actions decided before `ts`, return the rest.
+    pub(crate) fn pending_since(&mut self, ts: u64) -> Vec<PendingAction> {
+        self.data.pending.retain(|(t, _)| *t >= ts);
+        self.data.pending.iter().map(|(_, a)| a.clone()).collect()
+    }
+
+    /// Number of accepted ah to keep for each chat.
+    pub(crate) fn set_ah_history_len(&mut self, len: usize) {
+        self.ah_history_len = len;
+    }
+
+    /// Halve `MaybeSpam` scores every `half_life` since they last changed,
+    /// so one borderline message long ago doesn't add up with a new one.
+    pub(crate) fn set_score_half_life(&mut self, half_life: Option<Duration>) {
+        self.score_half_life = half_life;
+    }
+
+    /// Score at which users turn into `Spam`, see
+    /// `ScoringConfig::spam_threshold`.
+    pub(crate) fn set_spam_threshold(&mut self, threshold: u8) {
+        self.spam_threshold = threshold;
+    }
+
+    /// Keep changes in memory only, e.g. on a dry run so that the real
+    /// state is left as it was.
+    pub(crate) fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Keep at most `max` users, see `evict_users()`.
+    pub(crate) fn set_max_users(&mut self, max: Option<usize>) {
+        self.max_users = max;
+    }
+
+    /// Forget `MaybeSpam` users, least recently scored first, once over
+    /// `max_users`. Other users are never evicted: bans and trust are worth
+    /// more than the memory. A tenth below the cap is freed at a time, so
+    /// that it's not done again on every save.
+    fn evict_users(&mut self) {
+        let Some(max) = self.max_users else { return };
+        if self.data.users.len() <= max {
+            return;
+        }
+        let excess = self.data.users.len() - (max - max / 10);
+        let mut candidates: Vec<_> = self
+            .data
+            .users
+            .iter()
+            .filter(|(_, state)| matches!(state, SpamState::MaybeSpam(_)))
+            .map(|(user_id, _)| {
+                let ts = self
+                    .data
+                    .profiles
+                    .get(user_id)
+                    .map_or(0, |p| p.state_updated_ts);
+                (ts, *user_id)
+            })
+            .collect();
+        if candidates.len() > excess {
+            // Only the oldest are needed, not all of them in order
+            candidates.select_nth_unstable(excess);
+            candidates.truncate(excess);
+        }
+        if candidates.is_empty() {
+            return;
+        }
+        let evicted: HashSet<_> = candidates.into_iter().map(|(_, user_id)| user_id).collect();
+        for user_id in &evicted {
+            self.data.users.remove(user_id);
+            self.data.profiles.remove(user_id);
+            self.dirty.insert(*user_id);
+        }
+        info!(
+            "Evicted {} users over the cap of {}, {} left",
+            evicted.len(),
+            max,
+            self.data.users.len()
+        );
+    }
+
+    /// Up to `limit` latest accepted ah of the chat, oldest first.
+    pub(crate) fn recent_ah(
+        &self,
+        chat_id: &ChatId,
+        limit: usize,
+    ) -> impl Iterator<Item = (UserId, u32)> + '_ {
+        let history = self.data.ah_history.get(chat_id);
+        let skip = history.map_or(0, |h| h.len().saturating_sub(limit));
+        history.into_iter().flatten().skip(skip).copied()
+    }
+
+    fn push_ah_history(&mut self, chat_id: &ChatId, ah: (UserId, u32)) {
+        let history = self.data.ah_history.entry(*chat_id).or_default();
+        history.push_back(ah);
+        while history.len() > self.ah_history_len {
+            history.pop_front();
+        }
+    }
+
     pub(crate) fn update_chat(
         &mut self,
         chat_id: &ChatId,
         (user_id, noa): (UserId, u32),
-    ) -> anyhow::Result<()> {
+    ) -> Result<()> {
         match self.data.chats.entry(*chat_id) {
             Entry::Occupied(mut e) => {
-                if e.get().0 == user_id {
-                    Err(anyhow!("No single-user flooding"))
-                } else if noa > 3 && noa > e.get().1 + 1 {
-                    Err(anyhow!("No too many ah in a single message"))
+                let (last_user, last_noa) = *e.get();
+                if last_user == user_id {
+                    Err(AhFloodReason::SingleUser.into())
+                } else if noa > 3 && noa > last_noa + 1 {
+                    Err(AhFloodReason::TooManyAh { noa, last_noa }.into())
                 } else {
                     e.insert((user_id, noa));
+                    self.record_ah(chat_id, (user_id, noa), Some(last_noa));
                     Ok(())
                 }
             }
             Entry::Vacant(e) => {
                 // For group w/o history, anyone & any noa is allowed
                 e.insert((user_id, noa));
+                self.record_ah(chat_id, (user_id, noa), None);
                 Ok(())
             }
         }
     }
+
+    /// Count an accepted ah toward the history and stats of the chat.
+    fn record_ah(&mut self, chat_id: &ChatId, (user_id, noa): (UserId, u32), last: Option<u32>) {
+        self.push_ah_history(chat_id, (user_id, noa));
+        let stats = self.data.ah_stats.entry(*chat_id).or_default();
+        stats.record(noa, last);
+        *self
+            .profile_mut(&user_id)
+            .ah_count
+            .entry(*chat_id)
+            .or_default() += noa as u64;
+    }
+}
+
+/// Read a JSON state file, as written before the database.
+fn read_json(path: &Path) -> Result<Data> {
+    let buf = fs::read(path)?;
+    if buf.is_empty() {
+        return Ok(Default::default());
+    }
+    sonic_rs::from_slice(&buf).map_err(StorageError::Deserialize)
+}
+
+fn read_db(db: &Database) -> Result<Data> {
+    let txn = db.begin_read().map_err(db_err)?;
+    let mut data: Data = match txn.open_table(REST) {
+        Ok(table) => match table.get(REST_KEY).map_err(db_err)? {
+            Some(rest) => sonic_rs::from_slice(rest.value()).map_err(StorageError::Deserialize)?,
+            None => Default::default(),
+        },
+        // Newly created
+        Err(TableError::TableDoesNotExist(_)) => return Ok(Default::default()),
+        Err(err) => return Err(db_err(err)),
+    };
+    for entry in txn
+        .open_table(USERS)
+        .map_err(db_err)?
+        .iter()
+        .map_err(db_err)?
+    {
+        let (user_id, state) = entry.map_err(db_err)?;
+        let state = sonic_rs::from_slice(state.value()).map_err(StorageError::Deserialize)?;
+        data.users.insert(UserId(user_id.value()), state);
+    }
+    for entry in txn
+        .open_table(PROFILES)
+        .map_err(db_err)?
+        .iter()
+        .map_err(db_err)?
+    {
+        let (user_id, profile) = entry.map_err(db_err)?;
+        let profile = sonic_rs::from_slice(profile.value()).map_err(StorageError::Deserialize)?;
+        data.profiles.insert(UserId(user_id.value()), profile);
+    }
+    Ok(data)
+}
+
+/// Write `users` as they are in `data`, removing those gone, and all the
+/// rest, in one transaction.
+fn write_db(db: &Database, data: &Data, users: &HashSet<UserId>) -> Result<()> {
+    let txn = db.begin_write().map_err(db_err)?;
+    {
+        let mut states = txn.open_table(USERS).map_err(db_err)?;
+        let mut profiles = txn.open_table(PROFILES).map_err(db_err)?;
+        for user_id in users {
+            match data.users.get(user_id) {
+                Some(state) => {
+                    let state = sonic_rs::to_vec(state).map_err(StorageError::Serialize)?;
+                    states.insert(user_id.0, state.as_slice())
+                }
+                None => states.remove(user_id.0),
+            }
+            .map_err(db_err)?;
+            match data.profiles.get(user_id) {
+                Some(profile) => {
+                    let profile = sonic_rs::to_vec(profile).map_err(StorageError::Serialize)?;
+                    profiles.insert(user_id.0, profile.as_slice())
+                }
+                None => profiles.remove(user_id.0),
+            }
+            .map_err(db_err)?;
+        }
+        let rest = sonic_rs::to_vec(&DataRest::from(data)).map_err(StorageError::Serialize)?;
+        let mut table = txn.open_table(REST).map_err(db_err)?;
+        table.insert(REST_KEY, rest.as_slice()).map_err(db_err)?;
+    }
+    txn.commit().map_err(db_err)
+}
+
+/// Pretend the spam state of the user was last updated `age` earlier.
+#[cfg(test)]
+fn age_user(storage: &mut Storage, user_id: &UserId, age: Duration) {
+    let profile = storage.data.profiles.get_mut(user_id).unwrap();
+    profile.state_updated_ts -= age.as_secs();
 }
 
 #[tokio::test]
 async fn test_storage() {
-    use crate::antispam::SPAM_THREHOLD;
     let temp_dir = tempfile::tempdir().unwrap();
     let path = temp_dir.path().join("test.json");
     let mut storage = Storage::open(&path).await.unwrap();
@@ -112,8 +984,22 @@ async fn test_storage() {
     storage.update_chat(&ChatId(1), (UserId(2), 1)).unwrap();
     storage.update_chat(&ChatId(1), (UserId(1), 3)).unwrap();
     storage.update_chat(&ChatId(1), (UserId(2), 3)).unwrap();
-    assert!(storage.update_chat(&ChatId(1), (UserId(1), 5)).is_err());
-    assert!(storage.update_chat(&ChatId(1), (UserId(2), 4)).is_err());
+    assert!(matches!(
+        storage.update_chat(&ChatId(1), (UserId(1), 5)),
+        Err(StorageError::AhFlood {
+            reason: AhFloodReason::TooManyAh {
+                noa: 5,
+                last_noa: 3
+            }
+        })
+    ));
+    assert!(matches!(
+        storage.update_chat(&ChatId(1), (UserId(2), 4)),
+        Err(StorageError::AhFlood {
+            reason: AhFloodReason::SingleUser
+        })
+    ));
+    assert_eq!(storage.get_chat(&ChatId(1)), Some((UserId(2), 3)));
 
     // Spam state ops
     assert_eq!(
@@ -141,8 +1027,18 @@ async fn test_storage() {
         SpamState::Spam
     );
     storage.update_user(&UserId(3), SpamState::MaybeSpam(20));
+    storage.update_user(&UserId(5), SpamState::Authentic);
+    assert!(storage.demote_user(&UserId(5)));
+    assert!(!storage.demote_user(&UserId(5)));
+    assert!(!storage.demote_user(&UserId(2)));
+    assert_eq!(
+        storage.update_user(&UserId(5), SpamState::MaybeSpam(SPAM_THREHOLD)),
+        SpamState::Spam
+    );
+    storage.update_name(&UserId(1), "Alice");
     storage.save().await.unwrap();
     storage.save().await.unwrap(); // redundancy
+    drop(storage);
 
     let storage = Storage::open(&path).await.unwrap();
     assert_eq!(storage.get_user(&UserId(1)), SpamState::Authentic);
@@ -153,4 +1049,408 @@ async fn test_storage() {
     assert!(!storage.get_user(&UserId(1)).is_spam());
     assert!(storage.get_user(&UserId(2)).is_spam());
     assert!(!storage.get_user(&UserId(3)).is_spam());
+    assert_eq!(
+        Some("Alice"),
+        storage
+            .get_profile(&UserId(1))
+            .and_then(|p| p.last_name_seen.as_deref())
+    );
+    assert_eq!(None, storage.get_profile(&UserId(4)));
+
+    assert_eq!(storage.data.version, DATA_VERSION);
+    assert!(temp_dir.path().join("test.redb").exists());
+}
+
+#[tokio::test]
+async fn test_import_json() {
+    let temp_dir = tempfile::tempdir().unwrap();
+
+    // v1: written before versioning and profiles
+    let path = temp_dir.path().join("v1.json");
+    tokio::fs::write(&path, br#"{"chats":{"1":[2,3]},"users":{"1":"Authentic"}}"#)
+        .await
+        .unwrap();
+    let mut storage = Storage::open(&path).await.unwrap();
+    assert_eq!(storage.data.version, DATA_VERSION);
+    assert_eq!(storage.get_user(&UserId(1)), SpamState::Authentic);
+    assert_eq!(None, storage.get_profile(&UserId(1)));
+    storage.update_user(&UserId(2), SpamState::Spam);
+    storage.save().await.unwrap();
+    drop(storage);
+    // Left as is, the database is used from now on
+    tokio::fs::write(&path, b"{not json").await.unwrap();
+    let storage = Storage::open(&path).await.unwrap();
+    assert_eq!(storage.data.version, DATA_VERSION);
+    assert_eq!(storage.get_user(&UserId(1)), SpamState::Authentic);
+    assert_eq!(storage.get_user(&UserId(2)), SpamState::Spam);
+    assert_eq!(Some((UserId(2), 3)), storage.get_chat(&ChatId(1)));
+    drop(storage);
+    // Loaded the same way, from the database
+    let mut data = Data::load(&path).unwrap();
+    assert_eq!(Some(&SpamState::Spam), data.users.get(&UserId(2)));
+    // And saved back for the bot
+    data.users.insert(UserId(3), SpamState::Authentic);
+    data.save(&path).unwrap();
+    let storage = Storage::open(&path).await.unwrap();
+    assert_eq!(storage.get_user(&UserId(3)), SpamState::Authentic);
+    assert_eq!(storage.get_user(&UserId(2)), SpamState::Spam);
+    drop(storage);
+
+    // v3: ah counts of each user kept in the stats
+    let path = temp_dir.path().join("v3.json");
+    let v3 = br#"{"version":3,"chats":{},"users":{},
+        "ah_stats":{"1":{"total":5,"users":{"7":5},"streak":0,"longest_streak":1}}}"#;
+    tokio::fs::write(&path, v3).await.unwrap();
+    let storage = Storage::open(&path).await.unwrap();
+    assert_eq!(5, storage.get_ah_stats(&ChatId(1)).unwrap().total);
+    assert_eq!(
+        vec![(UserId(7), 5)],
+        storage.ah_counts(&ChatId(1)).collect::<Vec<_>>()
+    );
+    drop(storage);
+    let data = Data::load(&path).unwrap();
+    assert_eq!(5, data.profiles[&UserId(7)].ah_count[&ChatId(1)]);
+
+    // From the future
+    let path = temp_dir.path().join("future.json");
+    tokio::fs::write(&path, br#"{"version":999,"chats":{},"users":{}}"#)
+        .await
+        .unwrap();
+    assert!(matches!(
+        Storage::open(&path).await,
+        Err(StorageError::UnsupportedVersion(999))
+    ));
+    assert!(matches!(
+        Data::load(&path),
+        Err(StorageError::UnsupportedVersion(999))
+    ));
+
+    // Corrupt file
+    let path = temp_dir.path().join("corrupt.json");
+    tokio::fs::write(&path, b"{not json").await.unwrap();
+    assert!(matches!(
+        Storage::open(&path).await,
+        Err(StorageError::Deserialize(_))
+    ));
+    assert!(!temp_dir.path().join("corrupt.redb").exists());
+    assert!(matches!(
+        Storage::open(temp_dir.path()).await,
+        Err(StorageError::Io(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_ah_history() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut storage = Storage::open(temp_dir.path().join("test.json"))
+        .await
+        .unwrap();
+    storage.set_ah_history_len(3);
+    assert_eq!(0, storage.recent_ah(&ChatId(1), 10).count());
+    for (uid, noa) in [(1, 1), (2, 2), (3, 3), (4, 4)] {
+        storage.update_chat(&ChatId(1), (UserId(uid), noa)).unwrap();
+    }
+    // Rejected ones are not recorded
+    storage.update_chat(&ChatId(1), (UserId(4), 1)).unwrap_err();
+    storage.update_chat(&ChatId(1), (UserId(1), 9)).unwrap_err();
+    storage.update_chat(&ChatId(2), (UserId(1), 1)).unwrap();
+
+    let recent: Vec<_> = storage.recent_ah(&ChatId(1), 10).collect();
+    assert_eq!(vec![(UserId(2), 2), (UserId(3), 3), (UserId(4), 4)], recent);
+    let recent: Vec<_> = storage.recent_ah(&ChatId(1), 2).collect();
+    assert_eq!(vec![(UserId(3), 3), (UserId(4), 4)], recent);
+    assert_eq!(1, storage.recent_ah(&ChatId(2), 10).count());
+}
+
+#[tokio::test]
+async fn test_open_large_state() {
+    const USERS: u64 = 200_000;
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("large.json");
+    let mut data = Data::default();
+    for id in 0..USERS {
+        let state = match id % 3 {
+            0 => SpamState::Authentic,
+            1 => SpamState::MaybeSpam((id % 100) as u8),
+            _ => SpamState::Spam,
+        };
+        data.users.insert(UserId(id), state);
+    }
+    tokio::fs::write(&path, sonic_rs::to_vec(&data).unwrap())
+        .await
+        .unwrap();
+
+    // Imported once, then from the database
+    for _ in 0..2 {
+        let start = std::time::Instant::now();
+        let storage = Storage::open(&path).await.unwrap();
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        assert_eq!(USERS as usize, storage.data.users.len());
+        assert_eq!(SpamState::MaybeSpam(1), storage.get_user(&UserId(1)));
+    }
+}
+
+#[tokio::test]
+async fn test_save_large_state() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("large.json");
+    // Whitespace around is fine to import
+    let mut padded = br#"{"chats":{},"users":{}}"#.to_vec();
+    padded.resize(16 << 20, b' ');
+    tokio::fs::write(&path, padded).await.unwrap();
+    let mut storage = Storage::open(&path).await.unwrap();
+    for id in 0..200_000 {
+        storage.update_user(&UserId(id), SpamState::MaybeSpam((id % 100) as u8));
+        storage
+            .update_chat(&ChatId(-(id as i64)), (UserId(id), 1))
+            .unwrap();
+    }
+    storage.update_name(&UserId(1), "Alice");
+    storage.save().await.unwrap();
+    assert_eq!(200_000, storage.data.users.len());
+    let saved = storage.data.clone();
+    drop(storage);
+
+    let loaded = Storage::open(&path).await.unwrap();
+    assert_eq!(saved.users, loaded.data.users);
+    assert_eq!(saved.chats, loaded.data.chats);
+    assert_eq!(saved.profiles, loaded.data.profiles);
+    assert_eq!(saved.version, loaded.data.version);
+}
+
+#[tokio::test]
+async fn test_allowed_stickers() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("test.json");
+    let mut storage = Storage::open(&path).await.unwrap();
+    assert!(!storage.is_sticker_allowed(&ChatId(1), "AgADsticker"));
+    assert!(storage.add_allowed_sticker(None, "AgADsticker"));
+    assert!(!storage.add_allowed_sticker(None, "AgADsticker"));
+    assert!(storage.add_allowed_sticker(Some(&ChatId(1)), "AgADchat"));
+    storage.save().await.unwrap();
+    drop(storage);
+
+    let storage = Storage::open(&path).await.unwrap();
+    assert!(storage.is_sticker_allowed(&ChatId(2), "AgADsticker"));
+    assert!(storage.is_sticker_allowed(&ChatId(1), "AgADchat"));
+    assert!(!storage.is_sticker_allowed(&ChatId(2), "AgADchat"));
+    assert!(!storage.is_sticker_allowed(&ChatId(1), "AgADother"));
+}
+
+#[tokio::test]
+async fn test_allowed_sticker_sets() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("test.json");
+    let mut storage = Storage::open(&path).await.unwrap();
+    assert!(!storage.is_sticker_set_allowed(&ChatId(1), "ahahah"));
+    assert!(storage.add_allowed_sticker_set(None, "ahahah"));
+    assert!(!storage.add_allowed_sticker_set(None, "ahahah"));
+    assert!(storage.add_allowed_sticker_set(Some(&ChatId(1)), "ahchat"));
+    storage.save().await.unwrap();
+    drop(storage);
+
+    let storage = Storage::open(&path).await.unwrap();
+    assert!(storage.is_sticker_set_allowed(&ChatId(2), "ahahah"));
+    assert!(storage.is_sticker_set_allowed(&ChatId(1), "ahchat"));
+    assert!(!storage.is_sticker_set_allowed(&ChatId(2), "ahchat"));
+    // File ids and set names are apart
+    assert!(!storage.is_sticker_allowed(&ChatId(2), "ahahah"));
+}
+
+#[tokio::test]
+async fn test_ah_stats() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("test.json");
+    let mut storage = Storage::open(&path).await.unwrap();
+    let chat = ChatId(1);
+    for (uid, noa) in [(1, 1), (2, 2), (1, 3), (2, 1), (1, 2)] {
+        storage.update_chat(&chat, (UserId(uid), noa)).unwrap();
+    }
+    // Rejected ones are not counted
+    assert!(storage.update_chat(&chat, (UserId(1), 3)).is_err());
+    let stats = storage.get_ah_stats(&chat).unwrap();
+    assert_eq!(9, stats.total);
+    let mut counts: Vec<_> = storage.ah_counts(&chat).collect();
+    counts.sort();
+    assert_eq!(vec![(UserId(1), 6), (UserId(2), 3)], counts);
+    assert_eq!(2, stats.streak);
+    assert_eq!(3, stats.longest_streak);
+    assert!(storage.get_ah_stats(&ChatId(2)).is_none());
+}
+
+#[tokio::test]
+async fn test_pending() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("test.json");
+    let mut storage = Storage::open(&path).await.unwrap();
+    let delete = PendingAction::Delete(ChatId(1), MessageId(1));
+    let ban = PendingAction::Ban {
+        chat_id: ChatId(1),
+        user_id: UserId(1),
+        revoke_messages: true,
+        reason: "spam".into(),
+    };
+    storage.add_pending(100, delete.clone());
+    storage.add_pending(100, delete.clone());
+    storage.add_pending(200, ban.clone());
+    storage.save().await.unwrap();
+    drop(storage);
+
+    let mut storage = Storage::open(&path).await.unwrap();
+    assert_eq!(vec![delete.clone(), ban.clone()], storage.pending_since(0));
+    // Old ones are given up
+    assert_eq!(vec![ban.clone()], storage.pending_since(150));
+    storage.add_pending(300, delete.clone());
+    assert_eq!(1, storage.remove_pending(|a| *a == delete));
+    assert_eq!(0, storage.remove_pending(|a| *a == delete));
+    assert_eq!(vec![ban], storage.pending_since(0));
+}
+
+#[tokio::test]
+async fn test_challenges() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("test.json");
+    let mut storage = Storage::open(&path).await.unwrap();
+    storage.add_challenge(&ChatId(1), &UserId(1), 100);
+    storage.add_challenge(&ChatId(1), &UserId(2), 100);
+    storage.add_challenge(&ChatId(2), &UserId(1), 200);
+    storage.save().await.unwrap();
+    drop(storage);
+
+    let mut storage = Storage::open(&path).await.unwrap();
+    assert!(storage.pass_challenge(&ChatId(1), &UserId(1), 100));
+    assert!(!storage.pass_challenge(&ChatId(1), &UserId(1), 100));
+    assert!(!storage.pass_challenge(&ChatId(1), &UserId(3), 100));
+    assert!(storage.take_expired_challenges(100).is_empty());
+    assert_eq!(
+        vec![(ChatId(1), UserId(2))],
+        storage.take_expired_challenges(150)
+    );
+    assert!(!storage.pass_challenge(&ChatId(2), &UserId(1), 201));
+    assert_eq!(
+        vec![(ChatId(2), UserId(1))],
+        storage.take_expired_challenges(201)
+    );
+    assert!(storage.data.challenges.is_empty());
+}
+
+#[tokio::test]
+async fn test_reports() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut storage = Storage::open(temp_dir.path().join("test.json"))
+        .await
+        .unwrap();
+    let chat = ChatId(1);
+    assert_eq!(
+        1,
+        storage.add_report(&chat, MessageId(1), UserId(1), UserId(2))
+    );
+    // Counted once per reporter
+    assert_eq!(
+        1,
+        storage.add_report(&chat, MessageId(1), UserId(1), UserId(2))
+    );
+    assert_eq!(
+        2,
+        storage.add_report(&chat, MessageId(1), UserId(1), UserId(3))
+    );
+    assert_eq!(
+        1,
+        storage.add_report(&chat, MessageId(2), UserId(1), UserId(3))
+    );
+    storage.remove_report(&chat, MessageId(1));
+    assert_eq!(
+        1,
+        storage.add_report(&chat, MessageId(1), UserId(1), UserId(2))
+    );
+    // Old ones are forgotten
+    for id in 3..=REPORTS_KEPT as i32 + 2 {
+        storage.add_report(&chat, MessageId(id), UserId(1), UserId(2));
+    }
+    assert_eq!(REPORTS_KEPT, storage.data.reports[&chat].len());
+    assert_eq!(
+        1,
+        storage.add_report(&chat, MessageId(2), UserId(1), UserId(2))
+    );
+}
+
+#[tokio::test]
+async fn test_max_users() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut storage = Storage::open(temp_dir.path().join("test.json"))
+        .await
+        .unwrap();
+    storage.set_max_users(Some(3));
+    storage.update_user(&UserId(1), SpamState::Authentic);
+    for id in 2..=5 {
+        storage.update_name(&UserId(id), "test");
+        storage.update_user(&UserId(id), SpamState::MaybeSpam(10));
+        storage
+            .data
+            .profiles
+            .get_mut(&UserId(id))
+            .unwrap()
+            .state_updated_ts = id;
+    }
+    storage.update_user(&UserId(6), SpamState::Spam);
+    storage.update_chat(&ChatId(1), (UserId(2), 1)).unwrap();
+    storage.update_chat(&ChatId(1), (UserId(5), 2)).unwrap();
+    storage.save().await.unwrap();
+
+    let mut users: Vec<_> = storage.data.users.keys().map(|u| u.0).collect();
+    users.sort();
+    assert_eq!(vec![1, 5, 6], users);
+    assert!(storage.get_profile(&UserId(2)).is_none());
+    // And from the ah stats, which keep the total
+    let stats = storage.get_ah_stats(&ChatId(1)).unwrap();
+    assert_eq!(3, stats.total);
+    assert_eq!(
+        vec![(UserId(5), 2)],
+        storage.ah_counts(&ChatId(1)).collect::<Vec<_>>()
+    );
+
+    // Evicted again once over, nothing done while under
+    storage.update_user(&UserId(7), SpamState::Spam);
+    storage.save().await.unwrap();
+    storage.save().await.unwrap();
+    let mut users: Vec<_> = storage.data.users.keys().map(|u| u.0).collect();
+    users.sort();
+    assert_eq!(vec![1, 6, 7], users);
+
+    // Also gone from the database
+    drop(storage);
+    let storage = Storage::open(temp_dir.path().join("test.json"))
+        .await
+        .unwrap();
+    let mut users: Vec<_> = storage.data.users.keys().map(|u| u.0).collect();
+    users.sort();
+    assert_eq!(vec![1, 6, 7], users);
+    assert!(storage.get_profile(&UserId(2)).is_none());
+}
+
+#[tokio::test]
+async fn test_score_decay() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut storage = Storage::open(temp_dir.path().join("test.json"))
+        .await
+        .unwrap();
+    let day = Duration::from_secs(24 * 3600);
+    storage.set_score_half_life(Some(day));
+    storage.update_name(&UserId(1), "test");
+    storage.update_user(&UserId(1), SpamState::MaybeSpam(80));
+    assert_eq!(SpamState::MaybeSpam(80), storage.get_user(&UserId(1)));
+
+    // Two days later
+    age_user(&mut storage, &UserId(1), 2 * day);
+    assert_eq!(SpamState::MaybeSpam(20), storage.get_user(&UserId(1)));
+    assert_eq!(
+        SpamState::MaybeSpam(70),
+        storage.update_user(&UserId(1), SpamState::MaybeSpam(50))
+    );
+
+    // Spam is never forgiven
+    storage.update_name(&UserId(2), "test");
+    storage.update_user(&UserId(2), SpamState::Spam);
+    age_user(&mut storage, &UserId(2), 30 * day);
+    assert_eq!(SpamState::Spam, storage.get_user(&UserId(2)));
 }
@@ -0,0 +1,53 @@
+//! Replay recorded Telegram updates through the policy and check the actions.
+use std::{fs, path::Path, time::Duration};
+
+use ahgroupbot::{Action, BanReason, PolicyConfig, PolicyState};
+use teloxide::types::{ChatId, MessageId, Update, UserId};
+
+const GROUP: ChatId = ChatId(-1001000000001);
+
+fn load_update(name: &str) -> Update {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    let buf = fs::read(&path).unwrap_or_else(|e| panic!("read {:?}: {}", path, e));
+    sonic_rs::from_slice(&buf).unwrap_or_else(|e| panic!("parse {:?}: {}", path, e))
+}
+
+#[tokio::test]
+async fn test_replay_fixtures() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config = PolicyConfig {
+        // Fixtures are recorded long ago
+        max_action_age: Duration::MAX,
+        ..Default::default()
+    };
+    let mut policy = PolicyState::with_config(temp_dir.path().join("state.json"), config)
+        .await
+        .unwrap();
+
+    let cases = [
+        (
+            "join_spammer.json",
+            Action::DeleteAndBan(GROUP, MessageId(11), UserId(100), BanReason::SpamName),
+        ),
+        ("text_ah.json", Action::Accept),
+        (
+            "text_ah_double_post.json",
+            Action::Delete(GROUP, MessageId(13)),
+        ),
+        ("sticker_allowed.json", Action::Accept),
+        ("sticker_other.json", Action::Delete(GROUP, MessageId(15))),
+        (
+            "text_spam.json",
+            Action::DeleteAndBan(GROUP, MessageId(16), UserId(3), BanReason::SpamText),
+        ),
+        ("edited_message.json", Action::Delete(GROUP, MessageId(12))),
+        ("private_message.json", Action::Accept),
+        ("unknown_update.json", Action::Accept),
+    ];
+    for (fixture, expected) in cases {
+        let update = load_update(fixture);
+        assert_eq!(expected, policy.check_update(&update), "{}", fixture);
+    }
+}